@@ -8,10 +8,9 @@
  *
  **************************************************************************************************/
 
-use nlabapi::LabBench;
+use nlabapi::{FirmwareImage, LabBench, UpdateOptions};
+use std::env;
 use std::error::Error;
-use std::thread;
-use std::time::Duration;
 
 fn main() -> Result<(), Box<dyn Error>> {
     env_logger::init();
@@ -22,27 +21,25 @@ fn main() -> Result<(), Box<dyn Error>> {
     // Print the bench to show a list of detected nLabs
     println!("{bench:?}");
 
-    for nlab_link in bench.list() {
-        // Request DFU on any nLab that is available
-        if nlab_link.available {
-            if let Err(e) = nlab_link.request_dfu() {
-                println!("Failed to request DFU on an available nLab: {e}")
+    // Flash an external image if one is given on the command line
+    if let Some(path) = env::args().nth(1) {
+        let image = FirmwareImage::from_file(path)?;
+        println!("{image:?}");
+        for nlab_link in bench.list() {
+            if let Err(e) = nlab_link.update_from_image(&image, false) {
+                println!("Encountered an error updating nLab: {e}")
             }
         }
+        return Ok(());
     }
 
-    // Wait 500ms for the scope to detach and re-attach as DFU
-    thread::sleep(Duration::from_millis(500));
-    bench.refresh();
+    // Update every nLab that needs it, waiting for each to come back after flashing
+    for report in bench.update_all(UpdateOptions::default()) {
+        println!("{report:?}");
+    }
 
     // Print the bench to show the refreshed list
     println!("{bench:?}");
 
-    for nlab_link in bench.list() {
-        if let Err(e) = nlab_link.update() {
-            println!("Encountered an error updating nLab: {e}")
-        }
-    }
-
     Ok(())
 }
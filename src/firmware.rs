@@ -8,5 +8,66 @@
  *
  **************************************************************************************************/
 
+mod image;
+
+pub use image::{FirmwareFormat, FirmwareImage};
+
 pub(crate) static FIRMWARE: &[u8] = include_bytes!("firmware/v2");
-pub(crate) static SUPPORTED_FIRMWARE_VERSION: u16 = 0x0206;
\ No newline at end of file
+pub(crate) static SUPPORTED_FIRMWARE_VERSION: u16 = 0x0206;
+
+/// Oldest nLab v2 firmware that speaks a protocol this API understands
+///
+/// Provisional: no firmware protocol revision defines which versions are compatible. This range
+/// assumes that the minor version of v2 firmware never breaks the protocol, and has only been
+/// checked against the bundled 0x0206 firmware.
+pub(crate) static MIN_COMPATIBLE_FIRMWARE_VERSION: u16 = 0x0200;
+/// Newest nLab v2 firmware that speaks a protocol this API understands, see
+/// [`MIN_COMPATIBLE_FIRMWARE_VERSION`]
+pub(crate) static MAX_COMPATIBLE_FIRMWARE_VERSION: u16 = 0x02FF;
+
+/// Returns true if the host can talk to firmware with the given BCD version
+///
+/// Firmware within the same protocol major version is compatible, individual features are
+/// negotiated through the capabilities reported at initialization
+pub(crate) fn is_compatible(version: u16) -> bool {
+    (MIN_COMPATIBLE_FIRMWARE_VERSION..=MAX_COMPATIBLE_FIRMWARE_VERSION).contains(&version)
+}
+
+/// Returns true if firmware with the given BCD version can be used, but is older than the
+/// firmware bundled with this API
+pub(crate) fn update_available(version: u16) -> bool {
+    is_compatible(version) && version < SUPPORTED_FIRMWARE_VERSION
+}
+
+/// Returns true if firmware with the given BCD version is too new to be used, and can only be
+/// replaced by downgrading it to the bundled firmware
+pub(crate) fn must_be_downgraded(version: u16) -> bool {
+    version > MAX_COMPATIBLE_FIRMWARE_VERSION
+}
+
+/// Flash address of the nLab v2 application, directly after the bootloader
+pub(crate) const APPLICATION_ADDRESS: u32 = 0x0801_0000;
+/// End of the flash region available to the application
+pub(crate) const APPLICATION_END: u32 = 0x0808_0000;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn compatibility_covers_the_v2_protocol() {
+        let cases = [
+            // version, compatible, update available, must be downgraded
+            (0x01FF, false, false, false),
+            (0x0200, true, true, false),
+            (0x0206, true, false, false),
+            (0x02FF, true, false, false),
+            (0x0300, false, false, true),
+        ];
+        for &(version, compatible, update, downgrade) in cases.iter() {
+            assert_eq!(is_compatible(version), compatible, "{version:#06x}");
+            assert_eq!(update_available(version), update, "{version:#06x}");
+            assert_eq!(must_be_downgraded(version), downgrade, "{version:#06x}");
+        }
+    }
+}
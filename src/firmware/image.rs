@@ -0,0 +1,458 @@
+/***************************************************************************************************
+ *
+ *  nLabs, LLC
+ *  https://getnlab.com
+ *  Copyright(c) 2020. All Rights Reserved
+ *
+ *  This file is part of the nLab API
+ *
+ **************************************************************************************************/
+
+use std::convert::TryInto;
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::path::Path;
+
+use super::{APPLICATION_ADDRESS, APPLICATION_END, FIRMWARE};
+
+const NLAB_VENDOR_ID: u16 = 0x0483;
+const NLAB_PRODUCT_ID: u16 = 0xA4AA;
+const NLAB_DFU_PRODUCT_ID: u16 = 0xA4AB;
+
+/// Container formats a firmware image can be loaded from
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum FirmwareFormat {
+    /// Raw binary, assumed to start at the application address
+    Bin,
+    /// Intel HEX records
+    Hex,
+    /// ST DfuSe container with a DFU suffix
+    DfuSe,
+}
+
+/// A firmware image for the nLab v2, parsed from a file or from memory
+///
+/// Images are checked before flashing: the image must target the application address, fit in
+/// flash, start with a plausible vector table, and embed the nLab USB device descriptor, which
+/// carries the firmware version.
+#[derive(Clone)]
+pub struct FirmwareImage {
+    format: FirmwareFormat,
+    address: u32,
+    data: Vec<u8>,
+    crc_valid: bool,
+    product_id: Option<u16>,
+    version: Option<u16>,
+}
+
+impl fmt::Debug for FirmwareImage {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:?} firmware image [ {} bytes at 0x{:08X}, version: ", self.format, self.data.len(), self.address)?;
+        match self.version {
+            Some(v) => write!(f, "0x{v:04X} ]"),
+            None => write!(f, "unknown ]"),
+        }
+    }
+}
+
+impl FirmwareImage {
+    /// Returns the firmware image bundled with this version of the API
+    pub fn bundled() -> Self {
+        FirmwareImage::from_parts(FirmwareFormat::Bin, APPLICATION_ADDRESS, FIRMWARE.to_vec(), true)
+    }
+
+    /// Loads a firmware image from a `.dfu`, `.hex` or `.bin` file
+    ///
+    /// The format is chosen from the file extension, falling back to the file contents
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Self, Box<dyn Error>> {
+        let path = path.as_ref();
+        let bytes = fs::read(path)?;
+        let extension = path.extension()
+            .and_then(|e| e.to_str())
+            .map(|e| e.to_ascii_lowercase());
+
+        match extension.as_deref() {
+            Some("dfu") => FirmwareImage::parse_dfuse(&bytes),
+            Some("hex") | Some("ihex") => FirmwareImage::parse_hex(&bytes),
+            Some("bin") => Ok(FirmwareImage::parse_bin(&bytes)),
+            _ => FirmwareImage::from_bytes(&bytes),
+        }
+    }
+
+    /// Parses a firmware image held in memory, detecting its format from the contents
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, Box<dyn Error>> {
+        if bytes.starts_with(b"DfuSe") {
+            FirmwareImage::parse_dfuse(bytes)
+        } else if bytes.first() == Some(&b':') && bytes.is_ascii() {
+            FirmwareImage::parse_hex(bytes)
+        } else {
+            Ok(FirmwareImage::parse_bin(bytes))
+        }
+    }
+
+    pub fn format(&self) -> FirmwareFormat {
+        self.format
+    }
+
+    /// Flash address the image is written to
+    pub fn address(&self) -> u32 {
+        self.address
+    }
+
+    /// Size of the image in bytes
+    pub fn len(&self) -> usize {
+        self.data.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
+
+    /// Firmware version from the USB device descriptor embedded in the image, if found
+    pub fn version(&self) -> Option<u16> {
+        self.version
+    }
+
+    pub(crate) fn data(&self) -> &[u8] {
+        &self.data
+    }
+
+    /// Checks the image against a device running `device_version`
+    ///
+    /// Returns a description of every problem found. An empty list means the image is safe to flash.
+    pub fn check(&self, device_version: Option<u16>) -> Vec<String> {
+        let mut problems = Vec::new();
+
+        if !self.crc_valid {
+            problems.push("DFU suffix CRC does not match the file contents".to_string());
+        }
+        if self.data.is_empty() {
+            problems.push("image contains no data".to_string());
+            return problems;
+        }
+        if self.address != APPLICATION_ADDRESS {
+            problems.push(format!("image targets 0x{:08X}, expected 0x{APPLICATION_ADDRESS:08X}", self.address));
+        }
+        let max_size = (APPLICATION_END - APPLICATION_ADDRESS) as usize;
+        if self.data.len() > max_size {
+            problems.push(format!("image is {} bytes, the application region holds {max_size}", self.data.len()));
+        }
+
+        // The first two words of the vector table are the initial stack pointer and reset handler
+        if self.data.len() >= 8 {
+            let stack_pointer = u32::from_le_bytes(self.data[0..4].try_into().unwrap());
+            let reset_handler = u32::from_le_bytes(self.data[4..8].try_into().unwrap());
+            if !(0x2000_0000..0x2010_0000).contains(&stack_pointer) {
+                problems.push(format!("initial stack pointer 0x{stack_pointer:08X} is not in RAM"));
+            }
+            let reset_address = reset_handler & !1;
+            if reset_handler & 1 == 0 || !(APPLICATION_ADDRESS..APPLICATION_END).contains(&reset_address) {
+                problems.push(format!("reset handler 0x{reset_handler:08X} is not a thumb address in the application"));
+            }
+        } else {
+            problems.push("image is too short to hold a vector table".to_string());
+        }
+
+        match (self.product_id, self.version) {
+            (Some(pid), _) if pid != NLAB_PRODUCT_ID => {
+                problems.push(format!("image is built for product 0x{pid:04X}, not an nLab v2"));
+            }
+            (_, None) => {
+                problems.push("image does not contain an nLab version header".to_string());
+            }
+            (_, Some(image_version)) => {
+                if let Some(device_version) = device_version {
+                    if image_version < device_version {
+                        problems.push(format!("image version 0x{image_version:04X} is older than the device firmware 0x{device_version:04X}"));
+                    }
+                }
+            }
+        }
+
+        problems
+    }
+
+    fn from_parts(format: FirmwareFormat, address: u32, data: Vec<u8>, crc_valid: bool) -> Self {
+        let (product_id, version) = match find_device_descriptor(&data) {
+            Some((pid, version)) => (Some(pid), Some(version)),
+            None => (None, None),
+        };
+        FirmwareImage {
+            format,
+            address,
+            data,
+            crc_valid,
+            product_id,
+            version,
+        }
+    }
+
+    fn parse_bin(bytes: &[u8]) -> Self {
+        FirmwareImage::from_parts(FirmwareFormat::Bin, APPLICATION_ADDRESS, bytes.to_vec(), true)
+    }
+
+    fn parse_hex(bytes: &[u8]) -> Result<Self, Box<dyn Error>> {
+        let text = std::str::from_utf8(bytes)?;
+        let mut upper_address: u32 = 0;
+        let mut segments: Vec<(u32, Vec<u8>)> = Vec::new();
+        let mut found_eof = false;
+
+        for (line_number, line) in text.lines().enumerate().map(|(i, l)| (i + 1, l.trim())) {
+            if line.is_empty() {
+                continue;
+            }
+            let record = line.strip_prefix(':')
+                .ok_or_else(|| format!("line {line_number}: missing record start"))?;
+            if record.len() % 2 != 0 || record.len() < 10 {
+                return Err(format!("line {line_number}: malformed record").into());
+            }
+            let record: Vec<u8> = (0..record.len())
+                .step_by(2)
+                .map(|i| u8::from_str_radix(&record[i..i + 2], 16))
+                .collect::<Result<_, _>>()
+                .map_err(|_| format!("line {line_number}: invalid hex digit"))?;
+
+            if record.iter().fold(0u8, |sum, &b| sum.wrapping_add(b)) != 0 {
+                return Err(format!("line {line_number}: checksum mismatch").into());
+            }
+            let length = record[0] as usize;
+            if record.len() != length + 5 {
+                return Err(format!("line {line_number}: record length mismatch").into());
+            }
+            let offset = u16::from_be_bytes([record[1], record[2]]) as u32;
+            let payload = &record[4..4 + length];
+
+            match record[3] {
+                0x00 => {
+                    let address = upper_address + offset;
+                    match segments.last_mut() {
+                        Some((start, data)) if *start + data.len() as u32 == address => {
+                            data.extend_from_slice(payload)
+                        }
+                        _ => segments.push((address, payload.to_vec())),
+                    }
+                }
+                0x01 => {
+                    found_eof = true;
+                    break;
+                }
+                0x02 if length == 2 => {
+                    upper_address = (u16::from_be_bytes([payload[0], payload[1]]) as u32) << 4;
+                }
+                0x04 if length == 2 => {
+                    upper_address = (u16::from_be_bytes([payload[0], payload[1]]) as u32) << 16;
+                }
+                0x03 | 0x05 => {}
+                record_type => {
+                    return Err(format!("line {line_number}: unsupported record type {record_type:02X}").into());
+                }
+            }
+        }
+
+        if !found_eof {
+            return Err("HEX file has no end-of-file record".into());
+        }
+
+        let (address, data) = flatten_segments(segments)?;
+        Ok(FirmwareImage::from_parts(FirmwareFormat::Hex, address, data, true))
+    }
+
+    fn parse_dfuse(bytes: &[u8]) -> Result<Self, Box<dyn Error>> {
+        const PREFIX_LENGTH: usize = 11;
+        const TARGET_PREFIX_LENGTH: usize = 274;
+        const SUFFIX_LENGTH: usize = 16;
+
+        if bytes.len() < PREFIX_LENGTH + SUFFIX_LENGTH || !bytes.starts_with(b"DfuSe") {
+            return Err("not a DfuSe file".into());
+        }
+
+        let (body, suffix) = bytes.split_at(bytes.len() - SUFFIX_LENGTH);
+        if &suffix[8..11] != b"UFD" || suffix[11] as usize != SUFFIX_LENGTH {
+            return Err("DfuSe file has an invalid DFU suffix".into());
+        }
+        let suffix_vendor = u16::from_le_bytes([suffix[4], suffix[5]]);
+        let suffix_product = u16::from_le_bytes([suffix[2], suffix[3]]);
+        if suffix_vendor != 0xFFFF && suffix_vendor != NLAB_VENDOR_ID {
+            return Err(format!("DfuSe file is for vendor 0x{suffix_vendor:04X}").into());
+        }
+        if ![0xFFFF, NLAB_PRODUCT_ID, NLAB_DFU_PRODUCT_ID].contains(&suffix_product) {
+            return Err(format!("DfuSe file is for product 0x{suffix_product:04X}").into());
+        }
+        let expected_crc = u32::from_le_bytes(suffix[12..16].try_into().unwrap());
+        let crc_valid = dfu_crc(&bytes[..bytes.len() - 4]) == expected_crc;
+
+        let image_size = u32::from_le_bytes(body[6..10].try_into().unwrap()) as usize;
+        if image_size != body.len() {
+            return Err("DfuSe image size does not match the file length".into());
+        }
+
+        let num_targets = body[10];
+        let mut segments: Vec<(u32, Vec<u8>)> = Vec::new();
+        let mut cursor = PREFIX_LENGTH;
+        for _ in 0..num_targets {
+            let target = body.get(cursor..cursor + TARGET_PREFIX_LENGTH)
+                .ok_or("DfuSe target prefix is truncated")?;
+            if !target.starts_with(b"Target") {
+                return Err("DfuSe target prefix is invalid".into());
+            }
+            let alternate_setting = target[6];
+            let num_elements = u32::from_le_bytes(target[270..274].try_into().unwrap());
+            cursor += TARGET_PREFIX_LENGTH;
+
+            for _ in 0..num_elements {
+                let header = body.get(cursor..cursor + 8)
+                    .ok_or("DfuSe element header is truncated")?;
+                let address = u32::from_le_bytes(header[0..4].try_into().unwrap());
+                let size = u32::from_le_bytes(header[4..8].try_into().unwrap()) as usize;
+                cursor += 8;
+                let data = body.get(cursor..cursor + size)
+                    .ok_or("DfuSe element data is truncated")?;
+                cursor += size;
+                // Only the internal flash (alternate setting 0) holds the application
+                if alternate_setting == 0 {
+                    segments.push((address, data.to_vec()));
+                }
+            }
+        }
+
+        segments.sort_by_key(|(address, _)| *address);
+        let (address, data) = flatten_segments(segments)?;
+        Ok(FirmwareImage::from_parts(FirmwareFormat::DfuSe, address, data, crc_valid))
+    }
+}
+
+/// Joins address-sorted segments into one contiguous image, padding gaps with erased flash
+fn flatten_segments(segments: Vec<(u32, Vec<u8>)>) -> Result<(u32, Vec<u8>), Box<dyn Error>> {
+    let start = match segments.iter().map(|(address, _)| *address).min() {
+        Some(start) => start,
+        None => return Ok((APPLICATION_ADDRESS, Vec::new())),
+    };
+    let end = segments.iter().map(|(address, data)| *address as u64 + data.len() as u64).max().unwrap();
+    if end - start as u64 > (APPLICATION_END - APPLICATION_ADDRESS) as u64 * 2 {
+        return Err("image segments span an unreasonably large address range".into());
+    }
+
+    let mut image = vec![0xFFu8; (end - start as u64) as usize];
+    for (address, data) in segments {
+        let offset = (address - start) as usize;
+        image[offset..offset + data.len()].copy_from_slice(&data);
+    }
+    Ok((start, image))
+}
+
+/// Finds the nLab USB device descriptor in an image, returning the product id and bcdDevice
+fn find_device_descriptor(data: &[u8]) -> Option<(u16, u16)> {
+    let vendor = NLAB_VENDOR_ID.to_le_bytes();
+    data.windows(14)
+        .find(|d| d[0] == 0x12 && d[1] == 0x01 && d[8..10] == vendor)
+        .map(|d| (u16::from_le_bytes([d[10], d[11]]), u16::from_le_bytes([d[12], d[13]])))
+}
+
+/// CRC used by the DFU suffix: CRC-32 without the final inversion
+fn dfu_crc(data: &[u8]) -> u32 {
+    data.iter().fold(0xFFFF_FFFFu32, |crc, &byte| {
+        (0..8).fold(crc ^ byte as u32, |crc, _| {
+            if crc & 1 != 0 { (crc >> 1) ^ 0xEDB8_8320 } else { crc >> 1 }
+        })
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::firmware::SUPPORTED_FIRMWARE_VERSION;
+
+    fn to_hex(address: u32, data: &[u8]) -> String {
+        let mut lines = Vec::new();
+        let mut upper = None;
+        for (i, chunk) in data.chunks(16).enumerate() {
+            let chunk_address = address + 16 * i as u32;
+            if upper != Some(chunk_address >> 16) {
+                upper = Some(chunk_address >> 16);
+                let mut record = vec![2u8, 0, 0, 4];
+                record.extend_from_slice(&((chunk_address >> 16) as u16).to_be_bytes());
+                lines.push(record);
+            }
+            let mut record = vec![chunk.len() as u8];
+            record.extend_from_slice(&(chunk_address as u16).to_be_bytes());
+            record.push(0);
+            record.extend_from_slice(chunk);
+            lines.push(record);
+        }
+        lines.push(vec![0, 0, 0, 1]);
+        lines.iter()
+            .map(|r| {
+                let checksum = 0u8.wrapping_sub(r.iter().fold(0u8, |s, &b| s.wrapping_add(b)));
+                let hex: String = r.iter().chain([checksum].iter()).map(|b| format!("{b:02X}")).collect();
+                format!(":{hex}\n")
+            })
+            .collect()
+    }
+
+    fn to_dfuse(address: u32, data: &[u8]) -> Vec<u8> {
+        let mut target = b"Target".to_vec();
+        target.push(0);
+        target.extend_from_slice(&[0u8; 4 + 255]);
+        target.extend_from_slice(&(data.len() as u32 + 8).to_le_bytes());
+        target.extend_from_slice(&1u32.to_le_bytes());
+        target.extend_from_slice(&address.to_le_bytes());
+        target.extend_from_slice(&(data.len() as u32).to_le_bytes());
+        target.extend_from_slice(data);
+
+        let mut file = b"DfuSe\x01".to_vec();
+        file.extend_from_slice(&(11 + target.len() as u32).to_le_bytes());
+        file.push(1);
+        file.extend_from_slice(&target);
+        file.extend_from_slice(&[0xFF, 0xFF, 0xAB, 0xA4, 0x83, 0x04, 0x1A, 0x01]);
+        file.extend_from_slice(b"UFD");
+        file.push(16);
+        let crc = dfu_crc(&file);
+        file.extend_from_slice(&crc.to_le_bytes());
+        file
+    }
+
+    #[test]
+    fn bundled_image_passes_checks() {
+        let image = FirmwareImage::bundled();
+        assert_eq!(image.version(), Some(SUPPORTED_FIRMWARE_VERSION));
+        assert!(image.check(Some(SUPPORTED_FIRMWARE_VERSION)).is_empty());
+    }
+
+    #[test]
+    fn hex_and_dfuse_match_bundled_image() {
+        let hex = FirmwareImage::from_bytes(to_hex(APPLICATION_ADDRESS, FIRMWARE).as_bytes()).unwrap();
+        assert_eq!(hex.format(), FirmwareFormat::Hex);
+        assert_eq!(hex.data(), FIRMWARE);
+        assert!(hex.check(None).is_empty());
+
+        let dfuse = FirmwareImage::from_bytes(&to_dfuse(APPLICATION_ADDRESS, FIRMWARE)).unwrap();
+        assert_eq!(dfuse.format(), FirmwareFormat::DfuSe);
+        assert_eq!(dfuse.data(), FIRMWARE);
+        assert!(dfuse.check(None).is_empty());
+    }
+
+    #[test]
+    fn corrupt_images_are_rejected() {
+        let mut hex = to_hex(APPLICATION_ADDRESS, FIRMWARE);
+        hex.replace_range(20..21, if &hex[20..21] == "0" { "1" } else { "0" });
+        assert!(FirmwareImage::from_bytes(hex.as_bytes()).is_err());
+
+        let mut dfuse = to_dfuse(APPLICATION_ADDRESS, FIRMWARE);
+        dfuse[1000] ^= 0xFF;
+        let image = FirmwareImage::from_bytes(&dfuse).unwrap();
+        assert!(!image.check(None).is_empty());
+    }
+
+    #[test]
+    fn mismatched_images_are_reported() {
+        let image = FirmwareImage::from_bytes(&to_dfuse(0x0800_0000, FIRMWARE)).unwrap();
+        assert!(!image.check(None).is_empty());
+
+        let image = FirmwareImage::bundled();
+        assert!(!image.check(Some(SUPPORTED_FIRMWARE_VERSION + 1)).is_empty());
+
+        let image = FirmwareImage::from_bytes(&[0u8; 256]).unwrap();
+        assert!(!image.check(None).is_empty());
+    }
+}
@@ -12,9 +12,11 @@ use crate::scope::Nlab;
 use std::{fmt, io};
 use std::error::Error;
 use std::sync::{Arc, RwLock};
-use std::time::Duration;
+use std::thread;
+use std::time::{Duration, Instant};
+use log::{debug, info, warn};
 use rusb::Version;
-use crate::firmware::{FIRMWARE, SUPPORTED_FIRMWARE_VERSION};
+use crate::firmware::{self, FirmwareImage, MAX_COMPATIBLE_FIRMWARE_VERSION, SUPPORTED_FIRMWARE_VERSION};
 
 #[derive(Clone)]
 pub(crate) struct HidDevice(hidapi::DeviceInfo);
@@ -43,6 +45,7 @@ impl HidDevice {
     pub(crate) fn vendor_id(&self) -> u16 { self.0.vendor_id() }
     pub(crate) fn product_id(&self) -> u16 { self.0.product_id() }
     pub(crate) fn open_device(&self, api: &hidapi::HidApi) -> hidapi::HidResult<hidapi::HidDevice> { self.0.open_device(api) }
+    pub(crate) fn serial_number(&self) -> Option<&str> { self.0.serial_number() }
 }
 
 
@@ -53,11 +56,83 @@ pub struct LabBench {
     rusb_devices: Vec<rusb::Device<rusb::GlobalContext>>,
 }
 
+/// Options used by `LabBench::update_all` when updating connected nLabs
+#[derive(Debug, Copy, Clone)]
+pub struct UpdateOptions {
+    /// Flash the bundled firmware even if the device is running newer firmware
+    pub force_downgrade: bool,
+    /// How long to wait for a device to re-enumerate in DFU mode
+    pub dfu_timeout: Duration,
+    /// How long to wait for a device to return to application mode after flashing
+    pub reattach_timeout: Duration,
+    /// How often to re-scan the USB bus while waiting on a device
+    pub poll_interval: Duration,
+}
+
+impl Default for UpdateOptions {
+    fn default() -> Self {
+        UpdateOptions {
+            force_downgrade: false,
+            dfu_timeout: Duration::from_secs(5),
+            reattach_timeout: Duration::from_secs(10),
+            poll_interval: Duration::from_millis(50),
+        }
+    }
+}
+
+/// The result of attempting to update a single nLab
+#[derive(Debug, Clone, PartialEq)]
+pub enum UpdateOutcome {
+    /// The device was flashed and came back running the expected firmware
+    Updated,
+    /// The device is already running the supported firmware
+    UpToDate,
+    /// The device is running newer firmware and a downgrade was not forced
+    DowngradeSkipped,
+    /// The device cannot be updated by this API (e.g. nLab v1)
+    Unsupported,
+    /// The update failed, with a description of the step that failed
+    Failed(String),
+}
+
+/// Per-device report returned by `LabBench::update_all`
+#[derive(Debug, Clone)]
+pub struct UpdateReport {
+    /// USB bus and port path the device is attached to, if known
+    pub location: Option<String>,
+    /// Firmware version reported before the update
+    pub previous_version: Option<u16>,
+    /// Firmware version reported after the update
+    pub new_version: Option<u16>,
+    /// What happened to the device: `Updated` if it was flashed and came back running the
+    /// bundled firmware, `UpToDate` if it already ran compatible firmware at least as new as
+    /// the bundled firmware, `DowngradeSkipped` if it ran newer firmware and no downgrade was
+    /// forced, `Unsupported` if it is an nLab v1 that this API cannot flash, and `Failed` with
+    /// the step that went wrong otherwise
+    pub outcome: UpdateOutcome,
+}
+
+/// Physical USB location of a device, stable across DFU re-enumeration
+#[derive(Debug, Clone, PartialEq)]
+struct UsbLocation {
+    bus: u8,
+    ports: Vec<u8>,
+}
+
+impl fmt::Display for UsbLocation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let ports: Vec<String> = self.ports.iter().map(|p| p.to_string()).collect();
+        write!(f, "bus {} port {}", self.bus, ports.join("."))
+    }
+}
+
 /// A detected link between the computer and an nLab, used to open and retrieve an nLab
 pub struct NlabLink {
     pub available: bool,
     pub in_dfu: bool,
     pub needs_update: bool,
+    /// The nLab is running compatible firmware that is older than the firmware bundled with this API
+    pub update_available: bool,
     device_version: Option<Version>,
     device: NlabDevice,
 }
@@ -140,6 +215,147 @@ impl LabBench {
     pub fn get_first_needing_update(&self) -> Option<NlabLink> {
         self.list().find(|nsl| nsl.needs_update && nsl.available)
     }
+
+    /// Updates the firmware on every connected nLab that needs it
+    ///
+    /// Each device is asked to jump to DFU mode, flashed once it re-enumerates, and then
+    /// followed until it comes back in application mode so the new version can be confirmed.
+    /// Devices are tracked by their physical USB location, so several nLabs can be updated at
+    /// once. Returns one report per detected nLab.
+    pub fn update_all(&mut self, options: UpdateOptions) -> Vec<UpdateReport> {
+        self.refresh();
+
+        let mut reports = Vec::new();
+        let mut pending: Vec<(UsbLocation, Option<u16>)> = Vec::new();
+
+        for nlab_link in self.list() {
+            let location = nlab_link.usb_location();
+            let previous_version = nlab_link.device_version.map(bcd_from_version);
+            let mut report = UpdateReport {
+                location: location.as_ref().map(|l| l.to_string()),
+                previous_version,
+                new_version: previous_version,
+                outcome: UpdateOutcome::UpToDate,
+            };
+
+            if let NlabDevice::HidApiDevice { .. } = nlab_link.device {
+                report.outcome = UpdateOutcome::Unsupported;
+                reports.push(report);
+                continue;
+            }
+
+            let location = match location {
+                Some(location) => location,
+                None => {
+                    report.outcome = UpdateOutcome::Failed("cannot determine USB location".into());
+                    reports.push(report);
+                    continue;
+                }
+            };
+
+            if nlab_link.in_dfu {
+                // A device left in DFU by an earlier attempt, flash it along with the others
+                pending.push((location, None));
+                continue;
+            }
+            if !nlab_link.needs_update && !nlab_link.update_available {
+                reports.push(report);
+                continue;
+            }
+            if nlab_link.must_be_downgraded() && !options.force_downgrade {
+                report.outcome = UpdateOutcome::DowngradeSkipped;
+                reports.push(report);
+                continue;
+            }
+            if let Err(e) = nlab_link.request_dfu() {
+                report.outcome = UpdateOutcome::Failed(format!("cannot request DFU: {e}"));
+                reports.push(report);
+                continue;
+            }
+            debug!("Requested DFU on nLab at {location}");
+            pending.push((location, previous_version));
+        }
+
+        // Flash each device as soon as it shows up in DFU mode
+        let mut flashed: Vec<(UsbLocation, Option<u16>)> = Vec::new();
+        for (location, previous_version) in pending {
+            let mut report = UpdateReport {
+                location: Some(location.to_string()),
+                previous_version,
+                new_version: None,
+                outcome: UpdateOutcome::Updated,
+            };
+
+            let dfu_link = wait_for_rusb_link(&location, true, options.dfu_timeout, options.poll_interval);
+            match dfu_link.map(|link| link.update()) {
+                None => {
+                    report.outcome = UpdateOutcome::Failed("device did not re-enumerate in DFU mode".into());
+                    reports.push(report);
+                }
+                Some(Err(e)) => {
+                    report.outcome = UpdateOutcome::Failed(format!("cannot flash firmware: {e}"));
+                    reports.push(report);
+                }
+                Some(Ok(())) => {
+                    info!("Flashed firmware to nLab at {location}");
+                    flashed.push((location, previous_version));
+                }
+            }
+        }
+
+        // Wait for the flashed devices to come back and confirm the new version
+        for (location, previous_version) in flashed {
+            let mut report = UpdateReport {
+                location: Some(location.to_string()),
+                previous_version,
+                new_version: None,
+                outcome: UpdateOutcome::Updated,
+            };
+
+            match wait_for_rusb_link(&location, false, options.reattach_timeout, options.poll_interval) {
+                None => {
+                    report.outcome = UpdateOutcome::Failed("device did not return to application mode".into());
+                }
+                Some(link) => {
+                    report.new_version = link.device_version.map(bcd_from_version);
+                    if report.new_version != Some(SUPPORTED_FIRMWARE_VERSION) {
+                        report.outcome = UpdateOutcome::Failed(
+                            format!("device reports unexpected firmware {:?} after update", report.new_version));
+                    }
+                }
+            }
+            reports.push(report);
+        }
+
+        reports
+    }
+
+}
+
+/// Re-scans the USB bus until an nLab v2 link appears at the given location in the given mode
+fn wait_for_rusb_link(location: &UsbLocation, in_dfu: bool, timeout: Duration, poll_interval: Duration) -> Option<NlabLink> {
+    let start = Instant::now();
+    loop {
+        if let Ok(devices) = rusb::devices() {
+            let link = devices.iter()
+                .filter_map(NlabLink::from_rusb_device)
+                .find(|link| link.in_dfu == in_dfu && link.usb_location().as_ref() == Some(location));
+            if link.is_some() {
+                return link;
+            }
+        }
+        if start.elapsed() > timeout {
+            return None;
+        }
+        thread::sleep(poll_interval);
+    }
+}
+
+fn bcd_from_version(version: Version) -> u16 {
+    ((version.major() as u16 / 10) << 12)
+        | ((version.major() as u16 % 10) << 8)
+        | ((version.minor() as u16) << 4)
+        | (version.sub_minor() as u16)
 }
 
 impl NlabLink {
@@ -158,6 +374,7 @@ impl NlabLink {
                 available,
                 in_dfu: false,
                 needs_update: false,
+                update_available: false,
                 device_version: None,
                 device: NlabDevice::HidApiDevice { device: info.clone(), api: Arc::clone(&api) },
             });
@@ -181,7 +398,8 @@ impl NlabLink {
                 return Some(NlabLink {
                     available,
                     in_dfu: false,
-                    needs_update: firmware_version != Version::from_bcd(SUPPORTED_FIRMWARE_VERSION),
+                    needs_update: !firmware::is_compatible(bcd_from_version(firmware_version)),
+                    update_available: firmware::update_available(bcd_from_version(firmware_version)),
                     device_version: Some(firmware_version),
                     device: NlabDevice::RusbDevice(device),
                 });
@@ -190,6 +408,7 @@ impl NlabLink {
                     available: false,
                     in_dfu: true,
                     needs_update: false,
+                    update_available: false,
                     device_version: None,
                     device: NlabDevice::RusbDevice(device),
                 });
@@ -198,13 +417,23 @@ impl NlabLink {
         None
     }
 
+    fn usb_location(&self) -> Option<UsbLocation> {
+        match &self.device {
+            NlabDevice::HidApiDevice { .. } => None,
+            NlabDevice::RusbDevice(device) => Some(UsbLocation {
+                bus: device.bus_number(),
+                ports: device.port_numbers().ok()?,
+            }),
+        }
+    }
+
     ///
     /// Determines if an NlabLink must be downgraded in order to function
     ///
     ///
     ///
     pub(crate) fn must_be_downgraded(&self) -> bool {
-        self.needs_update && self.device_version.is_some_and(|v| v > Version::from_bcd(SUPPORTED_FIRMWARE_VERSION))
+        self.needs_update && self.device_version.is_some_and(|v| firmware::must_be_downgraded(bcd_from_version(v)))
     }
 
     ///
@@ -269,7 +498,45 @@ impl NlabLink {
         if !self.in_dfu {
             return Err("nLab is not in DFU mode".into());
         }
+        self.flash(&FirmwareImage::bundled())
+    }
+
+    /// Update the nLab at the link with an external firmware image
+    ///
+    /// The image is checked against the device before anything is written, and refused if it
+    /// is corrupt, targets the wrong address, does not fit, or would downgrade the device. Set
+    /// `force` to flash it anyway. If the link is in application mode, the device is asked to
+    /// jump to DFU mode first.
+    pub fn update_from_image(&self, image: &FirmwareImage, force: bool) -> Result<(), Box<dyn Error>> {
+        if let NlabDevice::HidApiDevice { .. } = self.device {
+            return Err("Cannot update nLab v1".into());
+        }
 
+        let device_version = self.device_version.map(bcd_from_version);
+        let problems = image.check(device_version);
+        if !problems.is_empty() {
+            if !force {
+                return Err(format!("Refusing to flash firmware image: {}", problems.join("; ")).into());
+            }
+            for problem in problems {
+                warn!("Flashing firmware image despite: {problem}");
+            }
+        }
+
+        if self.in_dfu {
+            return self.flash(image);
+        }
+
+        let location = self.usb_location().ok_or("Cannot determine USB location of nLab")?;
+        self.request_dfu()?;
+        let options = UpdateOptions::default();
+        match wait_for_rusb_link(&location, true, options.dfu_timeout, options.poll_interval) {
+            Some(dfu_link) => dfu_link.flash(image),
+            None => Err("nLab did not re-enumerate in DFU mode".into()),
+        }
+    }
+
+    fn flash(&self, image: &FirmwareImage) -> Result<(), Box<dyn Error>> {
         match &self.device {
             NlabDevice::HidApiDevice { .. } => {
                 return Err("Cannot update nLab v1".into());
@@ -279,8 +546,8 @@ impl NlabLink {
                     device.clone(),
                     device.open()?,
                     0, 0)?;
-                dfu.override_address(0x08010000);
-                dfu.download_from_slice(FIRMWARE)?;
+                dfu.override_address(image.address());
+                dfu.download_from_slice(image.data())?;
             }
         };
         Ok(())
@@ -328,13 +595,16 @@ impl fmt::Debug for NlabLink {
                 Some(v) if v < Version::from_bcd(SUPPORTED_FIRMWARE_VERSION) => {
                     write!(f, "Link to {device_name} [ Firmware Update Needed ]")
                 }
-                Some(v) if v > Version::from_bcd(SUPPORTED_FIRMWARE_VERSION) => {
+                Some(v) if v > Version::from_bcd(MAX_COMPATIBLE_FIRMWARE_VERSION) => {
                     write!(f, "Link to {device_name} [ Device is running newer firmware, software update needed ]")
                 }
                 Some(_) => { write!(f, "Link to {device_name} [ Unknown firmware mismatch ]") }
                 None => { write!(f, "Link to {device_name} [ Unknown firmware mismatch ]") }
             };
         }
+        if self.update_available {
+            return write!(f, "Link to {device_name} [ available: {}, firmware update available ]", self.available);
+        }
         write!(f, "Link to {device_name} [ available: {} ]", self.available)
     }
 }
@@ -59,11 +59,26 @@ mod firmware;
 
 pub use lab_bench::LabBench;
 pub use lab_bench::NlabLink;
+pub use lab_bench::{UpdateOptions, UpdateOutcome, UpdateReport};
 pub use scope::Nlab;
+pub use scope::RequestError;
 pub use scope::power::*;
 pub use scope::pulse_output::*;
+pub use scope::roll::*;
+pub use scope::safe_state::*;
+pub use scope::software_trigger::*;
 pub use scope::analog_output::*;
+pub use scope::acquisition::*;
 pub use scope::analog_input::*;
+pub use scope::autoset::*;
+pub use scope::averaging::*;
+pub use scope::calibration::*;
+pub use scope::capabilities::*;
 pub use scope::data_requests::*;
+pub use scope::equivalent_time::*;
+pub use scope::link_stats::*;
+pub use scope::math_channels::*;
 pub use scope::trigger::*;
+pub use scope::transport::{Transport, Waker, PACKET_SIZE};
 pub use version::version;
+pub use firmware::{FirmwareFormat, FirmwareImage};
@@ -5,7 +5,8 @@ mod pulse_output;
 mod cli;
 
 use pyo3::prelude::*;
-use crate::{AnalogSignalPolarity, AnalogWaveType, PowerStatus, PowerState};
+use pyo3::exceptions::PyRuntimeError;
+use crate::{AnalogSignalPolarity, AnalogWaveType, PowerError, PowerStatus, PowerState, RequestError};
 use cli::{Cli, Commands};
 use clap::Parser;
 
@@ -15,6 +16,18 @@ struct LabBench;
 #[pyclass]
 struct Nlab(crate::Nlab);
 
+impl From<RequestError> for PyErr {
+    fn from(error: RequestError) -> Self {
+        PyRuntimeError::new_err(error.to_string())
+    }
+}
+
+impl From<PowerError> for PyErr {
+    fn from(error: PowerError) -> Self {
+        PyRuntimeError::new_err(error.to_string())
+    }
+}
+
 #[pyfunction]
 fn run_cli(_py: Python) -> PyResult<()> {
     let args: Vec<_> = std::env::args_os().skip(1).collect();
@@ -75,7 +75,7 @@ impl python::Nlab {
             _ => return Err(PyValueError::new_err(format!("Invalid channel number: {ch}")))
         };
 
-        ax.turn_on();
+        ax.try_turn_on()?;
         Ok(())
     }
 
@@ -88,7 +88,7 @@ impl python::Nlab {
             _ => return Err(PyValueError::new_err(format!("Invalid channel number: {ch}")))
         };
 
-        ax.turn_off();
+        ax.try_turn_off()?;
         Ok(())
     }
 
@@ -101,7 +101,7 @@ impl python::Nlab {
             _ => return Err(PyValueError::new_err(format!("Invalid channel number: {ch}")))
         };
 
-        ax.set_frequency(desired_hz);
+        ax.try_set_frequency(desired_hz)?;
         Ok(())
     }
 
@@ -114,7 +114,7 @@ impl python::Nlab {
             _ => return Err(PyValueError::new_err(format!("Invalid channel number: {ch}")))
         };
 
-        ax.set_amplitude(desired_volts);
+        ax.try_set_amplitude(desired_volts)?;
         Ok(())
     }
 
@@ -127,7 +127,7 @@ impl python::Nlab {
             _ => return Err(PyValueError::new_err(format!("Invalid channel number: {ch}")))
         };
 
-        ax.set_wave_type(wave_type);
+        ax.try_set_wave_type(wave_type)?;
         Ok(())
     }
 
@@ -140,7 +140,7 @@ impl python::Nlab {
             _ => return Err(PyValueError::new_err(format!("Invalid channel number: {ch}")))
         };
 
-        ax.set_polarity(polarity);
+        ax.try_set_polarity(polarity)?;
         Ok(())
     }
 }
\ No newline at end of file
@@ -1,8 +1,6 @@
-use std::thread;
-use std::time::Duration;
 use pyo3::exceptions::*;
 use pyo3::prelude::*;
-use crate::{LabBench, python};
+use crate::{LabBench, python, UpdateOptions, UpdateOutcome};
 
 #[pymethods]
 impl python::LabBench {
@@ -71,48 +69,33 @@ impl python::LabBench {
                 }
             }
 
-            let mut device_update_count = 0;
-            for nlab_link in bench.list() {
-                if nlab_link.needs_update {
-                    if let Err(e) = nlab_link.request_dfu() {
-                        println!("Failed to request DFU on an available nLab: {e}");
-                        return Err(PyRuntimeError::new_err(format!("{e}")));
-                    } else {
-                        device_update_count += 1;
-                    }
-                }
-            }
+            let options = UpdateOptions { force_downgrade, ..Default::default() };
+            let updates_needed = bench.list().filter(|link| link.needs_update || link.update_available || link.in_dfu).count();
 
-            if device_update_count == 0 {
+            if updates_needed == 0 {
                 println!("No firmware updates are needed for connected nLab devices.");
                 return Ok(());
             }
-            match device_update_count {
+            match updates_needed {
                 1 => { println!("Updating connected nLab...") }
-                _ => { println!("Updating {device_update_count} connected nLabs...") }
+                _ => { println!("Updating {updates_needed} connected nLabs...") }
             }
 
-            // Wait 500ms for the scope to detach and re-attach as DFU
-            thread::sleep(Duration::from_millis(500));
-            bench.refresh();
-
-            for nlab_link in bench.list() {
-                if nlab_link.in_dfu {
-                    if let Err(e) = nlab_link.update() {
-                        println!("Encountered an error updating nLab: {e}");
-                        return Err(PyRuntimeError::new_err(format!("{e}")));
-                    } else {
-                        device_update_count -= 1;
-                    }
+            let mut failed_count = 0;
+            for report in bench.update_all(options) {
+                if let UpdateOutcome::Failed(e) = &report.outcome {
+                    println!("Encountered an error updating nLab: {e}");
+                    failed_count += 1;
                 }
             }
-            if device_update_count == 0 {
-                println!("Update complete!");
-            } else {
-                match device_update_count {
-                    1 => { println!("Failed to update {device_update_count} nLab") }
-                    _ => { println!("Failed to update {device_update_count} nLabs") }
-                }
+
+            match failed_count {
+                0 => { println!("Update complete!") }
+                1 => { println!("Failed to update {failed_count} nLab") }
+                _ => { println!("Failed to update {failed_count} nLabs") }
+            }
+            if failed_count > 0 {
+                return Err(PyRuntimeError::new_err("Failed to update all connected nLabs"));
             }
         }
         Ok(())
@@ -75,7 +75,7 @@ impl python::Nlab {
             _ => return Err(PyValueError::new_err(format!("Invalid channel number: {ch}")))
         };
 
-        px.turn_on();
+        px.try_turn_on()?;
         Ok(())
     }
 
@@ -88,7 +88,7 @@ impl python::Nlab {
             _ => return Err(PyValueError::new_err(format!("Invalid channel number: {ch}")))
         };
 
-        px.turn_off();
+        px.try_turn_off()?;
         Ok(())
     }
 
@@ -101,7 +101,7 @@ impl python::Nlab {
             _ => return Err(PyValueError::new_err(format!("Invalid channel number: {ch}")))
         };
 
-        px.set_frequency(desired_hz);
+        px.try_set_frequency(desired_hz)?;
         Ok(())
     }
 
@@ -114,7 +114,7 @@ impl python::Nlab {
             _ => return Err(PyValueError::new_err(format!("Invalid channel number: {ch}")))
         };
 
-        px.set_duty(desired_percentage);
+        px.try_set_duty(desired_percentage)?;
         Ok(())
     }
 }
\ No newline at end of file
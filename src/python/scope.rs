@@ -1,5 +1,6 @@
 use pyo3::exceptions::*;
 use pyo3::prelude::*;
+use std::time::Duration;
 
 use crate::{PowerStatus, python, Sample};
 
@@ -31,23 +32,38 @@ impl python::Nlab {
         }
     }
 
+    fn set_power(&self, power_on: bool) -> PyResult<()> {
+        let scope: &crate::Nlab = &self.0;
+        Ok(scope.set_power(power_on)?)
+    }
+
+    fn power_cycle(&self, off_seconds: f64) -> PyResult<()> {
+        let scope: &crate::Nlab = &self.0;
+        Ok(scope.power_cycle(Duration::from_secs_f64(off_seconds))?)
+    }
+
     fn read_all_channels(&mut self, sample_rate: f64, number_of_samples: u32) -> PyResult<Vec<Vec<Option<f64>>>> {
         let scope: &mut crate::Nlab = &mut self.0;
         scope.ch1.turn_on();
         scope.ch2.turn_on();
         scope.ch3.turn_on();
         scope.ch4.turn_on();
-        let sweep_handle = scope.request(sample_rate, number_of_samples, None);
+        let sweep_handle = scope.request_blocks(sample_rate, number_of_samples, None, 1024);
 
         let mut return_data: Vec<Vec<Option<f64>>> = Vec::new();
 
+        // A sweep can be stopped or fail long before it delivers every sample asked of it
         for _ in 0..Sample::num_channels() {
-            return_data.push(Vec::new());
+            return_data.push(Vec::with_capacity(number_of_samples.min(1 << 20) as usize));
         }
 
-        for sample in sweep_handle.receiver {
-            for (ch, &data) in sample.data.iter().enumerate() {
-                return_data[ch].push(data);
+        for block in sweep_handle.receiver {
+            for (ch, readings) in block.channels.iter().enumerate() {
+                match readings {
+                    Some(readings) => return_data[ch].extend(readings.iter()
+                        .map(|&reading| Some(reading as f64).filter(|v| !v.is_nan()))),
+                    None => return_data[ch].extend(std::iter::repeat_n(None, block.len())),
+                }
             }
         }
         Ok(return_data)
@@ -12,35 +12,73 @@ use std::{fmt, thread};
 use std::convert::TryInto;
 use std::error::Error;
 use std::sync::{Arc, mpsc, RwLock};
-use std::sync::mpsc::Sender;
 use std::thread::JoinHandle;
 use std::time::Duration;
 
-use hidapi::HidDevice;
 use log::info;
 
+use acquisition::AcquisitionMode;
 use analog_input::AnalogInput;
 use analog_output::AnalogOutput;
-use commands::Command;
-use power::PowerStatus;
+use calibration::Calibration;
+use capabilities::{Capabilities, SampleRates};
+use commands::{Command, CommandSender};
+use data_requests::OverRangeCallback;
+use link_stats::LinkStats;
+use math_channels::MathChannel;
+use run_loops::SharedState;
+use safe_state::SafeState;
+use transport::{HidTransport, Transport, UsbTransport};
+use power::{PowerMonitor, PowerStatus};
 use pulse_output::PulseOutput;
 use trigger::Trigger;
 use crate::lab_bench::NlabDevice;
 
 mod commands;
+pub mod acquisition;
 pub mod analog_input;
 pub mod analog_output;
+pub mod autoset;
+pub mod averaging;
+pub mod calibration;
+pub mod capabilities;
 pub mod pulse_output;
 pub mod trigger;
 pub mod power;
+pub mod roll;
+pub mod safe_state;
+pub mod software_trigger;
+mod skew;
 pub mod data_requests;
+pub mod equivalent_time;
+pub mod link_stats;
+pub mod math_channels;
 mod run_loops;
+pub mod transport;
 
-enum NlabHandle {
-    NlabLegacy(HidDevice),
-    Nlab(rusb::DeviceHandle<rusb::GlobalContext>),
+/// Reasons a request to the nLab can fail
+#[derive(Debug, Clone, PartialEq)]
+pub enum RequestError {
+    /// The nLab did not answer the request, even after retrying
+    Timeout,
+    /// The request could not be carried out, with a description of why
+    Rejected(String),
+    /// The connection to the nLab has been closed
+    Disconnected,
 }
 
+impl fmt::Display for RequestError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RequestError::Timeout => write!(f, "nLab did not respond to the request"),
+            RequestError::Rejected(reason) => write!(f, "nLab request rejected: {reason}"),
+            RequestError::Disconnected => write!(f, "nLab connection closed"),
+        }
+    }
+}
+
+impl Error for RequestError {}
+
 /// Primary interface to the nLab, used to set outputs,
 /// trigger sweeps of input data on scope channels, and monitor power state
 pub struct Nlab {
@@ -56,7 +94,17 @@ pub struct Nlab {
 
     fw_version: Arc<RwLock<Option<u16>>>,
     power_status: Arc<RwLock<PowerStatus>>,
-    command_tx: Sender<Command>,
+    power_monitor: Arc<RwLock<PowerMonitor>>,
+    capabilities: Arc<RwLock<Capabilities>>,
+    link_stats: Arc<RwLock<LinkStats>>,
+    safe_state: Arc<RwLock<SafeState>>,
+    serial: Option<String>,
+    calibration: Calibration,
+    over_range_callback: Option<OverRangeCallback>,
+    acquisition_mode: AcquisitionMode,
+    deskew: bool,
+    math_channels: Vec<MathChannel>,
+    command_tx: CommandSender,
     join_handle: Option<JoinHandle<()>>,
 }
 
@@ -69,44 +117,63 @@ impl fmt::Debug for Nlab {
 impl Nlab {
     /// Create a new Nlab object
     pub(crate) fn new(dev: &NlabDevice, power_on: bool) -> Result<Self, Box<dyn Error>> {
-        let device_handle: NlabHandle = match dev {
+        match dev {
             NlabDevice::HidApiDevice { device, api } => {
-                let api = api.read().unwrap();
-                NlabHandle::NlabLegacy(device.open_device(&api)?)
+                let hid_device = device.open_device(&api.read().unwrap())?;
+                let serial = device.serial_number().map(str::to_string);
+                Nlab::start(HidTransport::new(hid_device), true, power_on, serial)
             }
             NlabDevice::RusbDevice(device) => {
-                NlabHandle::Nlab(device.open()?)
+                let usb_device = device.open()?;
+                let serial = usb_device.read_serial_number_string_ascii(&device.device_descriptor()?).ok();
+                usb_device.claim_interface(0)?;
+                // Keep reads queued on the status endpoint and all four channel endpoints
+                let transport = UsbTransport::new(usb_device, &[0x81, 0x82, 0x83, 0x84, 0x85])?;
+                Nlab::start(transport, false, power_on, serial)
             }
-        };
+        }
+    }
 
+    /// Connects to an nLab over a custom transport, such as a network bridge or a simulator
+    ///
+    /// The transport must speak the nLab v2 protocol.
+    pub fn with_transport<T: Transport + 'static>(transport: T, power_on: bool) -> Result<Self, Box<dyn Error>> {
+        Nlab::start(transport, false, power_on, None)
+    }
+
+    fn start<T: Transport + 'static>(transport: T, is_legacy: bool, power_on: bool, serial: Option<String>) -> Result<Self, Box<dyn Error>> {
         // Create communication channels to scope
         let (command_tx, command_rx) = mpsc::channel::<Command>();
+        let command_tx = CommandSender::new(command_tx, transport.waker());
 
         let fw_version = Arc::new(RwLock::new(None));
         let power_status = Arc::new(RwLock::new(PowerStatus::default()));
+        let power_monitor = Arc::new(RwLock::new(PowerMonitor::default()));
+        let capabilities = Arc::new(RwLock::new(match is_legacy {
+            true => Capabilities::legacy(),
+            false => Capabilities::v2_default(),
+        }));
+        let link_stats = Arc::new(RwLock::new(LinkStats::new()));
+        let safe_state = Arc::new(RwLock::new(SafeState::default()));
 
         let backend_command_tx = command_tx.clone();
-        let backend_fw_version = fw_version.clone();
-        let backend_power_status = power_status.clone();
+        let backend_state = SharedState {
+            fw_version: fw_version.clone(),
+            power_status: power_status.clone(),
+            power_monitor: power_monitor.clone(),
+            capabilities: capabilities.clone(),
+            link_stats: link_stats.clone(),
+            safe_state: safe_state.clone(),
+        };
 
         // Create the communication thread
         let communication_thread = thread::Builder::new().name("Communication Thread".to_string());
-
-        let mut is_legacy = false;
-        let join_handle = match device_handle {
-            NlabHandle::NlabLegacy(hid_device) => {
-                is_legacy = true;
-                communication_thread.spawn(move || {
-                    Nlab::run_v1(hid_device, backend_command_tx, command_rx, backend_fw_version, backend_power_status);
-                }).ok()
-            }
-            NlabHandle::Nlab(usb_device) => {
-                usb_device.claim_interface(0)?;
-                communication_thread.spawn(move || {
-                    Nlab::run_v2(usb_device, backend_command_tx, command_rx, backend_fw_version, backend_power_status);
-                }).ok()
+        let join_handle = communication_thread.spawn(move || {
+            match is_legacy {
+                true => Nlab::run_v1(transport, backend_command_tx, command_rx, backend_state),
+                false => Nlab::run_v2(transport, backend_command_tx, command_rx, backend_state),
             }
-        };
+        }).ok();
 
         let scope = Nlab {
             a1: AnalogOutput::create(command_tx.clone(), 0),
@@ -119,6 +186,16 @@ impl Nlab {
             ch4: AnalogInput::create(is_legacy),
             fw_version,
             power_status,
+            power_monitor,
+            capabilities,
+            link_stats,
+            safe_state,
+            calibration: Calibration::new(serial.as_deref().unwrap_or_default()),
+            serial,
+            over_range_callback: None,
+            acquisition_mode: AcquisitionMode::Normal,
+            deskew: false,
+            math_channels: Vec::new(),
             command_tx,
             join_handle,
         };
@@ -169,6 +246,26 @@ impl Nlab {
         self.fw_version.read().unwrap().ok_or_else(|| "Cannot read nLab version".into())
     }
 
+    /// Returns the features and limits reported by the nLab firmware
+    pub fn capabilities(&self) -> Capabilities {
+        *self.capabilities.read().unwrap()
+    }
+
+    /// Returns the sample rates available with the scope channels that are turned on
+    pub fn sample_rates(&self) -> SampleRates {
+        self.capabilities().sample_rates(self.channels_on())
+    }
+
+    /// Returns the longest sweep available at a sample rate with the scope channels that are
+    /// turned on, which is `u32::MAX` when the sweep streams
+    pub fn max_samples_at(&self, sample_rate_hz: f64) -> u32 {
+        self.capabilities().max_samples_at(sample_rate_hz, self.channels_on())
+    }
+
+    fn channels_on(&self) -> usize {
+        [&self.ch1, &self.ch2, &self.ch3, &self.ch4].iter().filter(|ch| ch.is_on).count()
+    }
+
     pub fn analog_output(&self, channel: usize) -> Option<&AnalogOutput> {
         match channel {
             1 => Some(&self.a1),
@@ -0,0 +1,306 @@
+/***************************************************************************************************
+ *
+ *  nLabs, LLC
+ *  https://getnlab.com
+ *  Copyright(c) 2020. All Rights Reserved
+ *
+ *  This file is part of the nLab API
+ *
+ **************************************************************************************************/
+
+use std::f64::consts::PI;
+
+use log::warn;
+
+use super::capabilities::Capabilities;
+use super::data_requests::{Sample, SampleBlock};
+use super::Nlab;
+
+/// Most readings taken by the nLab for each sample delivered
+pub const MAX_OVERSAMPLING: u32 = 256;
+
+/// How the readings of a sweep are reduced on the host to the requested sample rate
+///
+/// Every mode but `Normal` runs the nLab faster than the requested rate, choosing the fastest
+/// rate the nLab can take the whole sweep at, up to [`MAX_OVERSAMPLING`] readings per sample.
+#[derive(Debug, Default, Copy, Clone, PartialEq)]
+pub enum AcquisitionMode {
+    /// Each sample is a single reading
+    #[default]
+    Normal,
+    /// Each sample is the mean of consecutive readings, enough of them to reach the given
+    /// resolution in bits. Averaging four readings adds one bit to the 12 of the ADC.
+    Average { resolution_bits: f64 },
+    /// Readings are low-pass filtered below the Nyquist frequency of the requested rate, then
+    /// decimated, which rejects aliases better than averaging
+    Decimate { resolution_bits: f64 },
+    /// Each pair of samples is the smallest then the largest reading over the time they span,
+    /// so short glitches are not lost between samples
+    PeakDetect,
+}
+
+impl AcquisitionMode {
+    /// Number of readings the nLab takes for each sample of a sweep
+    pub(crate) fn oversampling(&self, sample_rate_hz: f64, number_of_samples: u32, capabilities: &Capabilities, channels_on: usize) -> u32 {
+        let wanted = match self {
+            AcquisitionMode::Normal => return 1,
+            AcquisitionMode::Average { resolution_bits } | AcquisitionMode::Decimate { resolution_bits } => {
+                4.0f64.powf(resolution_bits - 12.0).ceil().clamp(1.0, MAX_OVERSAMPLING as f64) as u32
+            }
+            AcquisitionMode::PeakDetect => MAX_OVERSAMPLING,
+        };
+
+        let factor = (1..=wanted).rev().find(|&factor| {
+            let rate = sample_rate_hz * factor as f64;
+            rate <= capabilities.max_sample_rate_with(channels_on)
+                && self.readings(number_of_samples, factor).is_some_and(|readings| readings <= capabilities.max_samples_at(rate, channels_on))
+        }).unwrap_or(1);
+
+        if factor < wanted && !matches!(self, AcquisitionMode::PeakDetect) {
+            warn!("The nLab cannot take {wanted} readings per sample at {sample_rate_hz} hz, taking {factor}");
+        }
+        factor
+    }
+
+    /// Number of readings the nLab takes for a sweep of `number_of_samples`, or `None` if there
+    /// are too many to ask the nLab for
+    pub(crate) fn readings(&self, number_of_samples: u32, factor: u32) -> Option<u32> {
+        match self {
+            AcquisitionMode::Normal | AcquisitionMode::Average { .. } => number_of_samples.checked_mul(factor),
+            // The filter looks ahead of the last sample
+            AcquisitionMode::Decimate { .. } => number_of_samples.checked_mul(factor)?.checked_add(filter_half_length(factor) as u32),
+            // Samples come in pairs
+            AcquisitionMode::PeakDetect => number_of_samples.div_ceil(2).checked_mul(2)?.checked_mul(factor),
+        }
+    }
+}
+
+/// Number of filter taps on each side of the center tap when decimating by `factor`
+fn filter_half_length(factor: u32) -> usize {
+    8 * factor as usize
+}
+
+/// Blackman-windowed sinc low-pass filter with unity gain, cutting off a third of the way to the
+/// decimated sample rate so its transition band ends at the decimated Nyquist frequency
+fn low_pass_filter(factor: u32) -> Vec<f64> {
+    let half_length = filter_half_length(factor) as isize;
+    let cutoff = 1.0 / (3.0 * factor as f64);
+    let length = (2 * half_length + 1) as f64;
+
+    let taps: Vec<f64> = (-half_length..=half_length).map(|n| {
+        let sinc = match n {
+            0 => 2.0 * cutoff,
+            n => (2.0 * PI * cutoff * n as f64).sin() / (PI * n as f64),
+        };
+        let x = 2.0 * PI * (n + half_length) as f64 / (length - 1.0);
+        sinc * (0.42 - 0.5 * x.cos() + 0.08 * (2.0 * x).cos())
+    }).collect();
+    let sum: f64 = taps.iter().sum();
+    taps.into_iter().map(|tap| tap / sum).collect()
+}
+
+/// Reduces the readings of a sweep to samples, keeping readings that do not yet fill a sample
+/// for the next block
+#[derive(Debug)]
+pub(crate) struct Reducer {
+    mode: AcquisitionMode,
+    factor: usize,
+    filter: Vec<f64>,
+    readings: [Vec<f32>; Sample::num_channels() as usize],
+    clipped: [Vec<bool>; Sample::num_channels() as usize],
+    open: [bool; Sample::num_channels() as usize],
+    samples_delivered: usize,
+    started: bool,
+}
+
+impl Reducer {
+    pub(crate) fn new(mode: AcquisitionMode, factor: u32) -> Self {
+        Reducer {
+            mode,
+            factor: factor as usize,
+            filter: match mode {
+                AcquisitionMode::Decimate { .. } => low_pass_filter(factor),
+                _ => Vec::new(),
+            },
+            readings: Default::default(),
+            clipped: Default::default(),
+            open: Default::default(),
+            samples_delivered: 0,
+            started: false,
+        }
+    }
+
+    /// Returns a reducer in the same mode, for a new sweep
+    pub(crate) fn restart(&self) -> Self {
+        Reducer::new(self.mode, self.factor as u32)
+    }
+
+    /// Returns the samples made from the readings in `block` and any left over before it
+    pub(crate) fn reduce(&mut self, block: SampleBlock) -> SampleBlock {
+        if self.mode == AcquisitionMode::Normal {
+            return block;
+        }
+
+        let dt = block.dt * self.factor as f64;
+        for (ch, readings) in block.channels.iter().enumerate() {
+            if let Some(readings) = readings {
+                // The filter is centered on the first reading, and sees copies of it before the sweep
+                if !self.started && !self.filter.is_empty() {
+                    let first = readings.first().copied().unwrap_or(f32::NAN);
+                    self.readings[ch].extend(std::iter::repeat_n(first, self.filter.len() / 2));
+                    self.clipped[ch].extend(std::iter::repeat_n(false, self.filter.len() / 2));
+                }
+                self.open[ch] = true;
+                self.readings[ch].extend_from_slice(readings);
+                self.clipped[ch].extend(block.clipped[ch].iter().flatten());
+            }
+        }
+        self.started = true;
+
+        // Averaged samples are timed at the middle of the readings they are made from
+        let offset = match self.mode {
+            AcquisitionMode::Average { .. } => (self.factor - 1) as f64 / 2.0 * block.dt,
+            _ => 0.0,
+        };
+        let mut reduced = SampleBlock {
+            start_time: self.samples_delivered as f64 * dt + offset,
+            dt,
+            skews: block.skews,
+            ..Default::default()
+        };
+
+        let open = self.open;
+        for ch in (0..open.len()).filter(|&ch| open[ch]) {
+            let (samples, clipped, consumed) = self.reduce_channel(ch);
+            reduced.channels[ch] = Some(samples);
+            reduced.clipped[ch] = Some(clipped);
+            self.readings[ch].drain(..consumed);
+            self.clipped[ch].drain(..consumed);
+        }
+        self.samples_delivered += reduced.len();
+        reduced
+    }
+
+    /// Returns the samples that can be made from the readings of a channel, whether each one
+    /// clipped, and the number of readings no longer needed
+    fn reduce_channel(&self, ch: usize) -> (Vec<f32>, Vec<bool>, usize) {
+        let readings = &self.readings[ch];
+        let clipped = &self.clipped[ch];
+        let factor = self.factor;
+        let mut samples = Vec::new();
+        let mut sample_clipped = Vec::new();
+
+        match self.mode {
+            AcquisitionMode::Normal => unreachable!(),
+            AcquisitionMode::Average { .. } => {
+                for (bin, bin_clipped) in readings.chunks_exact(factor).zip(clipped.chunks_exact(factor)) {
+                    let valid: Vec<f64> = bin.iter().filter(|r| !r.is_nan()).map(|&r| r as f64).collect();
+                    samples.push(match valid.len() {
+                        0 => f32::NAN,
+                        n => (valid.iter().sum::<f64>() / n as f64) as f32,
+                    });
+                    sample_clipped.push(bin_clipped.contains(&true));
+                }
+                (samples, sample_clipped, readings.len() / factor * factor)
+            }
+            AcquisitionMode::PeakDetect => {
+                for (bin, bin_clipped) in readings.chunks_exact(2 * factor).zip(clipped.chunks_exact(2 * factor)) {
+                    let valid = bin.iter().filter(|r| !r.is_nan());
+                    let min = valid.clone().copied().fold(f32::NAN, f32::min);
+                    let max = valid.copied().fold(f32::NAN, f32::max);
+                    samples.extend([min, max].iter());
+                    sample_clipped.extend([bin_clipped.contains(&true); 2].iter());
+                }
+                (samples, sample_clipped, readings.len() / (2 * factor) * 2 * factor)
+            }
+            AcquisitionMode::Decimate { .. } => {
+                let length = self.filter.len();
+                let mut start = 0;
+                while start + length <= readings.len() {
+                    let window = &readings[start..start + length];
+                    samples.push(match window.iter().any(|r| r.is_nan()) {
+                        true => f32::NAN,
+                        false => window.iter().zip(&self.filter).map(|(&r, tap)| r as f64 * tap).sum::<f64>() as f32,
+                    });
+                    let center = start + length / 2;
+                    sample_clipped.push(clipped[center.saturating_sub(factor / 2)..(center + factor / 2 + 1).min(clipped.len())].contains(&true));
+                    start += factor;
+                }
+                (samples, sample_clipped, start)
+            }
+        }
+    }
+}
+
+impl Nlab {
+    pub fn acquisition_mode(&self) -> AcquisitionMode {
+        self.acquisition_mode
+    }
+
+    /// Sets how the readings of sweeps requested with [`Nlab::request`] and
+    /// [`Nlab::request_blocks`] are reduced to samples
+    pub fn set_acquisition_mode(&mut self, mode: AcquisitionMode) {
+        self.acquisition_mode = mode;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn block(readings: &[f32], dt: f64) -> SampleBlock {
+        SampleBlock {
+            start_time: 0.0,
+            dt,
+            channels: [Some(readings.to_vec()), None, None, None],
+            clipped: [Some(vec![false; readings.len()]), None, None, None],
+            skews: [0.0; 4],
+            math: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn readings_are_reduced_across_blocks() {
+        let readings: Vec<f32> = (0..40).map(|i| i as f32).collect();
+
+        let mut average = Reducer::new(AcquisitionMode::Average { resolution_bits: 13.0 }, 4);
+        let first = average.reduce(block(&readings[..10], 1.0));
+        let second = average.reduce(block(&readings[10..], 1.0));
+        assert_eq!(first.channels[0], Some(vec![1.5, 5.5]));
+        assert_eq!(second.channels[0].as_ref().unwrap()[0], 9.5);
+        assert_eq!((first.dt, second.start_time), (4.0, 9.5));
+
+        let mut peak = Reducer::new(AcquisitionMode::PeakDetect, 4);
+        let peaks = peak.reduce(block(&readings, 1.0));
+        assert_eq!(peaks.channels[0].as_ref().unwrap()[..4], [0.0, 7.0, 8.0, 15.0]);
+        assert_eq!(peaks.len(), 10);
+
+        // The filter passes a constant signal unchanged, and makes one sample per factor readings
+        let factor = 4;
+        let samples = 10;
+        let mode = AcquisitionMode::Decimate { resolution_bits: 13.0 };
+        let mut decimate = Reducer::new(mode, factor);
+        let constant = vec![1.25f32; mode.readings(samples, factor).unwrap() as usize];
+        let decimated = decimate.reduce(block(&constant, 1.0));
+        assert_eq!(decimated.len(), samples as usize);
+        assert!(decimated.channels[0].as_ref().unwrap().iter().all(|v| (v - 1.25).abs() < 1e-5));
+    }
+
+    #[test]
+    fn oversampling_is_limited_by_the_nlab() {
+        let capabilities = Capabilities::v2_default();
+        let average = AcquisitionMode::Average { resolution_bits: 14.0 };
+        assert_eq!(average.oversampling(100.0, 10_000, &capabilities, 1), 16);
+        assert_eq!(AcquisitionMode::PeakDetect.oversampling(100.0, 10_000, &capabilities, 1), MAX_OVERSAMPLING);
+        // Streaming tops out at 80 khz, and 20 khz sweeps of this length cannot be buffered
+        assert_eq!(average.oversampling(20_000.0, 10_000, &capabilities, 1), 4);
+
+        // Streamed sweeps can be of any length, but their readings must still be countable
+        let peak = AcquisitionMode::PeakDetect;
+        let factor = peak.oversampling(100.0, 20_000_000, &capabilities, 1);
+        assert_eq!(factor, 214);
+        assert_eq!(peak.readings(20_000_000, factor), Some(20_000_000 * 214));
+        assert_eq!(peak.readings(20_000_000, factor + 1), None);
+        assert_eq!(AcquisitionMode::Decimate { resolution_bits: 16.0 }.readings(u32::MAX, 1), None);
+    }
+}
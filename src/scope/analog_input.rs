@@ -13,6 +13,7 @@ mod voltages;
 
 use voltages_legacy::AnalogInterfaceLegacy;
 use voltages::AnalogInterfaceModern;
+use super::calibration::Correction;
 
 #[derive(Debug, Copy, Clone)]
 enum AnalogInterface {
@@ -20,11 +21,53 @@ enum AnalogInterface {
     Modern(AnalogInterfaceModern)
 }
 
+/// Largest code produced by the 12-bit ADC of a scope channel
+pub const ADC_MAX_CODE: u16 = 4095;
+
+/// Codes this close to either end of the ADC range are treated as clipped
+pub const RAIL_MARGIN: u16 = 4;
+
+/// End of the ADC range a reading has run into
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Rail {
+    Low,
+    High,
+}
+
+impl Rail {
+    /// Returns the rail an ADC code is at or near, if any
+    pub fn of(code: u16) -> Option<Rail> {
+        if code <= RAIL_MARGIN {
+            Some(Rail::Low)
+        } else if (ADC_MAX_CODE - RAIL_MARGIN..=ADC_MAX_CODE).contains(&code) {
+            Some(Rail::High)
+        } else {
+            None
+        }
+    }
+}
+
+/// Linear conversion from the ADC codes of a scope channel to volts
+///
+/// `volts = code * scale + offset`
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Conversion {
+    pub scale: f64,
+    pub offset: f64,
+}
+
+impl Conversion {
+    pub fn volts(&self, code: u16) -> f64 {
+        code as f64 * self.scale + self.offset
+    }
+}
+
 /// Interface to a single scope channel
 #[derive(Debug, Copy, Clone)]
 pub struct AnalogInput {
     pub(crate) is_on: bool,
     analog_interface: AnalogInterface,
+    correction: Correction,
 }
 
 impl AnalogInput {
@@ -37,12 +80,14 @@ impl AnalogInput {
                         gain_setting: 0,
                         offset_setting: 0,
                     }),
+                correction: Correction::default(),
             },
             false => AnalogInput {
                 is_on: true,
                 analog_interface: AnalogInterface::Modern(
                     AnalogInterfaceModern {
                     }),
+                correction: Correction::default(),
             }
         };
         analog_input.set_range(-5.0, 5.0);
@@ -63,13 +108,28 @@ impl AnalogInput {
         self.is_on = false;
     }
 
+    /// Sets the gain and offset of the channel to read from `vmin` to `vmax` volts
+    ///
+    /// On the nLab v1 the setting takes effect with the next sweep. Inputs with a fixed gain
+    /// ignore it. Releases up to 1.0.6 made the setting on a copy of the channel, so it never
+    /// reached the nLab and legacy channels always ran at gain code 0 and offset code 0. They
+    /// now default to offset code 31, which centers the ±5 V range on 0 V rather than -0.05 V.
     pub fn set_range(&mut self, vmin: f64, vmax: f64) {
         match self.analog_interface {
-            AnalogInterface::Legacy(mut interface) => { interface.set_range(vmin, vmax) }
-            AnalogInterface::Modern(mut interface) => { interface.set_range(vmin, vmax) }
+            AnalogInterface::Legacy(ref mut interface) => { interface.set_range(vmin, vmax) }
+            AnalogInterface::Modern(ref mut interface) => { interface.set_range(vmin, vmax) }
         }
     }
 
+    /// Range the channel is set to, as the (min, max) volts read at the bottom and top ADC codes
+    ///
+    /// This is the range the nLab applied, which is the full range on inputs with a fixed gain,
+    /// whatever was asked of [`AnalogInput::set_range`].
+    pub fn range(&self) -> (f64, f64) {
+        let conversion = self.conversion();
+        (conversion.volts(0), conversion.volts(ADC_MAX_CODE))
+    }
+
     pub fn gain(&self) -> f64 {
         match self.analog_interface {
             AnalogInterface::Legacy(interface) => { interface.gain() }
@@ -78,17 +138,41 @@ impl AnalogInput {
     }
 
     pub(crate) fn measurement_from_voltage(&self, voltage: f64) -> i16 {
+        let voltage = self.correction.remove(voltage);
         match self.analog_interface {
             AnalogInterface::Legacy(interface) => { interface.measurement_from_voltage(voltage) }
             AnalogInterface::Modern(interface) => { interface.measurement_from_voltage(voltage) }
         }
     }
 
+    /// Returns the conversion from ADC codes to volts at the current range of the channel,
+    /// including any calibration correction
+    pub fn conversion(&self) -> Conversion {
+        let nominal = match self.analog_interface {
+            AnalogInterface::Legacy(interface) => { interface.conversion() }
+            AnalogInterface::Modern(interface) => { interface.conversion() }
+        };
+        Conversion {
+            scale: nominal.scale * self.correction.gain,
+            offset: self.correction.apply(nominal.offset),
+        }
+    }
+
     pub(crate) fn voltage_from_measurement(&self, adc_data: u16) -> f64 {
-        match self.analog_interface {
+        let voltage = match self.analog_interface {
             AnalogInterface::Legacy(interface) => { interface.voltage_from_measurement(adc_data) }
             AnalogInterface::Modern(interface) => { interface.voltage_from_measurement(adc_data) }
-        }
+        };
+        self.correction.apply(voltage)
+    }
+
+    /// Identifies the range the channel is set to, as the settings sent to the nLab
+    pub(crate) fn range_key(&self) -> (u8, u8) {
+        (self.gain_cmd(), self.offset_cmd())
+    }
+
+    pub(crate) fn set_correction(&mut self, correction: Correction) {
+        self.correction = correction;
     }
 
     pub(crate) fn gain_cmd(&self) -> u8 {
@@ -106,3 +190,23 @@ impl AnalogInput {
     }
 }
 
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn set_range_changes_the_legacy_settings() {
+        let mut input = AnalogInput::create(true);
+        assert_eq!(input.range_key(), (0, 31));
+
+        input.set_range(0.0, 2.0);
+        assert_eq!((input.gain_cmd(), input.offset_cmd()), (51, 39));
+        assert_eq!(input.range_key(), (51, 39));
+        let (min, max) = input.range();
+        assert!(min.abs() < 0.1 && (max - 2.0).abs() < 0.1);
+
+        input.set_range(-5.0, 5.0);
+        assert_eq!(input.range_key(), (0, 31));
+    }
+}
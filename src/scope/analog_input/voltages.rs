@@ -1,3 +1,5 @@
+use super::Conversion;
+
 #[derive(Debug, Copy, Clone)]
 pub(super) struct AnalogInterfaceModern {}
 
@@ -19,11 +21,17 @@ impl AnalogInterfaceModern {
     }
 
     pub(super) fn voltage_from_measurement(&self, adc_data: u16) -> f64 {
+        self.conversion().volts(adc_data)
+    }
+
+    pub(super) fn conversion(&self) -> Conversion {
         let gain = 1.0f64;
         let v_offset = 0.0f64;
 
-        let adc_voltage = adc_data as f64 * 2.5 / 4095.0;
-        ((adc_voltage / gain + v_offset * (gain - 1.0) / (gain)) - 1.25) * 10.0 / 2.5
+        Conversion {
+            scale: 2.5 / 4095.0 / gain * 10.0 / 2.5,
+            offset: (v_offset * (gain - 1.0) / gain - 1.25) * 10.0 / 2.5,
+        }
     }
 
     pub(super) fn set_range(&mut self, vmin: f64, vmax: f64) {
@@ -1,3 +1,5 @@
+use super::Conversion;
+
 const DELTA1: f64 = 1.65;
 const DELTA2: f64 = 3.3 / 10.0;
 
@@ -45,14 +47,20 @@ impl AnalogInterfaceLegacy {
     }
 
     pub(super) fn voltage_from_measurement(&self, adc_data: u16) -> f64 {
-        let adc_reading = adc_data as f64;
+        self.conversion().volts(adc_data)
+    }
+
+    pub(super) fn conversion(&self) -> Conversion {
         let ch_gain = self.gain_setting as f64;
         let ch_level = self.offset_setting as f64;
 
         let gain = 1.0 + ALPHA1 + ALPHA2 * ch_gain;
         let level = (ch_level * (BETA1 + BETA2 * ch_gain) - DELTA1 * (gain - 1.0)) / DELTA2 / gain;
 
-        10.0 / gain * (adc_reading - 2047.0) / 4095.0 + level
+        Conversion {
+            scale: 10.0 / gain / 4095.0,
+            offset: level - 10.0 / gain * 2047.0 / 4095.0,
+        }
     }
 
     pub(super) fn set_range(&mut self, vmin: f64, vmax: f64) {
@@ -14,9 +14,13 @@ use std::sync::{mpsc, RwLock};
 use std::sync::mpsc::Sender;
 #[cfg(feature = "python_support")] use pyo3::pyclass;
 
+use log::warn;
+
+use crate::scope::capabilities::Capabilities;
 use crate::scope::commands::ScopeCommand;
+use crate::scope::RequestError;
 
-use super::commands::Command;
+use super::commands::{Command, CommandSender};
 
 /// Possible analog output signal types
 #[derive(Debug, PartialEq, Copy, Clone)]
@@ -70,19 +74,25 @@ struct AnalogOutputState {
 #[derive(Debug)]
 pub struct AnalogOutput {
     pub channel: usize,
-    command_tx: Sender<Command>,
+    command_tx: CommandSender,
     state: RwLock<AnalogOutputState>,
 }
 
-impl AnalogOutput {
-    pub(super) fn create(cmd_tx: Sender<Command>, ax_channel: usize) -> Self {
-        let default_state = AnalogOutputState {
+impl Default for AnalogOutputState {
+    fn default() -> Self {
+        AnalogOutputState {
             is_on: false,
             frequency: 1.0,
             amplitude: 1.0,
             wave_type: AnalogWaveType::Sine,
             polarity: AnalogSignalPolarity::Unipolar,
-        };
+        }
+    }
+}
+
+impl AnalogOutput {
+    pub(super) fn create(cmd_tx: CommandSender, ax_channel: usize) -> Self {
+        let default_state = AnalogOutputState::default();
 
         let ax = AnalogOutput {
             command_tx: cmd_tx,
@@ -90,13 +100,13 @@ impl AnalogOutput {
             state: RwLock::new(default_state),
         };
 
-        ax.set(default_state);
+        ax.set(default_state).ok();
         ax
     }
 
-    fn set(&self, ax_state: AnalogOutputState) {
+    fn set(&self, ax_state: AnalogOutputState) -> Result<(), RequestError> {
         // Create a method for the backend to communicate back to us what we want
-        let (tx, rx) = mpsc::channel::<AnalogOutputState>();
+        let (tx, rx) = mpsc::channel::<Result<AnalogOutputState, RequestError>>();
 
         // Create the command to set an analog output
         let command = Command::SetAnalogOutput(AxRequest {
@@ -107,14 +117,14 @@ impl AnalogOutput {
         });
 
         // Send the command to the backend
-        if self.command_tx.send(command).is_ok() {
+        self.command_tx.send(command)?;
 
-            // Wait for the response from the backend
-            if let Ok(response_state) = rx.recv() {
-                // Write the response state
-                *self.state.write().unwrap() = response_state;
-            }
-        }
+        // Wait for the response from the backend, which reports requests that go unanswered
+        let response_state = rx.recv().map_err(|_| RequestError::Disconnected)??;
+
+        // Write the response state
+        *self.state.write().unwrap() = response_state;
+        Ok(())
     }
 
     pub fn is_on(&self) -> bool {
@@ -134,40 +144,82 @@ impl AnalogOutput {
     }
 
 
-    pub fn turn_on(&self) {
+    pub fn try_turn_on(&self) -> Result<(), RequestError> {
         let mut state = *self.state.read().unwrap();
         state.is_on = true;
         self.set(state)
     }
-    pub fn turn_off(&self) {
+    pub fn try_turn_off(&self) -> Result<(), RequestError> {
         let mut state = *self.state.read().unwrap();
         state.is_on = false;
         self.set(state)
     }
 
-    pub fn set_frequency(&self, desired_hz: f64) {
+    pub fn try_set_frequency(&self, desired_hz: f64) -> Result<(), RequestError> {
         let mut state = *self.state.read().unwrap();
         state.frequency = desired_hz;
         self.set(state)
     }
 
-    pub fn set_amplitude(&self, desired_volts: f64) {
+    pub fn try_set_amplitude(&self, desired_volts: f64) -> Result<(), RequestError> {
         let mut state = *self.state.read().unwrap();
         state.amplitude = desired_volts;
         self.set(state)
     }
 
-    pub fn set_wave_type(&self, wave_type: AnalogWaveType) {
+    pub fn try_set_wave_type(&self, wave_type: AnalogWaveType) -> Result<(), RequestError> {
         let mut state = *self.state.read().unwrap();
         state.wave_type = wave_type;
         self.set(state)
     }
 
-    pub fn set_polarity(&self, polarity: AnalogSignalPolarity) {
+    pub fn try_set_polarity(&self, polarity: AnalogSignalPolarity) -> Result<(), RequestError> {
         let mut state = *self.state.read().unwrap();
         state.polarity = polarity;
         self.set(state)
     }
+
+    /// Same as [`AnalogOutput::try_turn_on`], logging any failure instead of returning it
+    pub fn turn_on(&self) {
+        if let Err(error) = self.try_turn_on() {
+            warn!("Cannot set analog output A{}: {error}", self.channel + 1);
+        }
+    }
+
+    /// Same as [`AnalogOutput::try_turn_off`], logging any failure instead of returning it
+    pub fn turn_off(&self) {
+        if let Err(error) = self.try_turn_off() {
+            warn!("Cannot set analog output A{}: {error}", self.channel + 1);
+        }
+    }
+
+    /// Same as [`AnalogOutput::try_set_frequency`], logging any failure instead of returning it
+    pub fn set_frequency(&self, desired_hz: f64) {
+        if let Err(error) = self.try_set_frequency(desired_hz) {
+            warn!("Cannot set analog output A{}: {error}", self.channel + 1);
+        }
+    }
+
+    /// Same as [`AnalogOutput::try_set_amplitude`], logging any failure instead of returning it
+    pub fn set_amplitude(&self, desired_volts: f64) {
+        if let Err(error) = self.try_set_amplitude(desired_volts) {
+            warn!("Cannot set analog output A{}: {error}", self.channel + 1);
+        }
+    }
+
+    /// Same as [`AnalogOutput::try_set_wave_type`], logging any failure instead of returning it
+    pub fn set_wave_type(&self, wave_type: AnalogWaveType) {
+        if let Err(error) = self.try_set_wave_type(wave_type) {
+            warn!("Cannot set analog output A{}: {error}", self.channel + 1);
+        }
+    }
+
+    /// Same as [`AnalogOutput::try_set_polarity`], logging any failure instead of returning it
+    pub fn set_polarity(&self, polarity: AnalogSignalPolarity) {
+        if let Err(error) = self.try_set_polarity(polarity) {
+            warn!("Cannot set analog output A{}: {error}", self.channel + 1);
+        }
+    }
 }
 
 
@@ -175,10 +227,28 @@ impl AnalogOutput {
 pub(crate) struct AxRequest {
     channel: usize,
     ax_state: AnalogOutputState,
-    sender: Sender<AnalogOutputState>,
+    sender: Sender<Result<AnalogOutputState, RequestError>>,
+}
+
+impl AxRequest {
+    /// A request that turns off an analog output, for which nobody waits on the response
+    pub(crate) fn off(channel: usize) -> Self {
+        AxRequest {
+            channel,
+            ax_state: AnalogOutputState::default(),
+            sender: mpsc::channel().0,
+        }
+    }
 }
 
 impl ScopeCommand for AxRequest {
+    fn check_capabilities(&self, capabilities: &Capabilities) -> Result<(), Box<dyn Error>> {
+        if self.ax_state.is_on && !capabilities.supports_wave_type(self.ax_state.wave_type) {
+            return Err(format!("{:?} waves are not supported by the nLab firmware", self.ax_state.wave_type).into());
+        }
+        Ok(())
+    }
+
     fn fill_tx_buffer_legacy(&self, usb_buf: &mut [u8; 65]) -> Result<(), Box<dyn Error>> {
         usb_buf[1] = 0x02;
 
@@ -241,11 +311,15 @@ impl ScopeCommand for AxRequest {
     }
 
     fn handle_rx_legacy(&self, _usb_buf: &[u8; 64]) {
-        self.sender.send(self.ax_state).unwrap();
+        self.sender.send(Ok(self.ax_state)).ok();
     }
 
     fn handle_rx(&self, _usb_buf: &[u8; 64]) {
-        self.sender.send(self.ax_state).unwrap();
+        self.sender.send(Ok(self.ax_state)).ok();
+    }
+
+    fn handle_failure(&self, error: RequestError) {
+        self.sender.send(Err(error)).ok();
     }
 
     fn is_finished(&self) -> bool {
@@ -0,0 +1,325 @@
+/***************************************************************************************************
+ *
+ *  nLabs, LLC
+ *  https://getnlab.com
+ *  Copyright(c) 2020. All Rights Reserved
+ *
+ *  This file is part of the nLab API
+ *
+ **************************************************************************************************/
+
+use std::error::Error;
+
+use log::{debug, warn};
+
+use super::analog_input::{AnalogInput, Rail};
+use super::data_requests::{RawBlock, Sample};
+use super::Nlab;
+use super::trigger::{Trigger, TriggerType};
+
+/// Number of samples in each probe sweep
+const PROBE_SAMPLES: u32 = 500;
+/// Sample rate of the first probe sweep
+const FIRST_PROBE_RATE_HZ: f64 = 10_000.0;
+/// Slowest probe sweep, which sets the lowest frequency autoset can find
+const MIN_PROBE_RATE_HZ: f64 = 1_000.0;
+const MAX_PROBES: usize = 8;
+/// Number of periods a probe must see to measure the frequency, and at most should see
+const PROBE_PERIODS: (usize, usize) = (2, 100);
+/// Signals with a smaller peak to peak voltage are treated as constant
+const MIN_PEAK_TO_PEAK: f64 = 0.05;
+/// Periods shown by the chosen sample rate
+const DISPLAY_PERIODS: f64 = 3.0;
+const DISPLAY_SAMPLES: u32 = 1000;
+/// Fraction of the range left free above and below the signal
+const RANGE_MARGIN: f64 = 0.2;
+/// The widest range of a scope channel
+const FULL_RANGE: (f64, f64) = (-5.0, 5.0);
+
+/// Settings chosen by [`Nlab::autoset`]
+#[derive(Debug, Copy, Clone)]
+pub struct AutosetConfig {
+    /// Range each channel that was probed is left at, as (min, max) volts, see
+    /// [`AnalogInput::range`]
+    pub ranges: [Option<(f64, f64)>; Sample::num_channels() as usize],
+    pub sample_rate_hz: f64,
+    pub number_of_samples: u32,
+    /// Rising edge trigger at the middle of the dominant channel, or disabled if no channel
+    /// carries a periodic signal
+    pub trigger: Trigger,
+    /// Frequency of the dominant channel, if it carries a periodic signal
+    pub frequency_hz: Option<f64>,
+    /// Whether the probes settled on a sample rate for the dominant channel
+    ///
+    /// A signal whose period count jumps past the window the probes look for as the rate
+    /// changes, such as a burst or a noisy signal, sends them back and forth between rates.
+    /// The frequency measured then cannot be trusted, so `frequency_hz` is left unset.
+    pub settled: bool,
+}
+
+/// Extent and period count of the readings of one channel
+#[derive(Debug, Copy, Clone, PartialEq)]
+struct SignalShape {
+    min: f64,
+    max: f64,
+    /// Whole periods between the first and last rising crossing of the middle
+    periods: usize,
+    /// Mean length of those periods, in samples
+    period_samples: f64,
+    clipped: bool,
+}
+
+impl SignalShape {
+    fn peak_to_peak(&self) -> f64 {
+        self.max - self.min
+    }
+
+    fn middle(&self) -> f64 {
+        (self.max + self.min) / 2.0
+    }
+
+    /// Measures the readings of a channel, counting periods as rising crossings of the middle
+    /// with a tenth of the peak to peak voltage of hysteresis
+    fn measure(codes: &[u16], volts: &[f32]) -> Option<SignalShape> {
+        let valid = volts.iter().filter(|v| !v.is_nan()).map(|&v| v as f64);
+        let min = valid.clone().fold(f64::INFINITY, f64::min);
+        let max = valid.fold(f64::NEG_INFINITY, f64::max);
+        if min > max {
+            return None;
+        }
+
+        let middle = (max + min) / 2.0;
+        let hysteresis = (max - min) / 10.0;
+        let mut below = None;
+        let mut crossings = Vec::new();
+        for (i, &v) in volts.iter().enumerate().filter(|(_, v)| !v.is_nan()) {
+            let v = v as f64;
+            if v < middle - hysteresis {
+                below = Some(true);
+            } else if v > middle + hysteresis {
+                if below == Some(true) {
+                    crossings.push(i);
+                }
+                below = Some(false);
+            }
+        }
+
+        let periods = crossings.len().saturating_sub(1);
+        Some(SignalShape {
+            min,
+            max,
+            periods,
+            period_samples: match (crossings.first(), crossings.last()) {
+                (Some(first), Some(last)) if periods > 0 => (last - first) as f64 / periods as f64,
+                _ => f64::INFINITY,
+            },
+            clipped: codes.iter().any(|&code| Rail::of(code).is_some()),
+        })
+    }
+
+    /// Range that fits the signal with a margin, or the full range if it has clipped
+    fn range(&self) -> (f64, f64) {
+        if self.clipped {
+            return FULL_RANGE;
+        }
+        let half_span = (self.peak_to_peak().max(MIN_PEAK_TO_PEAK) / 2.0) * (1.0 + 2.0 * RANGE_MARGIN);
+        ((self.middle() - half_span).max(FULL_RANGE.0), (self.middle() + half_span).min(FULL_RANGE.1))
+    }
+
+    /// Sets the range that fits the signal on `input`, returning the range the input ended up
+    /// with, which is wider where its gain cannot be set that finely or at all
+    fn apply_range(&self, input: &mut AnalogInput) -> (f64, f64) {
+        let (min, max) = self.range();
+        input.set_range(min, max);
+        input.range()
+    }
+}
+
+impl Nlab {
+    /// Probes the scope channels, numbered from 1, with quick sweeps, and chooses a range for
+    /// each, a sample rate that shows a few periods, and a trigger on the channel with the
+    /// largest signal
+    ///
+    /// The ranges are set on the channels, which are left on, and every other channel is turned
+    /// off. The sample rate and trigger are returned for the sweeps that follow.
+    pub fn autoset(&mut self, channels: &[usize]) -> Result<AutosetConfig, Box<dyn Error>> {
+        let channels: Vec<usize> = channels.iter().map(|ch| ch.wrapping_sub(1)).collect();
+        if channels.is_empty() || channels.iter().any(|&ch| ch >= Sample::num_channels() as usize) {
+            return Err("Autoset needs at least one channel, numbered 1 to 4".into());
+        }
+        for (ch, input) in [&mut self.ch1, &mut self.ch2, &mut self.ch3, &mut self.ch4].iter_mut().enumerate() {
+            if channels.contains(&ch) {
+                input.turn_on();
+                input.set_range(FULL_RANGE.0, FULL_RANGE.1);
+            } else {
+                input.turn_off();
+            }
+        }
+
+        let capabilities = self.capabilities();
+        let max_rate = capabilities.max_sample_rate_with(channels.len());
+        let first_rate = FIRST_PROBE_RATE_HZ.min(max_rate);
+        let (rate, shapes, settled) = search_probe_rates(first_rate, max_rate, |rate| self.probe(rate))?;
+
+        let mut config = AutosetConfig {
+            ranges: [None; Sample::num_channels() as usize],
+            sample_rate_hz: rate,
+            number_of_samples: DISPLAY_SAMPLES,
+            trigger: Trigger::default(),
+            frequency_hz: None,
+            settled,
+        };
+
+        for (ch, input) in [&mut self.ch1, &mut self.ch2, &mut self.ch3, &mut self.ch4].iter_mut().enumerate() {
+            if let Some(shape) = shapes[ch] {
+                config.ranges[ch] = Some(shape.apply_range(input));
+            }
+        }
+
+        if let Some(ch) = dominant_channel(&shapes) {
+            let shape = shapes[ch].unwrap();
+            if settled && shape.periods >= PROBE_PERIODS.0 {
+                let frequency = rate / shape.period_samples;
+                let display_rate = capabilities.sample_rates(channels.len()).nearest(frequency * DISPLAY_SAMPLES as f64 / DISPLAY_PERIODS);
+                config.frequency_hz = Some(frequency);
+                config.sample_rate_hz = display_rate;
+                config.number_of_samples = DISPLAY_SAMPLES.min(capabilities.max_samples_at(display_rate, channels.len()));
+            }
+            config.trigger = Trigger {
+                is_enabled: config.frequency_hz.is_some() && capabilities.supports_trigger(TriggerType::RisingEdge),
+                trigger_type: TriggerType::RisingEdge,
+                source_channel: ch,
+                trigger_level: shape.middle(),
+                trigger_delay_us: 0,
+            };
+        }
+        Ok(config)
+    }
+
+    /// Takes a quick sweep at `sample_rate_hz`, measuring each open channel
+    fn probe(&self, sample_rate_hz: f64) -> Result<[Option<SignalShape>; 4], Box<dyn Error>> {
+        let sweep = self.request_raw(sample_rate_hz, PROBE_SAMPLES, None, PROBE_SAMPLES as usize);
+        let blocks: Vec<RawBlock> = sweep.receiver.iter().collect();
+        if let Some(error) = sweep.error() {
+            return Err(error.into());
+        }
+
+        let mut shapes = [None; Sample::num_channels() as usize];
+        for (ch, shape) in shapes.iter_mut().enumerate() {
+            let codes: Vec<u16> = blocks.iter().filter_map(|block| block.channels[ch].as_ref()).flatten().copied().collect();
+            let volts: Vec<f32> = blocks.iter().filter_map(|block| block.to_volts().channels[ch].clone()).flatten().collect();
+            if !codes.is_empty() {
+                *shape = SignalShape::measure(&codes, &volts);
+            }
+        }
+        Ok(shapes)
+    }
+}
+
+/// Probes from `first_rate` until the dominant channel shows a measurable number of periods,
+/// returning the last rate probed, the shapes measured at it, and whether the search settled
+///
+/// The search has not settled if it would probe a rate again, or runs out of probes.
+fn search_probe_rates<E>(
+    first_rate: f64,
+    max_rate: f64,
+    mut probe: impl FnMut(f64) -> Result<[Option<SignalShape>; 4], E>,
+) -> Result<(f64, [Option<SignalShape>; 4], bool), E> {
+    let mut rate = first_rate;
+    let mut probed_rates = Vec::new();
+    loop {
+        let shapes = probe(rate)?;
+        probed_rates.push(rate);
+        let dominant = match dominant_channel(&shapes) {
+            Some(ch) => shapes[ch].unwrap(),
+            None => return Ok((rate, shapes, true)),
+        };
+        debug!("Autoset probe at {rate} hz saw {} periods", dominant.periods);
+
+        let next_rate = if dominant.periods < PROBE_PERIODS.0 {
+            (rate / 10.0).max(MIN_PROBE_RATE_HZ)
+        } else if dominant.periods > PROBE_PERIODS.1 {
+            (rate * 10.0).min(max_rate)
+        } else {
+            return Ok((rate, shapes, true));
+        };
+        if next_rate == rate {
+            return Ok((rate, shapes, true));
+        }
+        if probed_rates.contains(&next_rate) || probed_rates.len() == MAX_PROBES {
+            warn!("Autoset probes did not settle on a sample rate, the signal may be bursting or noisy");
+            return Ok((rate, shapes, false));
+        }
+        rate = next_rate;
+    }
+}
+
+/// Channel with the largest signal, if any channel carries more than a constant voltage
+fn dominant_channel(shapes: &[Option<SignalShape>; 4]) -> Option<usize> {
+    shapes.iter().enumerate()
+        .filter_map(|(ch, shape)| shape.map(|shape| (ch, shape.peak_to_peak())))
+        .filter(|&(_, peak_to_peak)| peak_to_peak >= MIN_PEAK_TO_PEAK)
+        .max_by(|a, b| a.1.total_cmp(&b.1))
+        .map(|(ch, _)| ch)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn signals_are_measured_from_probe_readings() {
+        // Five periods of a 2 V peak to peak sine centered on 1 V
+        let volts: Vec<f32> = (0..500).map(|i| 1.0 + (i as f32 / 100.0 * 2.0 * std::f32::consts::PI).sin()).collect();
+        let codes = vec![2048; volts.len()];
+        let shape = SignalShape::measure(&codes, &volts).unwrap();
+        assert!((shape.peak_to_peak() - 2.0).abs() < 1e-3);
+        assert_eq!(shape.periods, 3);
+        assert!((shape.period_samples - 100.0).abs() < 1e-9);
+        let (min, max) = shape.range();
+        assert!((min + 0.4).abs() < 1e-3 && (max - 2.4).abs() < 1e-3);
+
+        let clipped = SignalShape { clipped: true, ..shape };
+        assert_eq!(clipped.range(), FULL_RANGE);
+
+        let flat = SignalShape { min: 0.5, max: 0.51, periods: 0, period_samples: f64::INFINITY, clipped: false };
+        assert_eq!(dominant_channel(&[Some(flat), Some(shape), None, None]), Some(1));
+        assert_eq!(dominant_channel(&[Some(flat), None, None, None]), None);
+    }
+
+    #[test]
+    fn ranges_are_reported_as_the_inputs_apply_them() {
+        let shape = SignalShape { min: 0.5, max: 1.5, periods: 3, period_samples: 100.0, clipped: false };
+
+        // The v2 inputs have a fixed gain, so they stay at the full range
+        let mut input = AnalogInput::create(false);
+        let (min, max) = shape.apply_range(&mut input);
+        assert!((min - FULL_RANGE.0).abs() < 1e-9 && (max - FULL_RANGE.1).abs() < 1e-9);
+
+        // The legacy inputs narrow to the nearest gain they have
+        let mut input = AnalogInput::create(true);
+        let (min, max) = shape.apply_range(&mut input);
+        assert_eq!((min, max), input.range());
+        assert!(min < shape.min && max > shape.max && max - min < 2.0);
+    }
+
+    #[test]
+    fn probes_keep_the_rate_their_shapes_were_measured_at() {
+        let shape = |periods| SignalShape { min: 0.0, max: 1.0, periods, period_samples: 500.0 / periods as f64, clipped: false };
+
+        // Three periods at 1 khz, after too few at 10 khz
+        let mut rates = Vec::new();
+        let (rate, shapes, settled) = search_probe_rates::<()>(10_000.0, 1e6, |rate| {
+            rates.push(rate);
+            Ok([Some(shape(if rate > 1_000.0 { 1 } else { 3 })), None, None, None])
+        }).unwrap();
+        assert_eq!((rate, shapes[0].unwrap().periods, settled), (1_000.0, 3, true));
+        assert_eq!(rates, vec![10_000.0, 1_000.0]);
+
+        // A burst that shows too few periods at 10 khz and too many at 1 khz
+        let (rate, shapes, settled) = search_probe_rates::<()>(10_000.0, 1e6, |rate| {
+            Ok([Some(shape(if rate > 1_000.0 { 1 } else { 150 })), None, None, None])
+        }).unwrap();
+        assert_eq!((rate, shapes[0].unwrap().periods, settled), (1_000.0, 150, false));
+    }
+}
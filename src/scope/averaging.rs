@@ -0,0 +1,229 @@
+/***************************************************************************************************
+ *
+ *  nLabs, LLC
+ *  https://getnlab.com
+ *  Copyright(c) 2020. All Rights Reserved
+ *
+ *  This file is part of the nLab API
+ *
+ **************************************************************************************************/
+
+use super::data_requests::{Sample, SampleBlock};
+use super::Nlab;
+use super::RequestError;
+use super::Trigger;
+
+/// How repeated acquisitions are combined into one waveform
+#[derive(Debug, Default, Copy, Clone, PartialEq)]
+pub enum AveragingMode {
+    /// Mean of every acquisition
+    #[default]
+    Mean,
+    /// Root mean square of every acquisition, which measures the power of the signal and its
+    /// noise together rather than removing the noise
+    Rms,
+    /// Running average in which each acquisition moves the waveform by `weight` of the way
+    /// towards it, so older acquisitions fade out
+    Exponential { weight: f64 },
+}
+
+/// Waveform combined from repeated acquisitions, aligned at their trigger
+#[derive(Debug, Clone)]
+pub struct AveragedWaveform {
+    pub mode: AveragingMode,
+    /// Number of acquisitions combined
+    pub acquisitions: usize,
+    /// Factor by which uncorrelated noise is reduced relative to a single acquisition
+    pub noise_reduction: f64,
+    /// The combined waveform, as long as the shortest acquisition
+    ///
+    /// A reading is NaN where every acquisition lost it, and clipped where any acquisition clipped.
+    pub waveform: SampleBlock,
+}
+
+/// Combines acquisitions one at a time, sample by sample
+#[derive(Debug, Clone)]
+pub struct WaveformAverager {
+    mode: AveragingMode,
+    acquisitions: usize,
+    /// Length of the shortest acquisition
+    len: usize,
+    /// Sum of the squared weights of the acquisitions in the average, which scales the noise variance
+    weight_squares: f64,
+    totals: [Option<Vec<f64>>; Sample::num_channels() as usize],
+    counts: [Option<Vec<u32>>; Sample::num_channels() as usize],
+    template: SampleBlock,
+}
+
+impl WaveformAverager {
+    pub fn new(mode: AveragingMode) -> Self {
+        WaveformAverager {
+            mode,
+            acquisitions: 0,
+            len: 0,
+            weight_squares: 0.0,
+            totals: Default::default(),
+            counts: Default::default(),
+            template: SampleBlock::default(),
+        }
+    }
+
+    /// Adds an acquisition, which is expected to start at the trigger like the others
+    pub fn add(&mut self, acquisition: &SampleBlock) {
+        let first = self.acquisitions == 0;
+        if first {
+            self.template = SampleBlock {
+                start_time: acquisition.start_time,
+                dt: acquisition.dt,
+                clipped: acquisition.clipped.clone(),
+                skews: acquisition.skews,
+                ..Default::default()
+            };
+        }
+        self.len = match first {
+            true => acquisition.len(),
+            false => self.len.min(acquisition.len()),
+        };
+        let len = self.len;
+
+        for ch in 0..Sample::num_channels() as usize {
+            let readings = match &acquisition.channels[ch] {
+                Some(readings) => readings,
+                None => continue,
+            };
+            let totals = self.totals[ch].get_or_insert_with(|| vec![0.0; len]);
+            let counts = self.counts[ch].get_or_insert_with(|| vec![0; len]);
+            totals.truncate(len);
+            counts.truncate(len);
+
+            for ((total, count), &reading) in totals.iter_mut().zip(counts.iter_mut()).zip(readings) {
+                if reading.is_nan() {
+                    continue;
+                }
+                let reading = reading as f64;
+                match self.mode {
+                    AveragingMode::Mean => *total += reading,
+                    AveragingMode::Rms => *total += reading * reading,
+                    AveragingMode::Exponential { weight } => match *count {
+                        0 => *total = reading,
+                        _ => *total += weight * (reading - *total),
+                    },
+                }
+                *count += 1;
+            }
+
+            if let Some(clipped) = &mut self.template.clipped[ch] {
+                clipped.truncate(len);
+            }
+            if let (Some(clipped), Some(new_clipped)) = (&mut self.template.clipped[ch], &acquisition.clipped[ch]) {
+                clipped.iter_mut().zip(new_clipped).for_each(|(clipped, &new)| *clipped |= new);
+            }
+        }
+
+        self.acquisitions += 1;
+        self.weight_squares = match self.mode {
+            AveragingMode::Exponential { weight } if !first => {
+                self.weight_squares * (1.0 - weight).powi(2) + weight * weight
+            }
+            AveragingMode::Exponential { .. } => 1.0,
+            _ => 1.0 / self.acquisitions as f64,
+        };
+    }
+
+    pub fn acquisitions(&self) -> usize {
+        self.acquisitions
+    }
+
+    /// Factor by which uncorrelated noise is reduced relative to a single acquisition
+    pub fn noise_reduction(&self) -> f64 {
+        match (self.mode, self.acquisitions) {
+            (_, 0) | (AveragingMode::Rms, _) => 1.0,
+            _ => 1.0 / self.weight_squares.sqrt(),
+        }
+    }
+
+    /// Returns the waveform combined from the acquisitions added so far
+    pub fn result(&self) -> AveragedWaveform {
+        let mut waveform = self.template.clone();
+        for ch in 0..Sample::num_channels() as usize {
+            if let (Some(totals), Some(counts)) = (&self.totals[ch], &self.counts[ch]) {
+                waveform.channels[ch] = Some(totals.iter().zip(counts).map(|(&total, &count)| match (count, self.mode) {
+                    (0, _) => f32::NAN,
+                    (count, AveragingMode::Mean) => (total / count as f64) as f32,
+                    (count, AveragingMode::Rms) => (total / count as f64).sqrt() as f32,
+                    (_, AveragingMode::Exponential { .. }) => total as f32,
+                }).collect());
+            }
+        }
+        AveragedWaveform {
+            mode: self.mode,
+            acquisitions: self.acquisitions,
+            noise_reduction: self.noise_reduction(),
+            waveform,
+        }
+    }
+}
+
+impl Nlab {
+    /// Takes `acquisitions` triggered sweeps of `number_of_samples` and combines them sample by
+    /// sample, aligned at the trigger
+    ///
+    /// The sweeps are taken as a segmented capture, see [`Nlab::request_segments`].
+    pub fn average_waveforms(&self, sample_rate_hz: f64, number_of_samples: u32, acquisitions: usize, trigger: Trigger, mode: AveragingMode) -> Result<AveragedWaveform, RequestError> {
+        let capture = self.request_segments(sample_rate_hz, number_of_samples, acquisitions, trigger);
+        let mut averager = WaveformAverager::new(mode);
+        for frame in capture.receiver.iter() {
+            averager.add(&frame.samples);
+        }
+        match capture.error() {
+            Some(error) => Err(error),
+            None => Ok(averager.result()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn acquisition(readings: &[f32]) -> SampleBlock {
+        SampleBlock {
+            start_time: 0.0,
+            dt: 1e-3,
+            channels: [Some(readings.to_vec()), None, None, None],
+            clipped: [Some(vec![false; readings.len()]), None, None, None],
+            skews: [0.0; 4],
+            math: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn acquisitions_are_combined_sample_by_sample() {
+        let acquisitions = [
+            acquisition(&[1.0, 2.0, f32::NAN, 4.0]),
+            acquisition(&[3.0, -2.0, 6.0, 4.0]),
+            acquisition(&[2.0, 0.0, 6.0]),
+        ];
+
+        let mut mean = WaveformAverager::new(AveragingMode::Mean);
+        let mut rms = WaveformAverager::new(AveragingMode::Rms);
+        let mut exponential = WaveformAverager::new(AveragingMode::Exponential { weight: 0.5 });
+        for acquisition in acquisitions.iter() {
+            mean.add(acquisition);
+            rms.add(acquisition);
+            exponential.add(acquisition);
+        }
+
+        let result = mean.result();
+        assert_eq!(result.waveform.channels[0], Some(vec![2.0, 0.0, 6.0]));
+        assert_eq!(result.acquisitions, 3);
+        assert!((result.noise_reduction - 3f64.sqrt()).abs() < 1e-12);
+
+        assert_eq!(rms.result().waveform.channels[0].as_ref().unwrap()[1], (8.0f32 / 3.0).sqrt());
+        assert_eq!(rms.noise_reduction(), 1.0);
+
+        // Weights of 1/4, 1/4 and 1/2 for the three acquisitions
+        assert_eq!(exponential.result().waveform.channels[0], Some(vec![2.0, 0.0, 6.0]));
+        assert!((exponential.noise_reduction() - 1.0 / 0.375f64.sqrt()).abs() < 1e-12);
+    }
+}
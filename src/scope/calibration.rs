@@ -0,0 +1,360 @@
+/***************************************************************************************************
+ *
+ *  nLabs, LLC
+ *  https://getnlab.com
+ *  Copyright(c) 2020. All Rights Reserved
+ *
+ *  This file is part of the nLab API
+ *
+ **************************************************************************************************/
+
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::Duration;
+
+use log::info;
+
+use super::analog_input::{AnalogInput, Conversion};
+use super::analog_output::{AnalogSignalPolarity, AnalogWaveType};
+use super::data_requests::RawBlock;
+use super::Nlab;
+
+/// Amplitude of the triangle wave driven into the scope channels during calibration, in volts
+const CALIBRATION_AMPLITUDE: f64 = 4.0;
+/// Frequency of the calibration wave, giving several periods in each sweep
+const CALIBRATION_FREQUENCY_HZ: f64 = 50.0;
+const CALIBRATION_SAMPLE_RATE_HZ: f64 = 5000.0;
+const CALIBRATION_SAMPLES: u32 = 1000;
+/// Time the analog outputs are given to settle before a sweep
+const SETTLE_TIME: Duration = Duration::from_millis(50);
+/// Fraction of the readings ignored at each end of the wave, where noise skews the extremes
+const TAIL_FRACTION: f64 = 0.05;
+
+/// Correction applied to the nominal voltage read by a scope channel,
+/// `volts = nominal * gain + offset`
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Correction {
+    pub gain: f64,
+    pub offset: f64,
+}
+
+impl Default for Correction {
+    fn default() -> Self {
+        Correction { gain: 1.0, offset: 0.0 }
+    }
+}
+
+impl Correction {
+    pub fn apply(&self, nominal: f64) -> f64 {
+        nominal * self.gain + self.offset
+    }
+
+    /// Returns the nominal voltage that corrects to `volts`
+    pub fn remove(&self, volts: f64) -> f64 {
+        (volts - self.offset) / self.gain
+    }
+}
+
+/// Corrections for each scope channel of one nLab, at each range they have been measured at
+///
+/// A channel read at a range without a correction uses the nominal conversion.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Calibration {
+    /// Serial number of the nLab the calibration was measured on
+    pub serial: String,
+    corrections: BTreeMap<(usize, (u8, u8)), Correction>,
+}
+
+/// A connection the user is asked to make during [`Nlab::calibrate`]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct CalibrationStep {
+    /// Analog outputs and the scope channels they drive, as (output, channel), numbered from 1
+    pub connections: [(usize, usize); 2],
+}
+
+impl fmt::Display for CalibrationStep {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let [(a, ch), (b, ch_b)] = self.connections;
+        write!(f, "Connect A{a} to Ch{ch} and A{b} to Ch{ch_b}")
+    }
+}
+
+impl Calibration {
+    pub fn new(serial: &str) -> Self {
+        Calibration {
+            serial: serial.to_string(),
+            corrections: BTreeMap::new(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.corrections.is_empty()
+    }
+
+    /// Returns the correction for a channel, numbered from 0, at the range it is set to
+    pub fn correction(&self, channel: usize, input: &AnalogInput) -> Correction {
+        self.corrections.get(&(channel, input.range_key())).copied().unwrap_or_default()
+    }
+
+    /// Sets the correction for a channel, numbered from 0, at the range it is set to
+    pub fn set_correction(&mut self, channel: usize, input: &AnalogInput, correction: Correction) {
+        self.corrections.insert((channel, input.range_key()), correction);
+    }
+
+    /// Gives each channel the correction for the range it is set to
+    pub(crate) fn apply(&self, channels: &mut [AnalogInput; 4]) {
+        for (ch, input) in channels.iter_mut().enumerate() {
+            input.set_correction(self.correction(ch, input));
+        }
+    }
+
+    /// Path of the file holding the calibration of the nLab with `serial` in `dir`
+    ///
+    /// Fails for a serial number that is empty or could name a file outside of `dir`.
+    pub fn path(dir: &Path, serial: &str) -> io::Result<PathBuf> {
+        if serial.is_empty() || serial.contains("..") || serial.chars().any(std::path::is_separator) {
+            let message = format!("{serial:?} cannot be used as the name of a calibration file");
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, message));
+        }
+        Ok(dir.join(format!("{serial}.cal")))
+    }
+
+    /// Writes the calibration to its file in `dir`, returning the path of the file
+    pub fn save(&self, dir: &Path) -> io::Result<PathBuf> {
+        let mut contents = format!("# nLab calibration for {}\n# channel gain_setting offset_setting gain offset\n", self.serial);
+        for (&(ch, (gain_setting, offset_setting)), correction) in &self.corrections {
+            contents += &format!("{ch} {gain_setting} {offset_setting} {} {}\n", correction.gain, correction.offset);
+        }
+        let path = Calibration::path(dir, &self.serial)?;
+        fs::create_dir_all(dir)?;
+        fs::write(&path, contents)?;
+        Ok(path)
+    }
+
+    /// Reads the calibration of the nLab with `serial` from its file in `dir`
+    pub fn load(dir: &Path, serial: &str) -> Result<Self, Box<dyn Error>> {
+        let path = Calibration::path(dir, serial)?;
+        let mut calibration = Calibration::new(serial);
+        for (i, line) in fs::read_to_string(&path)?.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let invalid = || format!("Invalid calibration in {} on line {}", path.display(), i + 1);
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            if fields.len() != 5 {
+                return Err(invalid().into());
+            }
+            let ch: usize = fields[0].parse().map_err(|_| invalid())?;
+            let gain_setting: u8 = fields[1].parse().map_err(|_| invalid())?;
+            let offset_setting: u8 = fields[2].parse().map_err(|_| invalid())?;
+            let gain: f64 = fields[3].parse().map_err(|_| invalid())?;
+            let offset: f64 = fields[4].parse().map_err(|_| invalid())?;
+            if ch >= 4 || gain == 0.0 {
+                return Err(invalid().into());
+            }
+            calibration.corrections.insert((ch, (gain_setting, offset_setting)), Correction { gain, offset });
+        }
+        Ok(calibration)
+    }
+}
+
+/// Fits the correction that maps a channel reading a triangle wave between `-amplitude` and
+/// `amplitude` onto those levels
+///
+/// The readings of a triangle wave are spread evenly between its extremes, so its span is found
+/// from the readings a fixed fraction in from each end, which noise does not disturb. The wave
+/// is centered on zero volts, midway between those readings.
+fn fit_triangle(codes: &[u16], conversion: Conversion, amplitude: f64) -> Option<Correction> {
+    let mut volts: Vec<f64> = codes.iter()
+        .filter(|&&code| code != RawBlock::LOST_READING)
+        .map(|&code| conversion.volts(code))
+        .collect();
+    if volts.len() < 100 {
+        return None;
+    }
+    volts.sort_by(|a, b| a.total_cmp(b));
+
+    let tail = (volts.len() as f64 * TAIL_FRACTION) as usize;
+    let low = volts[tail];
+    let high = volts[volts.len() - 1 - tail];
+    let span = (high - low) / (1.0 - 2.0 * TAIL_FRACTION);
+    if span <= 0.0 {
+        return None;
+    }
+
+    let gain = 2.0 * amplitude / span;
+    Some(Correction { gain, offset: -(high + low) / 2.0 * gain })
+}
+
+impl Nlab {
+    /// Serial number of the nLab, if it reports one
+    pub fn serial(&self) -> Option<&str> {
+        self.serial.as_deref()
+    }
+
+    pub fn calibration(&self) -> &Calibration {
+        &self.calibration
+    }
+
+    /// Sets the corrections applied to the scope channels in every sweep
+    pub fn set_calibration(&mut self, calibration: Calibration) {
+        self.calibration = calibration;
+    }
+
+    /// Loads the calibration saved in `dir` for this nLab
+    pub fn load_calibration(&mut self, dir: &Path) -> Result<(), Box<dyn Error>> {
+        let serial = self.serial.as_deref().ok_or("nLab does not report a serial number")?;
+        self.calibration = Calibration::load(dir, serial)?;
+        Ok(())
+    }
+
+    /// Saves the calibration of this nLab in `dir`, returning the path of the file
+    pub fn save_calibration(&self, dir: &Path) -> io::Result<PathBuf> {
+        self.calibration.save(dir)
+    }
+
+    /// Measures the calibration of each scope channel at the range it is set to, by driving a
+    /// triangle wave of known levels into it from A1 or A2
+    ///
+    /// `prompt` is called before each step with the connections to make, and returns false to
+    /// skip the step. A step fails if the range of one of its channels does not include ±4 V,
+    /// the levels of the wave. The fitted corrections are
+    /// added to the calibration of the nLab, which is also returned. Both analog outputs are
+    /// left off.
+    pub fn calibrate(&mut self, mut prompt: impl FnMut(&CalibrationStep) -> bool) -> Result<Calibration, Box<dyn Error>> {
+        let steps = [
+            CalibrationStep { connections: [(1, 1), (2, 2)] },
+            CalibrationStep { connections: [(1, 3), (2, 4)] },
+        ];
+
+        for output in [&self.a1, &self.a2].iter() {
+            output.try_set_wave_type(AnalogWaveType::Triangle)?;
+            output.try_set_polarity(AnalogSignalPolarity::Bipolar)?;
+            output.try_set_amplitude(CALIBRATION_AMPLITUDE)?;
+            output.try_set_frequency(CALIBRATION_FREQUENCY_HZ)?;
+            output.try_turn_on()?;
+        }
+        thread::sleep(SETTLE_TIME);
+
+        let channels_on = [self.ch1.is_on, self.ch2.is_on, self.ch3.is_on, self.ch4.is_on];
+        // Sweep with the nominal conversion, so the fit replaces any earlier correction
+        let previous = std::mem::take(&mut self.calibration);
+        let result = steps.iter()
+            .filter(|step| prompt(step))
+            .try_fold(previous.clone(), |mut calibration, step| {
+                self.calibrate_step(step, &mut calibration)?;
+                Ok::<_, Box<dyn Error>>(calibration)
+            });
+
+        for (ch, &is_on) in [&mut self.ch1, &mut self.ch2, &mut self.ch3, &mut self.ch4].iter_mut().zip(channels_on.iter()) {
+            ch.is_on = is_on;
+        }
+        self.a1.turn_off();
+        self.a2.turn_off();
+
+        match result {
+            Ok(mut calibration) => {
+                if calibration.serial.is_empty() {
+                    calibration.serial = self.serial.clone().unwrap_or_default();
+                }
+                self.calibration = calibration.clone();
+                Ok(calibration)
+            }
+            Err(error) => {
+                self.calibration = previous;
+                Err(error)
+            }
+        }
+    }
+
+    fn calibrate_step(&mut self, step: &CalibrationStep, calibration: &mut Calibration) -> Result<(), Box<dyn Error>> {
+        let channels: Vec<usize> = step.connections.iter().map(|&(_, ch)| ch - 1).collect();
+        for &ch in &channels {
+            let (min, max) = [self.ch1, self.ch2, self.ch3, self.ch4][ch].range();
+            if min > -CALIBRATION_AMPLITUDE || max < CALIBRATION_AMPLITUDE {
+                return Err(format!("Ch{} reads {min:.2} V to {max:.2} V, its range must include ±{CALIBRATION_AMPLITUDE} V to be calibrated", ch + 1).into());
+            }
+        }
+        for (ch, input) in [&mut self.ch1, &mut self.ch2, &mut self.ch3, &mut self.ch4].iter_mut().enumerate() {
+            input.is_on = channels.contains(&ch);
+        }
+
+        let sweep = self.request_raw(CALIBRATION_SAMPLE_RATE_HZ, CALIBRATION_SAMPLES, None, CALIBRATION_SAMPLES as usize);
+        let blocks: Vec<RawBlock> = sweep.receiver.iter().collect();
+        if let Some(error) = sweep.error() {
+            return Err(error.into());
+        }
+
+        for &ch in &channels {
+            let codes: Vec<u16> = blocks.iter()
+                .filter_map(|block| block.channels[ch].as_ref())
+                .flatten()
+                .copied()
+                .collect();
+            let conversion = match blocks.iter().find_map(|block| block.conversions[ch]) {
+                Some(conversion) => conversion,
+                None => return Err(format!("No readings from Ch{} during calibration", ch + 1).into()),
+            };
+            let correction = fit_triangle(&codes, conversion, CALIBRATION_AMPLITUDE)
+                .ok_or_else(|| format!("Cannot find the calibration wave on Ch{}, check the connections of: {step}", ch + 1))?;
+            info!("Ch{} calibration: gain {:.4}, offset {:.4} V", ch + 1, correction.gain, correction.offset);
+
+            let input = [self.ch1, self.ch2, self.ch3, self.ch4][ch];
+            calibration.set_correction(ch, &input, correction);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn corrections_are_fitted_and_saved_by_serial() {
+        let conversion = Conversion { scale: 10.0 / 4095.0, offset: -5.0 };
+
+        // A triangle wave between -4 V and 4 V, read by a channel 3% short with a 50 mV offset
+        let codes: Vec<u16> = (0..1000).map(|i| {
+            let phase = (i % 500) as f64 / 500.0;
+            let volts = if phase < 0.5 { -4.0 + 16.0 * phase } else { 12.0 - 16.0 * phase };
+            let read = volts * 0.97 + 0.05;
+            ((read + 5.0) * 4095.0 / 10.0).round() as u16
+        }).collect();
+
+        let correction = fit_triangle(&codes, conversion, 4.0).unwrap();
+        assert!((correction.gain - 1.0 / 0.97).abs() < 0.01);
+        assert!(correction.apply(0.05).abs() < 0.01);
+
+        let input = AnalogInput::create(false);
+        let mut calibration = Calibration::new("TEST0001");
+        calibration.set_correction(2, &input, correction);
+
+        let dir = std::env::temp_dir().join(format!("nlab-calibration-{}", std::process::id()));
+        let path = calibration.save(&dir).unwrap();
+        let loaded = Calibration::load(&dir, "TEST0001");
+        fs::remove_dir_all(&dir).ok();
+
+        assert_eq!(path, Calibration::path(&dir, "TEST0001").unwrap());
+        let loaded = loaded.unwrap();
+        assert_eq!(loaded, calibration);
+        assert_eq!(loaded.correction(2, &input), correction);
+        assert_eq!(loaded.correction(1, &input), Correction::default());
+    }
+
+    #[test]
+    fn serials_cannot_leave_the_calibration_directory() {
+        let dir = Path::new("calibrations");
+        assert_eq!(Calibration::path(dir, "NL2-0042").unwrap(), dir.join("NL2-0042.cal"));
+        for serial in ["", "..", "../NL2-0042", "NL2/0042"] {
+            assert_eq!(Calibration::path(dir, serial).unwrap_err().kind(), io::ErrorKind::InvalidInput);
+        }
+        assert!(Calibration::load(dir, "../NL2-0042").is_err());
+    }
+}
@@ -0,0 +1,282 @@
+/***************************************************************************************************
+ *
+ *  nLabs, LLC
+ *  https://getnlab.com
+ *  Copyright(c) 2020. All Rights Reserved
+ *
+ *  This file is part of the nLab API
+ *
+ **************************************************************************************************/
+
+use std::convert::TryInto;
+
+use super::analog_output::AnalogWaveType;
+use super::trigger::TriggerType;
+
+/// Marks a capability block in an initialization response, which padding cannot be mistaken for
+///
+/// Provisional: no released firmware sends a capability block, and no firmware protocol revision
+/// defines one yet. The layout read by [`Capabilities::from_init_response`] is a proposal, and
+/// until firmware implements it every nLab v2 gets [`Capabilities::v2_default`].
+const CAPABILITY_MAGIC: [u8; 4] = *b"nCAP";
+/// Layout of the capability block that this API reads
+const CAPABILITY_BLOCK_VERSION: u8 = 1;
+
+/// Features and limits supported by the firmware of a connected nLab
+///
+/// nLab v2 firmware reports its capabilities in response to the initialization request.
+/// Firmware that predates capability reporting is described by the defaults for its hardware.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Capabilities {
+    /// Fastest sample rate of a single scope channel
+    pub max_sample_rate_hz: f64,
+    /// Fastest sample rate of a single scope channel for sweeps that are streamed as they are
+    /// taken, and can be of any length
+    pub max_streaming_rate_hz: f64,
+    /// Longest sweep of a single scope channel above the streaming rate, which the nLab buffers
+    pub buffered_samples: u32,
+    /// Scope channels share one converter, so the sample rate is divided between them
+    pub multiplexed_inputs: bool,
+    /// Smallest and largest front-end gain of the scope channels
+    pub gain_range: (f64, f64),
+    /// Channel data packets carry the sweep index of their first sample, so lost packets can be detected
+    pub sequenced_packets: bool,
+    /// Clock that the scope sample rate is divided from
+    sample_clock_hz: f64,
+    trigger_types: u8,
+    wave_types: u8,
+}
+
+impl Capabilities {
+    pub(crate) fn legacy() -> Self {
+        Capabilities {
+            max_sample_rate_hz: 4_000_000.0,
+            max_streaming_rate_hz: 16_000.0,
+            buffered_samples: 3200,
+            multiplexed_inputs: true,
+            gain_range: (1.0 + 50.0 / 5000.0, 1.0 + 50.0 / 5000.0 + 255.0 * 20.0 / 256.0),
+            sequenced_packets: false,
+            sample_clock_hz: 4_000_000.0,
+            trigger_types: trigger_bit(TriggerType::RisingEdge) | trigger_bit(TriggerType::FallingEdge),
+            wave_types: wave_bit(AnalogWaveType::Sine) | wave_bit(AnalogWaveType::Triangle),
+        }
+    }
+
+    pub(crate) fn v2_default() -> Self {
+        Capabilities {
+            max_sample_rate_hz: 2_000_000.0,
+            max_streaming_rate_hz: 80_000.0,
+            buffered_samples: 2400,
+            multiplexed_inputs: false,
+            gain_range: (1.0, 1.0),
+            sequenced_packets: false,
+            sample_clock_hz: 2_000_000.0,
+            trigger_types: trigger_bit(TriggerType::RisingEdge) | trigger_bit(TriggerType::FallingEdge),
+            wave_types: wave_bit(AnalogWaveType::Sine) | wave_bit(AnalogWaveType::Triangle),
+        }
+    }
+
+    /// Parses the capability block that follows the status fields of an initialization response
+    ///
+    /// Released firmware leaves these bytes undefined, so the block is only read when it starts
+    /// with [`CAPABILITY_MAGIC`] followed by a block version this API understands. Anything else
+    /// is firmware that does not report capabilities, and gets the defaults for v2 hardware.
+    pub(crate) fn from_init_response(buf: &[u8; 64]) -> Self {
+        let mut capabilities = Capabilities::v2_default();
+        if buf[8..12] != CAPABILITY_MAGIC || buf[12] != CAPABILITY_BLOCK_VERSION {
+            return capabilities;
+        }
+
+        let max_sample_rate = u32::from_le_bytes(buf[13..17].try_into().unwrap());
+        if max_sample_rate > 0 {
+            capabilities.max_sample_rate_hz = max_sample_rate as f64;
+        }
+        capabilities.trigger_types = buf[17];
+        capabilities.wave_types = buf[18];
+        capabilities.multiplexed_inputs = buf[19] & 0x01 != 0;
+        capabilities.sequenced_packets = buf[19] & 0x02 != 0;
+
+        let min_gain = f32::from_le_bytes(buf[20..24].try_into().unwrap()) as f64;
+        let max_gain = f32::from_le_bytes(buf[24..28].try_into().unwrap()) as f64;
+        if min_gain > 0.0 && max_gain >= min_gain {
+            capabilities.gain_range = (min_gain, max_gain);
+        }
+        capabilities
+    }
+
+    pub fn supports_trigger(&self, trigger_type: TriggerType) -> bool {
+        self.trigger_types & trigger_bit(trigger_type) != 0
+    }
+
+    pub fn supports_wave_type(&self, wave_type: AnalogWaveType) -> bool {
+        self.wave_types & wave_bit(wave_type) != 0
+    }
+
+    /// Fastest sample rate available with the given number of scope channels turned on
+    pub fn max_sample_rate_with(&self, channels_on: usize) -> f64 {
+        self.max_sample_rate_hz / self.rate_divisor(channels_on)
+    }
+
+    /// Sample rates available with the given number of scope channels turned on
+    pub fn sample_rates(&self, channels_on: usize) -> SampleRates {
+        let clock_hz = self.sample_clock_hz / self.rate_divisor(channels_on);
+        SampleRates {
+            clock_hz,
+            min_divisor: ((clock_hz / self.max_sample_rate_with(channels_on)).ceil() as u32).max(1),
+            max_divisor: u32::MAX,
+        }
+    }
+
+    /// Longest sweep available at a sample rate with the given number of scope channels turned on
+    ///
+    /// The sample rate is first snapped to the nearest one available, see [`SampleRates::nearest`].
+    pub fn max_samples_at(&self, sample_rate_hz: f64, channels_on: usize) -> u32 {
+        let sample_rate_hz = self.sample_rates(channels_on).nearest(sample_rate_hz);
+        if sample_rate_hz <= self.max_streaming_rate_hz / self.rate_divisor(channels_on) {
+            return u32::MAX;
+        }
+        match self.multiplexed_inputs {
+            true => self.buffered_samples / channels_on.max(1) as u32,
+            false => self.buffered_samples,
+        }
+    }
+
+    /// Time after each sample at which each scope channel is read, in seconds, with the given
+    /// scope channels turned on
+    ///
+    /// Multiplexed inputs are converted one after another in channel order, one period of the
+    /// sample clock apart, so only the first channel that is on is read at the sample time.
+    pub fn channel_skews(&self, channels_on: [bool; 4]) -> [f64; 4] {
+        let mut skews = [0.0; 4];
+        if self.multiplexed_inputs {
+            let mut order = 0;
+            for (skew, &on) in skews.iter_mut().zip(channels_on.iter()) {
+                if on {
+                    *skew = order as f64 / self.sample_clock_hz;
+                    order += 1;
+                }
+            }
+        }
+        skews
+    }
+
+    fn rate_divisor(&self, channels_on: usize) -> f64 {
+        match (self.multiplexed_inputs, channels_on) {
+            (true, 3..) => 4.0,
+            (true, 2) => 2.0,
+            _ => 1.0,
+        }
+    }
+}
+
+/// Sample rates a scope sweep can run at, which are a clock divided by a whole number
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct SampleRates {
+    pub clock_hz: f64,
+    pub min_divisor: u32,
+    /// Largest divisor a request can carry, which is the most a 32-bit field holds, so there
+    /// are billions of rates between the fastest and slowest
+    pub max_divisor: u32,
+}
+
+impl SampleRates {
+    /// Sample rate with the clock divided by `divisor`
+    pub fn rate(&self, divisor: u32) -> f64 {
+        self.clock_hz / divisor as f64
+    }
+
+    pub fn fastest(&self) -> f64 {
+        self.rate(self.min_divisor)
+    }
+
+    pub fn slowest(&self) -> f64 {
+        self.rate(self.max_divisor)
+    }
+
+    /// Lists the sample rates, fastest first
+    ///
+    /// There can be billions of them, see [`SampleRates::max_divisor`], so take the ones needed
+    /// rather than collecting them all.
+    pub fn iter(&self) -> impl Iterator<Item = f64> + '_ {
+        (self.min_divisor..=self.max_divisor).map(move |divisor| self.rate(divisor))
+    }
+
+    /// Snaps a sample rate to the nearest one available
+    pub fn nearest(&self, sample_rate_hz: f64) -> f64 {
+        self.rate(self.divisor(sample_rate_hz))
+    }
+
+    /// Divisor of the sample rate nearest to `sample_rate_hz`
+    ///
+    /// A rate that is not a positive number has no nearest rate, and gets the slowest one.
+    pub fn divisor(&self, sample_rate_hz: f64) -> u32 {
+        if sample_rate_hz.is_nan() || sample_rate_hz <= 0.0 {
+            return self.max_divisor;
+        }
+        let exact = (self.clock_hz / sample_rate_hz).clamp(self.min_divisor as f64, self.max_divisor as f64);
+        let (below, above) = (exact.floor() as u32, exact.ceil() as u32);
+        match (self.rate(below) - sample_rate_hz).abs() <= (self.rate(above) - sample_rate_hz).abs() {
+            true => below,
+            false => above,
+        }
+    }
+}
+
+fn trigger_bit(trigger_type: TriggerType) -> u8 {
+    match trigger_type {
+        TriggerType::RisingEdge => 0x01,
+        TriggerType::FallingEdge => 0x02,
+    }
+}
+
+fn wave_bit(wave_type: AnalogWaveType) -> u8 {
+    0x01 << wave_type as u8
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sample_rates_snap_to_whole_divisors_of_the_clock() {
+        let rates = Capabilities::v2_default().sample_rates(1);
+        assert_eq!(rates.fastest(), 2_000_000.0);
+        assert_eq!(rates.iter().take(3).collect::<Vec<_>>(), vec![2_000_000.0, 1_000_000.0, 2_000_000.0 / 3.0]);
+        assert_eq!(rates.nearest(700_000.0), 2_000_000.0 / 3.0);
+        assert_eq!(rates.nearest(900_000.0), 1_000_000.0);
+        assert_eq!(rates.nearest(5_000_000.0), 2_000_000.0);
+        assert_eq!(rates.divisor(1234.0), 1621);
+        for rate in [f64::NAN, 0.0, -1000.0] {
+            assert_eq!(rates.divisor(rate), rates.max_divisor);
+        }
+        assert_eq!(rates.divisor(f64::INFINITY), rates.min_divisor);
+
+        let legacy = Capabilities::legacy();
+        assert_eq!(legacy.sample_rates(4).clock_hz, 1_000_000.0);
+        assert_eq!(legacy.max_samples_at(4_000.0, 4), u32::MAX);
+        assert_eq!(legacy.max_samples_at(4_100.0, 4), 800);
+        // Snaps to 4 khz, which streams
+        assert_eq!(legacy.max_samples_at(4_001.0, 4), u32::MAX);
+
+        assert_eq!(legacy.channel_skews([false, true, false, true]), [0.0, 0.0, 0.0, 0.25e-6]);
+        assert_eq!(Capabilities::v2_default().channel_skews([true; 4]), [0.0; 4]);
+    }
+
+    #[test]
+    fn capabilities_are_only_read_from_a_marked_block() {
+        let mut buf = [0xA5u8; 64];
+        assert_eq!(Capabilities::from_init_response(&buf), Capabilities::v2_default());
+
+        buf[8..12].copy_from_slice(&CAPABILITY_MAGIC);
+        buf[12] = CAPABILITY_BLOCK_VERSION + 1;
+        assert_eq!(Capabilities::from_init_response(&buf), Capabilities::v2_default());
+
+        buf[12] = CAPABILITY_BLOCK_VERSION;
+        buf[13..17].copy_from_slice(&1_000_000u32.to_le_bytes());
+        buf[20..24].copy_from_slice(&1.0f32.to_le_bytes());
+        buf[24..28].copy_from_slice(&4.0f32.to_le_bytes());
+        let capabilities = Capabilities::from_init_response(&buf);
+        assert_eq!(capabilities.max_sample_rate_hz, 1_000_000.0);
+        assert_eq!(capabilities.gain_range, (1.0, 4.0));
+    }
+}
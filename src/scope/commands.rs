@@ -11,21 +11,28 @@
 
 
 use std::error::Error;
+use std::fmt;
 use std::sync::mpsc::Sender;
+use std::time::Duration;
 
 use log::debug;
 
 use super::analog_output::AxRequest;
+use super::capabilities::Capabilities;
 use super::data_requests::{DataRequest};
 use super::pulse_output::PxRequest;
+use super::RequestError;
+use super::transport::Waker;
 
 pub(super) const NULL_REQ: [u8; 2] = [0, 0xFF];
 
 pub(super) trait ScopeCommand {
+    fn check_capabilities(&self, capabilities: &Capabilities) -> Result<(), Box<dyn Error>>;
     fn fill_tx_buffer_legacy(&self, usb_buf: &mut [u8; 65]) -> Result<(), Box<dyn Error>>;
     fn fill_tx_buffer(&self, usb_buf: &mut [u8; 64]) -> Result<(), Box<dyn Error>>;
     fn handle_rx_legacy(&self, usb_buf: &[u8; 64]);
     fn handle_rx(&self, usb_buf: &[u8; 64]);
+    fn handle_failure(&self, error: RequestError);
     fn is_finished(&self) -> bool;
 }
 
@@ -33,21 +40,58 @@ pub(super) trait ScopeCommand {
 // PWM_DUTY_REQUEST = 0x00, -- not for 1.0
 // FINITE_DATA_REQUEST = 0x03, -- not for 1.0
 // CONTINUOUS_DATA_REQUEST = 0x04 -- not for 1.0
-// SCOPE_ROLL_REQUEST = 0x09 -- not for 1.0
+// SCOPE_ROLL_REQUEST = 0x09 -- not for 1.0, rolls stream on the host, see Nlab::roll
 // RESET_TO_BOOTLOADER = 0x10 -- not for 1.0
 
 
+/// Sends commands to the communication thread, waking it if it is waiting on USB events
+#[derive(Clone)]
+pub(crate) struct CommandSender {
+    sender: Sender<Command>,
+    wake: Option<Waker>,
+}
+
+impl fmt::Debug for CommandSender {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("CommandSender").field("sender", &self.sender).finish_non_exhaustive()
+    }
+}
+
+impl CommandSender {
+    pub(crate) fn new(sender: Sender<Command>, wake: Option<Waker>) -> Self {
+        CommandSender { sender, wake }
+    }
+
+    pub(crate) fn send(&self, command: Command) -> Result<(), RequestError> {
+        self.sender.send(command).map_err(|_| RequestError::Disconnected)?;
+        if let Some(wake) = &self.wake {
+            wake();
+        }
+        Ok(())
+    }
+}
+
 #[derive(Debug)]
 pub(crate) enum Command {
     Quit,
     Initialize(bool, Sender<()>),
+    SetPower(bool, Sender<Result<(), RequestError>>),
     SetAnalogOutput(AxRequest),
     SetPulseOutput(PxRequest),
-    RequestData(DataRequest),
+    RequestData(Box<DataRequest>),
     StopData,
 }
 
 impl Command {
+    pub(super) fn check_capabilities(&self, capabilities: &Capabilities) -> Result<(), Box<dyn Error>> {
+        match self {
+            Command::SetAnalogOutput(cmd) => { cmd.check_capabilities(capabilities) }
+            Command::SetPulseOutput(cmd) => { cmd.check_capabilities(capabilities) }
+            Command::RequestData(cmd) => { cmd.check_capabilities(capabilities) }
+            _ => { Ok(()) }
+        }
+    }
+
     pub(super) fn fill_tx_buffer_legacy(&mut self, usb_buf: &mut [u8; 65]) -> Result<(), Box<dyn Error>> {
         debug!("Processed command: {self:?}");
         match self {
@@ -63,6 +107,10 @@ impl Command {
             Command::SetAnalogOutput(cmd) => { cmd.fill_tx_buffer_legacy(usb_buf) }
             Command::SetPulseOutput(cmd) => { cmd.fill_tx_buffer_legacy(usb_buf) }
             Command::RequestData(cmd) => { cmd.fill_tx_buffer_legacy(usb_buf) }
+            Command::SetPower(power_on, _) => {
+                usb_buf[1] = if *power_on { 0x07 } else { 0x06 };
+                Ok(())
+            }
             Command::StopData => {
                 usb_buf[1] = 0x05;
                 Ok(())
@@ -74,6 +122,7 @@ impl Command {
         match self {
             Command::Quit => {}
             Command::Initialize(_, _) => {}
+            Command::SetPower(_, sender) => { sender.send(Ok(())).ok(); }
             Command::SetAnalogOutput(cmd) => { cmd.handle_rx_legacy(buffer) }
             Command::SetPulseOutput(cmd) => { cmd.handle_rx_legacy(buffer) }
             Command::RequestData(cmd) => { cmd.handle_rx_legacy(buffer) }
@@ -85,6 +134,7 @@ impl Command {
         match self {
             Command::Quit => {}
             Command::Initialize(_, sender) => { sender.send(()).unwrap() }
+            Command::SetPower(_, sender) => { sender.send(Ok(())).ok(); }
             Command::SetAnalogOutput(cmd) => { cmd.handle_rx(buffer) }
             Command::SetPulseOutput(cmd) => { cmd.handle_rx(buffer) }
             Command::RequestData(cmd) => { cmd.handle_rx(buffer) }
@@ -92,10 +142,41 @@ impl Command {
         }
     }
 
+    /// Reports to the front end that this command will never be answered
+    pub(super) fn handle_failure(&self, error: RequestError) {
+        debug!("Failed command: {self:?}: {error}");
+        match self {
+            Command::Quit => {}
+            Command::Initialize(_, _) => {}
+            Command::SetPower(_, sender) => { sender.send(Err(error)).ok(); }
+            Command::SetAnalogOutput(cmd) => { cmd.handle_failure(error) }
+            Command::SetPulseOutput(cmd) => { cmd.handle_failure(error) }
+            Command::RequestData(cmd) => { cmd.handle_failure(error) }
+            Command::StopData => {}
+        }
+    }
+
+    /// How long to wait for the nLab to answer this command before sending it again
+    pub(super) fn timeout(&self) -> Duration {
+        match self {
+            Command::Initialize(_, _) | Command::SetPower(_, _) => { Duration::from_millis(1000) }
+            _ => { Duration::from_millis(250) }
+        }
+    }
+
+    /// Whether carrying out this command twice leaves the nLab as carrying it out once, so it
+    /// can be sent again when its response goes missing
+    ///
+    /// A data request sent again would start a second sweep streaming into the same request.
+    pub(super) fn is_idempotent(&self) -> bool {
+        !matches!(self, Command::RequestData(_))
+    }
+
     pub(super) fn is_finished(&self) -> bool {
         match self {
             Command::Quit => { true }
             Command::Initialize(_, _) => { true }
+            Command::SetPower(_, _) => { true }
             Command::SetAnalogOutput(cmd) => { cmd.is_finished() }
             Command::SetPulseOutput(cmd) => { cmd.is_finished() }
             Command::RequestData(cmd) => { cmd.is_finished() }
@@ -103,10 +184,25 @@ impl Command {
         }
     }
 
+    /// Name of the command, used to label link statistics
+    pub(super) fn name(&self) -> &'static str {
+        match self {
+            Command::Quit => { "Quit" }
+            Command::Initialize(_, _) => { "Initialize" }
+            Command::SetPower(_, _) => { "SetPower" }
+            Command::SetAnalogOutput(_) => { "SetAnalogOutput" }
+            Command::SetPulseOutput(_) => { "SetPulseOutput" }
+            Command::RequestData(_) => { "RequestData" }
+            Command::StopData => { "StopData" }
+        }
+    }
+
     pub(crate) fn id_byte(&self) -> u8 {
         match self {
             Command::Quit => { 0 }
-            Command::Initialize(_, _) => { 1 }
+            // The v2 firmware has no separate power request, it applies the power setting
+            // carried by an initialization request
+            Command::Initialize(_, _) | Command::SetPower(_, _) => { 1 }
             Command::SetAnalogOutput(_) => { 2 }
             Command::SetPulseOutput(_) => { 3 }
             Command::RequestData(_) => { 4 }
@@ -10,15 +10,27 @@
 
 use std::collections::VecDeque;
 use std::error::Error;
-use std::sync::{Arc, mpsc, RwLock};
+use std::fmt;
+use std::mem;
+use std::sync::{Arc, mpsc, Mutex, RwLock};
 use std::sync::mpsc::{Receiver, Sender};
+use std::time::{Duration, Instant};
 
-use log::{trace, debug};
+use log::{trace, debug, warn};
 
+use super::acquisition::{AcquisitionMode, MAX_OVERSAMPLING, Reducer};
 use super::AnalogInput;
+use super::analog_input::{Conversion, Rail};
+use super::capabilities::Capabilities;
 use super::Command;
+use super::commands::CommandSender;
 use super::commands::ScopeCommand;
+use super::math_channels::MathChannels;
 use super::Nlab;
+use super::RequestError;
+use super::roll::RollBuffer;
+use super::skew::Deskewer;
+use super::software_trigger::{SoftwareTriggerEngine, TriggeredCapture};
 use super::Trigger;
 
 /// Voltage information from all open channels at a given time
@@ -26,75 +38,578 @@ use super::Trigger;
 pub struct Sample {
     pub time_since_start: f64,
     pub data: [Option<f64>; Sample::num_channels() as usize],
+    /// A reading from at least one open channel was lost in transfer, and is `None` in `data`
+    pub gap: bool,
+    /// Channels whose reading is at or near a rail of the ADC, where the signal may be outside
+    /// the range of the channel
+    pub clipped: [bool; Sample::num_channels() as usize],
+    /// Time after `time_since_start` at which each channel was read, in seconds, see
+    /// [`Capabilities::channel_skews`]
+    pub skews: [f64; Sample::num_channels() as usize],
+    /// Values of the math channels, in the order they were added, see [`Nlab::add_math_channel`]
+    pub math: Vec<Option<f64>>,
 }
 
 impl Sample {
     pub const fn num_channels() -> u32 { 4 }
 
+    /// Time at which a channel was read, in seconds since the start of the sweep
+    pub fn channel_time(&self, channel: usize) -> f64 {
+        self.time_since_start + self.skews[channel]
+    }
+
     pub fn clear(&mut self) {
         self.data = [None; Sample::num_channels() as usize];
+        self.gap = false;
+        self.clipped = [false; Sample::num_channels() as usize];
+        self.math.clear();
+    }
+}
+
+/// Consecutive samples from a sweep, with the readings of each channel stored contiguously
+#[derive(Debug, Default, Clone)]
+pub struct SampleBlock {
+    /// Time of the first sample in the block, in seconds since the start of the sweep
+    pub start_time: f64,
+    /// Time between samples, in seconds
+    pub dt: f64,
+    /// Voltages read by each open channel, or `None` for a closed channel
+    ///
+    /// Readings lost in transfer are NaN.
+    pub channels: [Option<Vec<f32>>; Sample::num_channels() as usize],
+    /// Whether each reading in `channels` is at or near a rail of the ADC
+    pub clipped: [Option<Vec<bool>>; Sample::num_channels() as usize],
+    /// Time after each sample at which each channel was read, in seconds, see
+    /// [`Capabilities::channel_skews`]
+    pub skews: [f64; Sample::num_channels() as usize],
+    /// Values of the math channels, in the order they were added, see [`Nlab::add_math_channel`]
+    ///
+    /// Values that cannot be computed, such as from lost readings, are NaN.
+    pub math: Vec<Vec<f32>>,
+}
+
+impl SampleBlock {
+    /// Number of samples in the block
+    pub fn len(&self) -> usize {
+        self.channels.iter().flatten().map(Vec::len).next().unwrap_or(0)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Time at which a channel read sample `index`, in seconds since the start of the sweep
+    pub fn channel_time(&self, channel: usize, index: usize) -> f64 {
+        self.start_time + index as f64 * self.dt + self.skews[channel]
+    }
+
+    /// Returns the samples in the block one at a time
+    pub fn samples(&self) -> impl Iterator<Item = Sample> + '_ {
+        (0..self.len()).map(move |i| {
+            let mut sample = Sample {
+                time_since_start: self.start_time + i as f64 * self.dt,
+                skews: self.skews,
+                ..Default::default()
+            };
+            for (ch, readings) in self.channels.iter().enumerate() {
+                if let Some(readings) = readings {
+                    match readings[i] {
+                        reading if reading.is_nan() => sample.gap = true,
+                        reading => sample.data[ch] = Some(reading as f64),
+                    }
+                }
+                if let Some(clipped) = &self.clipped[ch] {
+                    sample.clipped[ch] = clipped[i];
+                }
+            }
+            sample.math = self.math.iter().map(|values| Some(values[i] as f64).filter(|value| !value.is_nan())).collect();
+            sample
+        })
     }
 }
 
+/// Consecutive samples from a sweep as the codes read by the ADC, before conversion to volts
+///
+/// The codes of a channel convert to the voltages in a [`SampleBlock`] through its entry in
+/// `conversions`. A code of 0 or [`ADC_MAX_CODE`](crate::ADC_MAX_CODE) is a reading at the edge
+/// of the range of the channel, which may have clipped, see [`Rail::of`].
+#[derive(Debug, Default, Clone)]
+pub struct RawBlock {
+    /// Time of the first sample in the block, in seconds since the start of the sweep
+    pub start_time: f64,
+    /// Time between samples, in seconds
+    pub dt: f64,
+    /// Codes read by each open channel, or `None` for a closed channel
+    ///
+    /// Readings lost in transfer are [`RawBlock::LOST_READING`].
+    pub channels: [Option<Vec<u16>>; Sample::num_channels() as usize],
+    /// Conversion from codes to volts for each open channel, at the range it was swept with
+    pub conversions: [Option<Conversion>; Sample::num_channels() as usize],
+    /// Time after each sample at which each channel was read, in seconds, see
+    /// [`Capabilities::channel_skews`]
+    pub skews: [f64; Sample::num_channels() as usize],
+}
+
+impl RawBlock {
+    /// Code standing in for a reading lost in transfer, outside the range of the 12-bit ADC
+    pub const LOST_READING: u16 = u16::MAX;
+
+    fn new(start_time: f64, dt: f64, channels: &[AnalogInput; 4], skews: [f64; 4], capacity: usize) -> Self {
+        let mut block = RawBlock {
+            start_time,
+            dt,
+            skews,
+            ..Default::default()
+        };
+        for ((codes, conversion), ch) in block.channels.iter_mut().zip(block.conversions.iter_mut()).zip(channels) {
+            if ch.is_on {
+                *codes = Some(Vec::with_capacity(capacity));
+                *conversion = Some(ch.conversion());
+            }
+        }
+        block
+    }
+
+    /// Number of samples in the block
+    pub fn len(&self) -> usize {
+        self.channels.iter().flatten().map(Vec::len).next().unwrap_or(0)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Converts the codes in the block to volts
+    pub fn to_volts(&self) -> SampleBlock {
+        let mut block = SampleBlock {
+            start_time: self.start_time,
+            dt: self.dt,
+            channels: Default::default(),
+            clipped: Default::default(),
+            skews: self.skews,
+            math: Vec::new(),
+        };
+        for (ch, codes) in self.channels.iter().enumerate() {
+            if let (Some(codes), Some(conversion)) = (codes, self.conversions[ch]) {
+                block.channels[ch] = Some(codes.iter().map(|&code| match code {
+                    RawBlock::LOST_READING => f32::NAN,
+                    code => conversion.volts(code) as f32,
+                }).collect());
+                block.clipped[ch] = Some(codes.iter().map(|&code| Rail::of(code).is_some()).collect());
+            }
+        }
+        block
+    }
+}
+
+/// One triggered frame of a segmented capture, see [`Nlab::request_segments`]
+#[derive(Debug, Default, Clone)]
+pub struct Frame {
+    /// Position of the frame in the capture, from 0
+    pub index: usize,
+    /// Time of the trigger, in seconds since the capture was requested
+    ///
+    /// The nLab does not timestamp its triggers, so this is estimated from when the first
+    /// readings of the frame reached the host, and carries the latency of the USB link.
+    pub trigger_time: f64,
+    /// Samples of the frame, starting at the trigger
+    pub samples: SampleBlock,
+}
+
+/// Samples in a continuous sweep, the most that any acquisition mode can take without
+/// overflowing the reading count of a sweep
+pub(crate) const CONTINUOUS_SWEEP_SAMPLES: u32 = u32::MAX / (2 * MAX_OVERSAMPLING);
+
+/// Where the samples of a sweep are delivered
+#[derive(Debug, Clone)]
+pub(crate) enum DataSink {
+    /// One sample at a time, as soon as it is received
+    Samples(Sender<Sample>),
+    /// In blocks of the given number of samples
+    Blocks(Sender<SampleBlock>, usize),
+    /// In blocks of the given number of samples, as ADC codes
+    Raw(Sender<RawBlock>, usize),
+    /// In a single frame of the given number of samples, at the end of each sweep of a
+    /// segmented capture
+    Frames(Sender<Frame>, usize),
+    /// One packet at a time into the buffer of a roll, see [`Nlab::roll`]
+    Roll(Arc<RwLock<RollBuffer>>),
+    /// One packet at a time through a software trigger, sending the captures it completes
+    Triggered(Sender<TriggeredCapture>, Arc<RwLock<SoftwareTriggerEngine>>),
+}
+
+impl DataSink {
+    fn block_size(&self) -> usize {
+        match self {
+            DataSink::Samples(_) | DataSink::Frames(..) | DataSink::Roll(_) | DataSink::Triggered(..) => usize::MAX,
+            DataSink::Blocks(_, block_size) | DataSink::Raw(_, block_size) => *block_size,
+        }
+    }
+
+    fn capacity(&self) -> usize {
+        match self {
+            DataSink::Samples(_) | DataSink::Roll(_) | DataSink::Triggered(..) => 0,
+            DataSink::Blocks(_, block_size) | DataSink::Raw(_, block_size) | DataSink::Frames(_, block_size) => *block_size,
+        }
+    }
+}
+
+/// Record of the data packets that did not arrive intact during a sweep
+///
+/// Packets are only checked when the nLab firmware numbers them, see
+/// [`Capabilities::sequenced_packets`]. No released firmware does, so `checked` is false, and
+/// losses are instead found by counting the readings received on each channel once the sweep
+/// ends. That count cannot say where in the sweep readings went missing, so they are not
+/// delivered as gaps, and it only catches a loss once at least one channel has received every
+/// reading asked of it.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub struct StreamIntegrity {
+    /// Whether the packets of the sweep were checked, without which losses go undetected
+    pub checked: bool,
+    /// Packets that never arrived, across all channels
+    pub dropped_packets: u32,
+    /// Readings carried by the dropped packets, delivered as gaps
+    pub dropped_samples: u32,
+    /// Packets that arrived out of order or twice, and were discarded
+    pub discarded_packets: u32,
+    /// Whether a channel ended the sweep with more or fewer readings than were requested, so
+    /// its samples after the loss do not line up with those of the other channels
+    pub misaligned: bool,
+}
+
+/// Readings of a sweep at or near the rails of the ADC, where the signal may be outside the
+/// range of the channel
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub struct OverRange {
+    /// Readings at the bottom of the range of each channel
+    pub low: [u32; Sample::num_channels() as usize],
+    /// Readings at the top of the range of each channel
+    pub high: [u32; Sample::num_channels() as usize],
+}
+
+impl OverRange {
+    /// True if any channel has clipped
+    pub fn is_over_range(&self) -> bool {
+        (0..Sample::num_channels() as usize).any(|ch| self.channel(ch))
+    }
+
+    /// True if the channel, numbered from 0, has clipped
+    pub fn channel(&self, channel: usize) -> bool {
+        self.low[channel] > 0 || self.high[channel] > 0
+    }
+
+    fn record(&mut self, channel: usize, rail: Rail) -> u32 {
+        let count = match rail {
+            Rail::Low => &mut self.low[channel],
+            Rail::High => &mut self.high[channel],
+        };
+        *count += 1;
+        *count
+    }
+}
+
+/// Called with the channel, numbered from 0, the first time in a sweep that it clips at a rail
+///
+/// The callback runs on the communication thread, and should return quickly.
+pub type OverRangeCallback = Arc<dyn Fn(usize, Rail) + Send + Sync>;
+
+/// Position of a sweep in a segmented capture, which requests the sweep for the next frame as
+/// soon as it finishes
 #[derive(Debug)]
+struct Segment {
+    index: usize,
+    frames: usize,
+    readings_per_frame: u32,
+    requested_at: Instant,
+    remaining_frames: Arc<RwLock<u32>>,
+    command_tx: CommandSender,
+}
+
 pub(crate) struct DataRequest {
     pub channels: [AnalogInput; 4],
     pub sample_rate_hz: f64,
     pub remaining_samples: Arc<RwLock<u32>>,
+    /// Readings of each channel the nLab was asked for
+    pub requested_readings: u32,
     pub trigger: Trigger,
-    pub sender: Sender<Sample>,
-    pub stop_recv: Receiver<()>,
+    pub sink: DataSink,
+    pub stop_recv: Arc<Mutex<Receiver<()>>>,
+    pub error: Arc<RwLock<Option<RequestError>>>,
+    pub integrity: Arc<RwLock<StreamIntegrity>>,
+    pub over_range: Arc<RwLock<OverRange>>,
+    pub over_range_callback: Option<OverRangeCallback>,
+    /// Time after each sample at which each channel is read
+    pub skews: [f64; 4],
 
-    data_collator: Arc<RwLock<[VecDeque<u16>; 4]>>,
+    data_collator: Arc<RwLock<[VecDeque<Option<u16>>; 4]>>,
+    next_sample_index: RwLock<[u16; 4]>,
+    readings_received: RwLock<[u32; 4]>,
+    block: RwLock<RawBlock>,
+    reducer: RwLock<Reducer>,
+    deskewer: Option<RwLock<Deskewer>>,
+    math: RwLock<MathChannels>,
+    segment: Option<Segment>,
+    first_data_at: RwLock<Option<Instant>>,
 }
 
 /// Handle to an ongoing data sweep, holds received data from nLab
+///
+/// Data arrives one [`Sample`] at a time, in a [`SampleBlock`] for sweeps started with
+/// [`Nlab::request_blocks`], or in a [`RawBlock`] for sweeps started with [`Nlab::request_raw`].
 #[derive(Debug)]
-pub struct SweepHandle {
-    pub receiver: Receiver<Sample>,
+pub struct SweepHandle<T = Sample> {
+    pub receiver: Receiver<T>,
     samples_remaining: Arc<RwLock<u32>>,
     stop_send: Sender<()>,
+    error: Arc<RwLock<Option<RequestError>>>,
+    integrity: Arc<RwLock<StreamIntegrity>>,
+    over_range: Arc<RwLock<OverRange>>,
+    acquisition_mode: AcquisitionMode,
+    oversampling: u32,
+    sample_rate_hz: f64,
+    segments: Option<(Arc<RwLock<u32>>, u32)>,
+}
+
+impl fmt::Debug for DataRequest {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("DataRequest")
+            .field("channels", &self.channels)
+            .field("sample_rate_hz", &self.sample_rate_hz)
+            .field("remaining_samples", &self.remaining_samples)
+            .field("trigger", &self.trigger)
+            .field("sink", &self.sink)
+            .finish_non_exhaustive()
+    }
 }
 
 impl Nlab {
+    /// Sets a callback that warns when a channel clips during a sweep
+    ///
+    /// Only applies to sweeps requested after it is set.
+    pub fn on_over_range(&mut self, callback: impl Fn(usize, Rail) + Send + Sync + 'static) {
+        self.over_range_callback = Some(Arc::new(callback));
+    }
+
+    pub fn clear_over_range_callback(&mut self) {
+        self.over_range_callback = None;
+    }
+
     pub fn request(&self, sample_rate_hz: f64, number_of_samples: u32, trigger: Option<Trigger>) -> SweepHandle {
         let (tx, rx) = mpsc::channel::<Sample>();
+        self.start_sweep(sample_rate_hz, number_of_samples, trigger, DataSink::Samples(tx), rx, None)
+    }
+
+    /// Requests a sweep whose data is delivered in blocks of `block_size` samples
+    ///
+    /// Blocks avoid the cost of handling each sample separately at high sample rates. The last
+    /// block of a sweep holds whatever samples remain, and may be shorter.
+    pub fn request_blocks(&self, sample_rate_hz: f64, number_of_samples: u32, trigger: Option<Trigger>, block_size: usize) -> SweepHandle<SampleBlock> {
+        let (tx, rx) = mpsc::channel::<SampleBlock>();
+        self.start_sweep(sample_rate_hz, number_of_samples, trigger, DataSink::Blocks(tx, block_size.max(1)), rx, None)
+    }
+
+    /// Requests a sweep whose data is delivered in blocks of `block_size` samples, as the codes
+    /// read by the ADC along with their conversion to volts
+    pub fn request_raw(&self, sample_rate_hz: f64, number_of_samples: u32, trigger: Option<Trigger>, block_size: usize) -> SweepHandle<RawBlock> {
+        let (tx, rx) = mpsc::channel::<RawBlock>();
+        self.start_sweep(sample_rate_hz, number_of_samples, trigger, DataSink::Raw(tx, block_size.max(1)), rx, None)
+    }
+
+    /// Requests a segmented capture of `frames` sweeps of `samples_per_frame`, each started by
+    /// the trigger
+    ///
+    /// The trigger is rearmed by requesting the sweep for the next frame as soon as the last one
+    /// finishes, without waiting on the caller. Stopping the capture ends it with the frame in
+    /// progress, which may be short. [`SweepHandle::remaining_samples`] counts the samples of
+    /// every frame that has not finished.
+    pub fn request_segments(&self, sample_rate_hz: f64, samples_per_frame: u32, frames: usize, trigger: Trigger) -> SweepHandle<Frame> {
+        let (tx, rx) = mpsc::channel::<Frame>();
+        let trigger = Trigger { is_enabled: true, ..trigger };
+        self.start_sweep(sample_rate_hz, samples_per_frame, Some(trigger), DataSink::Frames(tx, samples_per_frame as usize), rx, Some(frames.max(1)))
+    }
+
+    /// Starts a sweep that streams until stopped, or until it has taken
+    /// [`CONTINUOUS_SWEEP_SAMPLES`]
+    pub(crate) fn start_continuous_sweep<T>(&self, sample_rate_hz: f64, sink: DataSink, receiver: Receiver<T>) -> Result<SweepHandle<T>, RequestError> {
+        if self.max_samples_at(sample_rate_hz) < u32::MAX {
+            return Err(RequestError::Rejected(format!("{sample_rate_hz} hz is too fast to stream with the channels that are on")));
+        }
+        Ok(self.start_sweep(sample_rate_hz, CONTINUOUS_SWEEP_SAMPLES, None, sink, receiver, None))
+    }
+
+    fn start_sweep<T>(&self, sample_rate_hz: f64, number_of_samples: u32, trigger: Option<Trigger>, sink: DataSink, receiver: Receiver<T>, frames: Option<usize>) -> SweepHandle<T> {
         let (stop_send, stop_recv) = mpsc::channel::<()>();
+        let mut channels = [self.ch1, self.ch2, self.ch3, self.ch4];
+        self.calibration.apply(&mut channels);
+
+        // Raw codes are delivered as read, so the nLab only oversamples for sweeps in volts
+        let acquisition_mode = match sink {
+            DataSink::Raw(..) => AcquisitionMode::Normal,
+            _ => self.acquisition_mode,
+        };
+        let channels_on = channels.iter().filter(|ch| ch.is_on).count();
+        let capabilities = self.capabilities();
+        let oversampling = acquisition_mode.oversampling(sample_rate_hz, number_of_samples, &capabilities, channels_on);
+
+        // The nLab divides its clock by a whole number, so the rate is snapped to one it can run
+        // at. Rates that are too fast, or are not a positive number, are left for the capability
+        // check to reject.
+        let sample_rates = capabilities.sample_rates(channels_on);
+        let sample_rate_hz = match sample_rate_hz * oversampling as f64 {
+            rate if rate > 0.0 && rate <= sample_rates.fastest() => sample_rates.nearest(rate),
+            rate => rate,
+        };
+        let requested_samples = number_of_samples;
+        // Only a sweep too long for even a single reading per sample gets no count, and it is
+        // cut short at the most readings the nLab can be asked for
+        let number_of_samples = acquisition_mode.readings(number_of_samples, oversampling).unwrap_or(u32::MAX);
+        let sink = match sink {
+            DataSink::Blocks(sender, block_size) => DataSink::Blocks(sender, block_size * oversampling as usize),
+            DataSink::Frames(sender, _) => DataSink::Frames(sender, number_of_samples as usize),
+            sink => sink,
+        };
+        let segment = frames.map(|frames| Segment {
+            index: 0,
+            frames,
+            readings_per_frame: number_of_samples,
+            requested_at: Instant::now(),
+            remaining_frames: Arc::new(RwLock::new(frames as u32)),
+            command_tx: self.command_tx.clone(),
+        });
+        let segments = segment.as_ref().map(|segment| (segment.remaining_frames.clone(), requested_samples));
 
         let remaining_samples = Arc::new(RwLock::new(number_of_samples));
-        let command = Command::RequestData(DataRequest {
-            channels: [self.ch1, self.ch2, self.ch3, self.ch4],
+        let error = Arc::new(RwLock::new(None));
+        let integrity = Arc::new(RwLock::new(StreamIntegrity {
+            checked: capabilities.sequenced_packets,
+            ..Default::default()
+        }));
+        let over_range = Arc::new(RwLock::new(OverRange::default()));
+        let skews = capabilities.channel_skews(channels.map(|ch| ch.is_on));
+        let block = RawBlock::new(0.0, 1.0 / sample_rate_hz, &channels, skews, sink.capacity());
+        let command = Command::RequestData(Box::new(DataRequest {
+            channels,
             sample_rate_hz,
             remaining_samples: remaining_samples.clone(),
+            requested_readings: number_of_samples,
             trigger: trigger.unwrap_or_default(),
-            sender: tx,
-            stop_recv,
+            sink,
+            stop_recv: Arc::new(Mutex::new(stop_recv)),
+            error: error.clone(),
+            integrity: integrity.clone(),
+            over_range: over_range.clone(),
+            over_range_callback: self.over_range_callback.clone(),
+            skews,
             data_collator: Default::default(),
-        });
+            next_sample_index: Default::default(),
+            readings_received: Default::default(),
+            block: RwLock::new(block),
+            reducer: RwLock::new(Reducer::new(acquisition_mode, oversampling)),
+            deskewer: self.deskew.then(|| RwLock::new(Deskewer::default())),
+            math: RwLock::new(MathChannels::new(&self.math_channels)),
+            segment,
+            first_data_at: RwLock::new(None),
+        }));
 
         if self.command_tx.send(command).is_err() {
             *remaining_samples.write().unwrap() = 0;
+            if let Some((remaining_frames, _)) = &segments {
+                *remaining_frames.write().unwrap() = 0;
+            }
+            *error.write().unwrap() = Some(RequestError::Disconnected);
         }
 
         SweepHandle {
-            receiver: rx,
+            receiver,
             samples_remaining: remaining_samples,
             stop_send,
+            error,
+            integrity,
+            over_range,
+            acquisition_mode,
+            oversampling,
+            sample_rate_hz: sample_rate_hz / oversampling as f64,
+            segments,
         }
     }
 }
 
-impl SweepHandle {
+impl<T> SweepHandle<T> {
     pub fn remaining_samples(&self) -> u32 {
-        *self.samples_remaining.read().unwrap()
+        if let Some((remaining_frames, samples_per_frame)) = &self.segments {
+            return *remaining_frames.read().unwrap() * samples_per_frame;
+        }
+        let readings = *self.samples_remaining.read().unwrap();
+        let lookahead = self.acquisition_mode.readings(0, self.oversampling).unwrap_or_default();
+        readings.saturating_sub(lookahead).div_ceil(self.oversampling)
+    }
+
+    /// Number of readings the nLab takes for each sample delivered, see [`AcquisitionMode`]
+    pub fn oversampling(&self) -> u32 {
+        self.oversampling
+    }
+
+    /// Rate at which samples are delivered, which is the requested rate snapped to one the nLab
+    /// can run at, see [`Capabilities::sample_rates`]
+    pub fn sample_rate_hz(&self) -> f64 {
+        self.sample_rate_hz
     }
 
     pub fn stop(&self) {
         self.stop_send.send(()).ok();
     }
+
+    /// Returns the reason the sweep ended early, if the nLab could not fulfill it
+    pub fn error(&self) -> Option<RequestError> {
+        self.error.read().unwrap().clone()
+    }
+
+    /// Returns the number of packets lost or discarded so far in the sweep
+    pub fn integrity(&self) -> StreamIntegrity {
+        *self.integrity.read().unwrap()
+    }
+
+    /// Returns the number of readings so far in the sweep that clipped on each channel
+    pub fn over_range(&self) -> OverRange {
+        *self.over_range.read().unwrap()
+    }
+}
+
+impl SweepHandle<Frame> {
+    /// Waits for the capture to finish, returning its frames
+    pub fn wait(self) -> Result<Vec<Frame>, RequestError> {
+        let frames = self.receiver.iter().collect();
+        match self.error() {
+            Some(error) => Err(error),
+            None => Ok(frames),
+        }
+    }
 }
 
 impl ScopeCommand for DataRequest {
+    fn check_capabilities(&self, capabilities: &Capabilities) -> Result<(), Box<dyn Error>> {
+        let num_channels_on = self.channels.iter().filter(|&ch| ch.is_on).count();
+        if !(self.sample_rate_hz.is_finite() && self.sample_rate_hz > 0.0) {
+            return Err(format!("Cannot fulfill data request: {} hz is not a sample rate", self.sample_rate_hz).into());
+        }
+        let max_sample_rate = capabilities.max_sample_rate_with(num_channels_on);
+        if self.sample_rate_hz > max_sample_rate {
+            return Err(format!("Cannot fulfill data request: maximum sample rate with {num_channels_on} channels on is {max_sample_rate} hz").into());
+        }
+
+        if self.trigger.is_enabled && !capabilities.supports_trigger(self.trigger.trigger_type) {
+            return Err(format!("{:?} triggers are not supported by the nLab firmware", self.trigger.trigger_type).into());
+        }
+
+        let (min_gain, max_gain) = capabilities.gain_range;
+        for (i, ch) in self.channels.iter().enumerate().filter(|(_, ch)| ch.is_on) {
+            if ch.gain() < min_gain - 1e-9 || ch.gain() > max_gain + 1e-9 {
+                return Err(format!("Ch{} gain of {:.3} is outside the supported range of {min_gain:.3} to {max_gain:.3}", i + 1, ch.gain()).into());
+            }
+        }
+        Ok(())
+    }
+
     fn fill_tx_buffer_legacy(&self, usb_buf: &mut [u8; 65]) -> Result<(), Box<dyn Error>> {
         usb_buf[1] = 0x08;
 
@@ -103,9 +618,9 @@ impl ScopeCommand for DataRequest {
 
         let samples_between_records: u32 = match num_channels_on {
             0 => { return Err("No scope channels are on".into()); }
-            1 => { (4_000_000.0 / self.sample_rate_hz) as u32 }
-            2 => { (2_000_000.0 / self.sample_rate_hz) as u32 }
-            3 | 4 => { (1_000_000.0 / self.sample_rate_hz) as u32 }
+            1 => { (4_000_000.0 / self.sample_rate_hz).round() as u32 }
+            2 => { (2_000_000.0 / self.sample_rate_hz).round() as u32 }
+            3 | 4 => { (1_000_000.0 / self.sample_rate_hz).round() as u32 }
             _ => { return Err("Unexpected number of channels are on".into()); }
         };
 
@@ -167,7 +682,7 @@ impl ScopeCommand for DataRequest {
     }
 
     fn fill_tx_buffer(&self, usb_buf: &mut [u8; 64]) -> Result<(), Box<dyn Error>> {
-        let samples_between_records: u32 = (2_000_000.0 / self.sample_rate_hz) as u32;
+        let samples_between_records: u32 = (2_000_000.0 / self.sample_rate_hz).round() as u32;
 
         let total_samples = *self.remaining_samples.read().unwrap();
         debug!("Requesting {total_samples} samples with {samples_between_records} samples between records");
@@ -225,21 +740,12 @@ impl ScopeCommand for DataRequest {
 
     fn handle_rx_legacy(&self, usb_buf: &[u8; 64]) {
         let number_received_samples = usb_buf[3] as u32;
-
-        {
-            let mut remaining_samples = self.remaining_samples.write().unwrap();
-            *remaining_samples -= number_received_samples;
-            trace!("Received {number_received_samples} samples, {remaining_samples} samples remaining");
-        }
-
+        self.mark_first_data(number_received_samples as usize);
 
         let mut total_parsed_readings: usize = 0;
 
         for _ in 0..number_received_samples {
-            let mut sample = Sample {
-                time_since_start: 0.0,
-                data: [None; 4],
-            };
+            let mut data_collator = self.data_collator.write().unwrap();
 
             for (i, ch) in self.channels.iter().enumerate() {
                 if ch.is_on {
@@ -252,16 +758,21 @@ impl ScopeCommand for DataRequest {
                     };
 
                     trace!("Ch{}: ADCData: {} Vi: {}", i+1, adc_data, ch.voltage_from_measurement(adc_data));
-                    sample.data[i] = Some(ch.voltage_from_measurement(adc_data));
+                    data_collator[i].push_back(Some(adc_data));
                     total_parsed_readings += 1;
                 }
             }
-
-            self.sender.send(sample).unwrap();
         }
+
+        self.collate_results();
     }
     fn handle_rx(&self, _usb_buf: &[u8; 64]) {}
 
+    fn handle_failure(&self, error: RequestError) {
+        *self.error.write().unwrap() = Some(error);
+        self.end();
+    }
+
     fn is_finished(&self) -> bool {
         *self.remaining_samples.read().unwrap() == 0
     }
@@ -269,8 +780,38 @@ impl ScopeCommand for DataRequest {
 
 
 impl DataRequest {
+    /// Adds the readings in a channel data packet to the collator
+    ///
+    /// When the packets are checked, see [`StreamIntegrity::checked`], bytes 2-3 of the packet hold the index within the sweep of its
+    /// first reading, modulo 2^16. Readings skipped over by a packet were lost in transfer and
+    /// are collated as gaps, which keeps the channels aligned; packets that go backwards in the
+    /// sweep arrived out of order or twice, and are discarded.
     pub(crate) fn handle_incoming_data(&self, usb_buf: &[u8; 64], channel: usize) {
         let num_received = usb_buf[1] as usize;
+        self.mark_first_data(num_received);
+
+        if self.integrity.read().unwrap().checked {
+            let first_index = u16::from_le_bytes([usb_buf[2], usb_buf[3]]);
+            let mut next_sample_index = self.next_sample_index.write().unwrap();
+            let skipped = first_index.wrapping_sub(next_sample_index[channel]);
+
+            if skipped >= 0x8000 {
+                warn!("Discarding out of order data packet on Ch{}", channel + 1);
+                self.integrity.write().unwrap().discarded_packets += 1;
+                return;
+            }
+            if skipped > 0 {
+                warn!("Lost {skipped} readings on Ch{} before sample index {first_index}", channel + 1);
+                let mut integrity = self.integrity.write().unwrap();
+                integrity.dropped_packets += 1;
+                integrity.dropped_samples += skipped as u32;
+                let input_buffer = &mut self.data_collator.write().unwrap()[channel];
+                input_buffer.extend(std::iter::repeat_n(None, skipped as usize));
+            }
+            next_sample_index[channel] = first_index.wrapping_add(num_received as u16);
+        }
+        self.readings_received.write().unwrap()[channel] += num_received as u32;
+
         let mut num_parsed: usize = 0;
         while num_parsed < num_received {
             let byte: usize = 4 + num_parsed / 2 * 3;
@@ -280,12 +821,15 @@ impl DataRequest {
                 1 => (usb_buf[byte + 1] as u16 >> 4) | ((usb_buf[byte + 2] as u16) << 4),
                 _ => panic!("Unexpected behavior of odd/even bitmask")
             };
-            self.data_collator.write().unwrap()[channel].push_back(adc_data);
+            self.data_collator.write().unwrap()[channel].push_back(Some(adc_data));
             num_parsed += 1;
         }
     }
 
-    pub(crate) fn collate_results(&self) {
+    /// Collects every sample that has arrived on all open channels, returning how many there were
+    ///
+    /// Samples are delivered as soon as they are collected, or once a block of them is full.
+    pub(crate) fn collate_results(&self) -> usize {
         let data_collator = &mut *self.data_collator.write().unwrap();
 
         // Find the number of samples received for all channels that are on using filter and map
@@ -296,32 +840,401 @@ impl DataRequest {
             .map(|(_, collator_channel)| collator_channel.len())
             .collect::<Vec<usize>>();
 
-        if let Some(&complete_samples) = received_samples.iter().min() {
-            let mut samples_to_pop = complete_samples;
-            while samples_to_pop > 0 {
-                let mut sample = Sample {
-                    time_since_start: 0.0,
-                    data: [None; 4],
-                };
-
-                for (ch, input_buffer) in data_collator.iter_mut().enumerate() {
-                    let channel = &self.channels[ch];
+        let complete_samples = match received_samples.iter().min() {
+            Some(&complete_samples) if complete_samples > 0 => complete_samples,
+            _ => return 0,
+        };
 
-                    if channel.is_on {
-                        let data = input_buffer.pop_front().unwrap();
-                        sample.data[ch] = Some(channel.voltage_from_measurement(data));
+        let mut block = self.block.write().unwrap();
+        for _ in 0..complete_samples {
+            for (ch, input_buffer) in data_collator.iter_mut().enumerate() {
+                if let Some(codes) = &mut block.channels[ch] {
+                    let code = input_buffer.pop_front().unwrap().unwrap_or(RawBlock::LOST_READING);
+                    if let Some(rail) = Rail::of(code) {
+                        self.record_over_range(ch, rail);
                     }
+                    codes.push(code);
+                }
+            }
+            if block.len() == self.sink.block_size() {
+                self.deliver(&mut block);
+            }
+        }
+
+        let sweep_finished = {
+            let mut remaining_samples = self.remaining_samples.write().unwrap();
+            *remaining_samples = remaining_samples.saturating_sub(complete_samples as u32);
+            trace!("Received {complete_samples} samples, {remaining_samples} samples remaining");
+            *remaining_samples == 0
+        };
+
+        // Continuous sinks do not wait on a block to fill, and the end of a sweep sends what is left
+        if matches!(self.sink, DataSink::Samples(_) | DataSink::Roll(_) | DataSink::Triggered(..)) || sweep_finished {
+            self.deliver(&mut block);
+        }
+        if sweep_finished {
+            self.check_reading_counts();
+            self.request_next_segment();
+        }
+        complete_samples
+    }
+
+    /// Notes when the first readings of the sweep arrived, less the time taken to read them
+    fn mark_first_data(&self, num_received: usize) {
+        let mut first_data_at = self.first_data_at.write().unwrap();
+        if first_data_at.is_none() && num_received > 0 {
+            let reading_time = Duration::from_secs_f64(num_received as f64 / self.sample_rate_hz);
+            *first_data_at = Some(Instant::now().checked_sub(reading_time).unwrap_or_else(Instant::now));
+        }
+    }
+
+    /// Requests the sweep for the next frame of a segmented capture
+    fn request_next_segment(&self) {
+        let segment = match &self.segment {
+            Some(segment) => segment,
+            None => return,
+        };
+        *segment.remaining_frames.write().unwrap() = (segment.frames - segment.index - 1) as u32;
+        if segment.index + 1 >= segment.frames {
+            return;
+        }
+
+        let next = DataRequest {
+            channels: self.channels,
+            sample_rate_hz: self.sample_rate_hz,
+            remaining_samples: Arc::new(RwLock::new(segment.readings_per_frame)),
+            requested_readings: segment.readings_per_frame,
+            trigger: self.trigger,
+            sink: self.sink.clone(),
+            stop_recv: self.stop_recv.clone(),
+            error: self.error.clone(),
+            integrity: self.integrity.clone(),
+            over_range: self.over_range.clone(),
+            over_range_callback: self.over_range_callback.clone(),
+            skews: self.skews,
+            data_collator: Default::default(),
+            next_sample_index: Default::default(),
+            readings_received: Default::default(),
+            block: RwLock::new(RawBlock::new(0.0, 1.0 / self.sample_rate_hz, &self.channels, self.skews, self.sink.capacity())),
+            reducer: RwLock::new(self.reducer.read().unwrap().restart()),
+            deskewer: self.deskewer.as_ref().map(|_| RwLock::new(Deskewer::default())),
+            math: RwLock::new(self.math.read().unwrap().restart()),
+            segment: Some(Segment {
+                index: segment.index + 1,
+                command_tx: segment.command_tx.clone(),
+                remaining_frames: segment.remaining_frames.clone(),
+                ..*segment
+            }),
+            first_data_at: RwLock::new(None),
+        };
+        if segment.command_tx.send(Command::RequestData(Box::new(next))).is_err() {
+            *segment.remaining_frames.write().unwrap() = 0;
+        }
+    }
+
+    /// Compares the readings received on each channel with the number requested, once per sweep
+    ///
+    /// Without numbered packets, a channel that ends the sweep short lost readings and one that
+    /// ends it long received a packet twice. A sweep stopped before any channel received every
+    /// reading is not judged, as its channels may simply have been stopped at different points.
+    fn check_reading_counts(&self) {
+        let received = mem::take(&mut *self.readings_received.write().unwrap());
+        if self.integrity.read().unwrap().checked {
+            return;
+        }
+        let channels_on = || (0..self.channels.len()).filter(|&ch| self.channels[ch].is_on);
+        if !channels_on().any(|ch| received[ch] >= self.requested_readings) {
+            return;
+        }
+
+        let mut misaligned = false;
+        let mut dropped_samples = 0;
+        for ch in channels_on().filter(|&ch| received[ch] != self.requested_readings) {
+            warn!("Ch{} received {} of the {} readings requested", ch + 1, received[ch], self.requested_readings);
+            dropped_samples += self.requested_readings.saturating_sub(received[ch]);
+            misaligned = true;
+        }
+        let mut integrity = self.integrity.write().unwrap();
+        integrity.dropped_samples += dropped_samples;
+        integrity.misaligned |= misaligned;
+    }
+
+    /// Counts a clipped reading, warning the first time a channel clips at each rail
+    fn record_over_range(&self, channel: usize, rail: Rail) {
+        if self.over_range.write().unwrap().record(channel, rail) == 1 {
+            warn!("Ch{} is clipping at the {rail:?} rail of the ADC, its range may be too small", channel + 1);
+            if let Some(callback) = &self.over_range_callback {
+                callback(channel, rail);
+            }
+        }
+    }
+
+    /// Ends the sweep, delivering any samples that have not been sent yet
+    pub(crate) fn end(&self) {
+        self.check_reading_counts();
+        *self.remaining_samples.write().unwrap() = 0;
+        if let Some(segment) = &self.segment {
+            *segment.remaining_frames.write().unwrap() = 0;
+        }
+        self.deliver(&mut self.block.write().unwrap());
+    }
+
+    /// Converts the readings in `block` to the samples delivered in volts
+    fn volts(&self, block: &RawBlock) -> SampleBlock {
+        let samples = self.reducer.write().unwrap().reduce(block.to_volts());
+        let mut samples = match &self.deskewer {
+            Some(deskewer) => deskewer.write().unwrap().deskew(samples),
+            None => samples,
+        };
+        self.math.write().unwrap().compute(&mut samples);
+        samples
+    }
+
+    /// Sends the samples collected in `block`, and starts the next block after them
+    fn deliver(&self, block: &mut RawBlock) {
+        if block.is_empty() {
+            return;
+        }
+        let next_start_time = block.start_time + block.len() as f64 * block.dt;
+        let next_block = RawBlock::new(next_start_time, block.dt, &self.channels, self.skews, self.sink.capacity());
+        let block = mem::replace(block, next_block);
+
+        // Nobody is left to receive the samples if the sweep handle has been dropped
+        match &self.sink {
+            DataSink::Samples(sender) => {
+                for sample in self.volts(&block).samples() {
+                    sender.send(sample).ok();
+                }
+            }
+            DataSink::Blocks(sender, _) => {
+                let block = self.volts(&block);
+                if !block.is_empty() {
+                    sender.send(block).ok();
                 }
-                self.sender.send(sample).unwrap();
-                samples_to_pop -= 1;
             }
+            DataSink::Raw(sender, _) => {
+                sender.send(block).ok();
+            }
+            DataSink::Roll(buffer) => {
+                buffer.write().unwrap().push(&self.volts(&block));
+            }
+            DataSink::Triggered(sender, engine) => {
+                for capture in engine.write().unwrap().process(&self.volts(&block)) {
+                    sender.send(capture).ok();
+                }
+            }
+            DataSink::Frames(sender, _) => {
+                let (index, requested_at) = match &self.segment {
+                    Some(segment) => (segment.index, segment.requested_at),
+                    None => (0, Instant::now()),
+                };
+                let trigger_time = match *self.first_data_at.read().unwrap() {
+                    Some(first_data_at) => first_data_at.saturating_duration_since(requested_at).as_secs_f64(),
+                    None => 0.0,
+                };
+                let samples = self.volts(&block);
+                sender.send(Frame { index, trigger_time, samples }).ok();
+            }
+        }
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn data_request(number_of_samples: u32, sink: DataSink) -> DataRequest {
+        let (_stop_send, stop_recv) = mpsc::channel();
+        let stop_recv = Arc::new(Mutex::new(stop_recv));
+        let mut channels = [AnalogInput::create(false); 4];
+        channels[2].turn_off();
+        channels[3].turn_off();
+        let block = RawBlock::new(0.0, 1e-3, &channels, [0.0; 4], sink.capacity());
+        DataRequest {
+            channels,
+            sample_rate_hz: 1000.0,
+            remaining_samples: Arc::new(RwLock::new(number_of_samples)),
+            requested_readings: number_of_samples,
+            trigger: Trigger::default(),
+            sink,
+            stop_recv,
+            error: Default::default(),
+            integrity: Default::default(),
+            over_range: Default::default(),
+            over_range_callback: None,
+            skews: [0.0; 4],
+            data_collator: Default::default(),
+            next_sample_index: Default::default(),
+            readings_received: Default::default(),
+            block: RwLock::new(block),
+            reducer: RwLock::new(Reducer::new(AcquisitionMode::Normal, 1)),
+            deskewer: None,
+            math: Default::default(),
+            segment: None,
+            first_data_at: RwLock::new(None),
+        }
+    }
+
+    fn packet(first_index: u16, num_samples: u8) -> [u8; 64] {
+        let mut buf = [0u8; 64];
+        buf[1] = num_samples;
+        buf[2..4].copy_from_slice(&first_index.to_le_bytes());
+        buf
+    }
 
+    #[test]
+    fn finished_frames_request_the_next_frame() {
+        let (sender, receiver) = mpsc::channel();
+        let (command_tx, command_rx) = mpsc::channel();
+        let mut request = data_request(10, DataSink::Frames(sender, 10));
+        let remaining_frames = Arc::new(RwLock::new(2));
+        request.segment = Some(Segment {
+            index: 0,
+            frames: 2,
+            readings_per_frame: 10,
+            requested_at: Instant::now(),
+            remaining_frames: remaining_frames.clone(),
+            command_tx: CommandSender::new(command_tx, None),
+        });
 
-            if complete_samples > 0 {
-                let mut remaining_samples = self.remaining_samples.write().unwrap();
-                *remaining_samples -= complete_samples as u32;
-                trace!("Received {complete_samples} samples, {remaining_samples} samples remaining");
+        request.handle_incoming_data(&packet(0, 10), 0);
+        request.handle_incoming_data(&packet(0, 10), 1);
+        request.collate_results();
+
+        let frame = receiver.try_recv().unwrap();
+        assert_eq!((frame.index, frame.samples.len()), (0, 10));
+        assert_eq!(*remaining_frames.read().unwrap(), 1);
+
+        match command_rx.try_recv() {
+            Ok(Command::RequestData(next)) => {
+                assert_eq!(next.segment.as_ref().unwrap().index, 1);
+                assert_eq!(*next.remaining_samples.read().unwrap(), 10);
+                assert_eq!(next.sample_rate_hz, request.sample_rate_hz);
             }
+            _ => panic!("The next frame was not requested"),
+        }
+    }
+
+    #[test]
+    fn raw_blocks_keep_the_adc_codes() {
+        let (sender, receiver) = mpsc::channel();
+        let request = data_request(4, DataSink::Raw(sender, 4));
+        request.integrity.write().unwrap().checked = true;
+
+        // Codes 0x000 and 0xFFF, 0x123 and 0x456, packed two readings to three bytes
+        let mut ch1 = packet(0, 4);
+        ch1[4..10].copy_from_slice(&[0x00, 0xF0, 0xFF, 0x23, 0x61, 0x45]);
+        request.handle_incoming_data(&ch1, 0);
+        request.handle_incoming_data(&packet(2, 2), 1);
+        request.collate_results();
+
+        let block = receiver.try_recv().unwrap();
+        assert_eq!(block.channels[0], Some(vec![0x000, 0xFFF, 0x123, 0x456]));
+        assert_eq!(block.channels[1], Some(vec![RawBlock::LOST_READING, RawBlock::LOST_READING, 0, 0]));
+
+        let volts = block.to_volts();
+        let conversion = block.conversions[0].unwrap();
+        assert_eq!(volts.channels[0].as_ref().unwrap()[3], conversion.volts(0x456) as f32);
+        assert!(volts.channels[1].as_ref().unwrap()[0].is_nan());
+
+        // Readings at the rails are flagged, and lost readings are not
+        assert_eq!(volts.clipped[0], Some(vec![true, true, false, false]));
+        let over_range = *request.over_range.read().unwrap();
+        assert_eq!((over_range.low, over_range.high), ([1, 2, 0, 0], [1, 0, 0, 0]));
+        assert!(over_range.channel(0) && over_range.channel(1) && !over_range.channel(2));
+    }
+
+    #[test]
+    fn lost_packets_are_collated_as_gaps() {
+        let (sender, receiver) = mpsc::channel();
+        let request = data_request(30, DataSink::Samples(sender));
+        request.integrity.write().unwrap().checked = true;
+
+        request.handle_incoming_data(&packet(0, 10), 0);
+        request.handle_incoming_data(&packet(20, 10), 0);
+        request.handle_incoming_data(&packet(0, 10), 1);
+        request.handle_incoming_data(&packet(10, 10), 1);
+        request.handle_incoming_data(&packet(20, 10), 1);
+        request.handle_incoming_data(&packet(10, 10), 1);
+        request.collate_results();
+
+        let samples: Vec<Sample> = receiver.try_iter().collect();
+        assert_eq!(samples.len(), 30);
+        assert!(request.is_finished());
+        for (i, sample) in samples.iter().enumerate() {
+            let lost = (10..20).contains(&i);
+            assert_eq!(sample.gap, lost);
+            assert_eq!(sample.data[0].is_none(), lost);
+            assert!(sample.data[1].is_some());
         }
+
+        assert_eq!(*request.integrity.read().unwrap(), StreamIntegrity {
+            checked: true,
+            dropped_packets: 1,
+            dropped_samples: 10,
+            discarded_packets: 1,
+            misaligned: false,
+        });
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn unnumbered_packets_are_counted_when_the_sweep_ends() {
+        let (sender, _receiver) = mpsc::channel();
+        let request = data_request(30, DataSink::Samples(sender));
+
+        // Ch1 receives every reading, while a packet for Ch2 never arrives
+        for _ in 0..3 {
+            request.handle_incoming_data(&packet(0, 10), 0);
+        }
+        for _ in 0..2 {
+            request.handle_incoming_data(&packet(0, 10), 1);
+        }
+        request.collate_results();
+        assert!(!request.is_finished());
+        assert_eq!(request.integrity.read().unwrap().dropped_samples, 0);
+
+        request.end();
+        request.end();
+        let integrity = *request.integrity.read().unwrap();
+        assert_eq!((integrity.checked, integrity.dropped_samples, integrity.misaligned), (false, 10, true));
+
+        // A sweep stopped before any channel finished cannot be judged
+        let (sender, _receiver) = mpsc::channel();
+        let request = data_request(30, DataSink::Samples(sender));
+        request.handle_incoming_data(&packet(0, 10), 0);
+        request.end();
+        assert_eq!(*request.integrity.read().unwrap(), StreamIntegrity::default());
+    }
+
+    #[test]
+    fn sample_rates_must_be_positive_numbers() {
+        let capabilities = Capabilities::v2_default();
+        for rate in [f64::NAN, f64::INFINITY, 0.0, -1000.0] {
+            let (sender, _receiver) = mpsc::channel();
+            let request = DataRequest { sample_rate_hz: rate, ..data_request(10, DataSink::Samples(sender)) };
+            assert!(request.check_capabilities(&capabilities).is_err());
+        }
+        let (sender, _receiver) = mpsc::channel();
+        assert!(data_request(10, DataSink::Samples(sender)).check_capabilities(&capabilities).is_ok());
+    }
+
+    #[test]
+    fn samples_are_delivered_in_blocks() {
+        let (sender, receiver) = mpsc::channel();
+        let request = data_request(30, DataSink::Blocks(sender, 8));
+
+        for first_index in [0u16, 20, 40].iter() {
+            request.handle_incoming_data(&packet(*first_index / 2, 10), 0);
+            request.handle_incoming_data(&packet(*first_index / 2, 10), 1);
+            request.collate_results();
+        }
+
+        let blocks: Vec<SampleBlock> = receiver.try_iter().collect();
+        assert_eq!(blocks.iter().map(SampleBlock::len).collect::<Vec<_>>(), vec![8, 8, 8, 6]);
+        assert!(blocks.iter().all(|block| block.channels[2].is_none()));
+        assert!((blocks[3].start_time - 0.024).abs() < 1e-12);
+
+        let samples: Vec<Sample> = blocks.iter().flat_map(SampleBlock::samples).collect();
+        assert_eq!(samples.len(), 30);
+        assert!((samples[29].time_since_start - 0.029).abs() < 1e-12);
+    }
+}
@@ -0,0 +1,243 @@
+/***************************************************************************************************
+ *
+ *  nLabs, LLC
+ *  https://getnlab.com
+ *  Copyright(c) 2020. All Rights Reserved
+ *
+ *  This file is part of the nLab API
+ *
+ **************************************************************************************************/
+
+use std::collections::BTreeMap;
+
+use log::warn;
+
+use super::data_requests::{Sample, SampleBlock};
+use super::Nlab;
+use super::RequestError;
+use super::trigger::{Trigger, TriggerType};
+
+/// Waveform of a repetitive signal interleaved from many triggered acquisitions, at a multiple
+/// of the sample rate the nLab ran at
+#[derive(Debug, Clone)]
+pub struct EquivalentTimeWaveform {
+    /// Sample rate of `waveform`, which is the rate of each acquisition times the interleave factor
+    pub effective_rate_hz: f64,
+    /// Number of acquisitions that crossed the trigger level, and were interleaved
+    pub acquisitions: usize,
+    /// Fraction of the samples in `waveform` that at least one acquisition contributed to
+    pub coverage: f64,
+    /// Number of the interleave factor's phases between two samples that at least one
+    /// acquisition was triggered at
+    ///
+    /// Fewer phases than the factor leave gaps in the waveform. A signal locked to the clock of
+    /// the nLab triggers at the same phase every time, and more acquisitions will not fill them.
+    pub phases: u32,
+    /// The interleaved waveform, timed from where the trigger channel crosses the trigger level
+    ///
+    /// Samples that no acquisition contributed to are NaN.
+    pub waveform: SampleBlock,
+}
+
+/// Readings of one channel gathered into bins of the effective sample period
+#[derive(Debug, Default, Copy, Clone)]
+struct Bin {
+    total: f64,
+    count: u32,
+    clipped: bool,
+}
+
+/// Interleaves triggered acquisitions of a repetitive signal into one waveform
+///
+/// The trigger of the nLab fires at a random point between two of its samples. Each
+/// acquisition is placed in time by interpolating where the trigger channel crosses the trigger
+/// level, and its readings fall into bins `factor` times finer than the sample period. Given
+/// enough acquisitions, every bin holds readings and the waveform is sampled `factor` times
+/// faster than the nLab can.
+#[derive(Debug, Clone)]
+pub struct EquivalentTimeSampler {
+    trigger: Trigger,
+    factor: u32,
+    acquisitions: usize,
+    dt: f64,
+    /// Whether an acquisition has been triggered at each phase between two samples
+    phases: Vec<bool>,
+    bins: [BTreeMap<i64, Bin>; Sample::num_channels() as usize],
+}
+
+impl EquivalentTimeSampler {
+    pub fn new(trigger: Trigger, factor: u32) -> Self {
+        EquivalentTimeSampler {
+            trigger,
+            factor: factor.max(1),
+            acquisitions: 0,
+            dt: 0.0,
+            phases: vec![false; factor.max(1) as usize],
+            bins: Default::default(),
+        }
+    }
+
+    /// Adds an acquisition, returning whether it crossed the trigger level and was interleaved
+    pub fn add(&mut self, acquisition: &SampleBlock) -> bool {
+        let crossing = match self.crossing(acquisition) {
+            Some(crossing) => crossing,
+            None => return false,
+        };
+        self.dt = acquisition.dt;
+        self.phases[(crossing.fract() * self.factor as f64).round() as usize % self.factor as usize] = true;
+        let bin_width = acquisition.dt / self.factor as f64;
+        let source_skew = acquisition.skews[self.trigger.source_channel];
+
+        for ch in 0..Sample::num_channels() as usize {
+            let readings = match &acquisition.channels[ch] {
+                Some(readings) => readings,
+                None => continue,
+            };
+            let skew = acquisition.skews[ch] - source_skew;
+            for (i, &reading) in readings.iter().enumerate().filter(|(_, reading)| !reading.is_nan()) {
+                let time = (i as f64 - crossing) * acquisition.dt + skew;
+                let bin = self.bins[ch].entry((time / bin_width).round() as i64).or_default();
+                bin.total += reading as f64;
+                bin.count += 1;
+                bin.clipped |= acquisition.clipped[ch].as_ref().is_some_and(|clipped| clipped[i]);
+            }
+        }
+        self.acquisitions += 1;
+        true
+    }
+
+    /// Position of the first crossing of the trigger level in the direction of the trigger, in
+    /// samples from the start of `acquisition`
+    fn crossing(&self, acquisition: &SampleBlock) -> Option<f64> {
+        let readings = acquisition.channels.get(self.trigger.source_channel)?.as_ref()?;
+        let level = self.trigger.trigger_level;
+        readings.windows(2).enumerate().find_map(|(i, pair)| {
+            let (before, after) = (pair[0] as f64, pair[1] as f64);
+            let crossed = match self.trigger.trigger_type {
+                TriggerType::RisingEdge => before < level && after >= level,
+                TriggerType::FallingEdge => before > level && after <= level,
+            };
+            crossed.then(|| i as f64 + (level - before) / (after - before))
+        })
+    }
+
+    pub fn acquisitions(&self) -> usize {
+        self.acquisitions
+    }
+
+    /// Returns the waveform interleaved from the acquisitions added so far
+    pub fn result(&self) -> EquivalentTimeWaveform {
+        let bin_width = self.dt / self.factor as f64;
+        let first = self.bins.iter().filter_map(|bins| bins.keys().next()).min().copied().unwrap_or(0);
+        let last = self.bins.iter().filter_map(|bins| bins.keys().next_back()).max().copied().unwrap_or(-1);
+        let len = (last - first + 1).max(0) as usize;
+
+        let mut waveform = SampleBlock {
+            start_time: first as f64 * bin_width,
+            dt: bin_width,
+            ..Default::default()
+        };
+        let (mut filled, mut total) = (0, 0);
+        for (ch, bins) in self.bins.iter().enumerate().filter(|(_, bins)| !bins.is_empty()) {
+            let mut readings = vec![f32::NAN; len];
+            let mut clipped = vec![false; len];
+            for (&index, bin) in bins {
+                let i = (index - first) as usize;
+                readings[i] = (bin.total / bin.count as f64) as f32;
+                clipped[i] = bin.clipped;
+            }
+            filled += bins.len();
+            total += len;
+            waveform.channels[ch] = Some(readings);
+            waveform.clipped[ch] = Some(clipped);
+        }
+
+        EquivalentTimeWaveform {
+            effective_rate_hz: match bin_width {
+                width if width > 0.0 => 1.0 / width,
+                _ => 0.0,
+            },
+            acquisitions: self.acquisitions,
+            phases: self.phases.iter().filter(|&&phase| phase).count() as u32,
+            coverage: match total {
+                0 => 0.0,
+                total => filled as f64 / total as f64,
+            },
+            waveform,
+        }
+    }
+}
+
+impl Nlab {
+    /// Samples a repetitive signal `factor` times faster than `sample_rate_hz`, by interleaving
+    /// `acquisitions` triggered sweeps of `number_of_samples`, see [`EquivalentTimeSampler`]
+    ///
+    /// The trigger must be enabled, and its channel must cross the trigger level within each
+    /// sweep. The nLab counts trigger delays in whole samples, so the delay of `trigger` only moves
+    /// the window, and the finer timing comes from where each sweep crosses the trigger level.
+    /// Acquisitions land at random phases, so several times `factor` acquisitions are needed to
+    /// fill every sample of the waveform, see [`EquivalentTimeWaveform::coverage`]. Signals
+    /// locked to the clock of the nLab never land between its samples, and are reported with
+    /// fewer [`EquivalentTimeWaveform::phases`] than `factor`.
+    pub fn equivalent_time(&self, sample_rate_hz: f64, number_of_samples: u32, factor: u32, acquisitions: usize, trigger: Trigger) -> Result<EquivalentTimeWaveform, RequestError> {
+        let capture = self.request_segments(sample_rate_hz, number_of_samples, acquisitions, trigger);
+        let mut sampler = EquivalentTimeSampler::new(trigger, factor);
+        for frame in capture.receiver.iter() {
+            sampler.add(&frame.samples);
+        }
+        if let Some(error) = capture.error() {
+            return Err(error);
+        }
+        let result = sampler.result();
+        if result.phases < sampler.factor {
+            warn!("Equivalent time acquisitions were triggered at {} of {} phases, leaving gaps in the waveform", result.phases, sampler.factor);
+        }
+        Ok(result)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn acquisitions_at_different_phases_are_interleaved() {
+        let trigger = Trigger {
+            is_enabled: true,
+            trigger_level: 0.5,
+            ..Default::default()
+        };
+        // A ramp of 1 V per sample, triggered a quarter sample apart
+        let acquisition = |phase: f32| SampleBlock {
+            dt: 1e-6,
+            channels: [Some((0..4).map(|i| i as f32 - 1.0 + phase).collect()), None, None, None],
+            ..Default::default()
+        };
+
+        let mut sampler = EquivalentTimeSampler::new(trigger, 4);
+        for phase in [0.0, 0.25, 0.5, 0.75].iter() {
+            assert!(sampler.add(&acquisition(*phase)));
+        }
+        assert!(!sampler.add(&acquisition(-5.0)));
+
+        let result = sampler.result();
+        assert_eq!(result.acquisitions, 4);
+        assert_eq!(result.phases, 4);
+        assert_eq!(result.coverage, 1.0);
+        assert!((result.effective_rate_hz - 4e6).abs() < 1e-3);
+        let readings = result.waveform.channels[0].as_ref().unwrap();
+        for (i, &reading) in readings.iter().enumerate() {
+            let time = result.waveform.start_time + i as f64 * result.waveform.dt;
+            assert!((reading as f64 - (0.5 + time / 1e-6)).abs() < 1e-6);
+        }
+
+        // Acquisitions that all trigger at the same phase only fill one bin per sample
+        let mut locked = EquivalentTimeSampler::new(trigger, 4);
+        for _ in 0..8 {
+            locked.add(&acquisition(0.25));
+        }
+        let result = locked.result();
+        assert_eq!(result.phases, 1);
+        assert!(result.coverage < 0.5);
+    }
+}
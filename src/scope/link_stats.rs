@@ -0,0 +1,186 @@
+/***************************************************************************************************
+ *
+ *  nLabs, LLC
+ *  https://getnlab.com
+ *  Copyright(c) 2020. All Rights Reserved
+ *
+ *  This file is part of the nLab API
+ *
+ **************************************************************************************************/
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::time::{Duration, Instant};
+
+use log::info;
+
+use super::Nlab;
+
+/// How often the communication thread logs a summary of the link statistics
+const LOG_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Upper bounds of the round-trip latency histogram buckets, slower responses land in a final bucket
+pub const LATENCY_BUCKET_BOUNDS: [Duration; 8] = [
+    Duration::from_micros(250),
+    Duration::from_micros(500),
+    Duration::from_millis(1),
+    Duration::from_millis(2),
+    Duration::from_millis(5),
+    Duration::from_millis(10),
+    Duration::from_millis(50),
+    Duration::from_millis(250),
+];
+
+/// Traffic on a single USB endpoint
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub struct EndpointStats {
+    pub packets: u64,
+    pub bytes: u64,
+}
+
+/// Distribution of the time between sending a request and receiving its response
+#[derive(Debug, Default, Copy, Clone, PartialEq)]
+pub struct LatencyHistogram {
+    /// Number of responses in each bucket of [`LATENCY_BUCKET_BOUNDS`], plus one for slower responses
+    pub counts: [u64; LATENCY_BUCKET_BOUNDS.len() + 1],
+    pub total: Duration,
+    pub max: Duration,
+}
+
+impl LatencyHistogram {
+    pub fn count(&self) -> u64 {
+        self.counts.iter().sum()
+    }
+
+    pub fn mean(&self) -> Option<Duration> {
+        match self.count() {
+            0 => None,
+            n => Some(self.total / n as u32),
+        }
+    }
+
+    fn record(&mut self, latency: Duration) {
+        let bucket = LATENCY_BUCKET_BOUNDS.iter()
+            .position(|&bound| latency <= bound)
+            .unwrap_or(LATENCY_BUCKET_BOUNDS.len());
+        self.counts[bucket] += 1;
+        self.total += latency;
+        self.max = self.max.max(latency);
+    }
+}
+
+/// Diagnostics about the USB link to an nLab, gathered by the communication thread
+#[derive(Debug, Clone)]
+pub struct LinkStats {
+    /// Packets and bytes sent on each OUT endpoint and received on each IN endpoint
+    pub endpoints: BTreeMap<u8, EndpointStats>,
+    /// Status packets received, each of which refreshes the power status and firmware version
+    pub status_updates: u64,
+    /// Round-trip latency of requests, by command
+    pub latency: BTreeMap<&'static str, LatencyHistogram>,
+    /// Failed USB reads and writes
+    pub usb_errors: u64,
+    /// Requests sent again because their response did not arrive in time
+    pub retries: u64,
+    /// Requests that were never answered, even after retrying
+    pub timeouts: u64,
+    /// Responses that did not match any request waiting on one
+    pub stale_responses: u64,
+    /// Complete samples received from all data sweeps
+    pub samples_received: u64,
+    started: Instant,
+}
+
+impl LinkStats {
+    pub(crate) fn new() -> Self {
+        LinkStats {
+            endpoints: BTreeMap::new(),
+            status_updates: 0,
+            latency: BTreeMap::new(),
+            usb_errors: 0,
+            retries: 0,
+            timeouts: 0,
+            stale_responses: 0,
+            samples_received: 0,
+            started: Instant::now(),
+        }
+    }
+
+    /// Time since the link was opened
+    pub fn elapsed(&self) -> Duration {
+        self.started.elapsed()
+    }
+
+    pub fn status_update_rate_hz(&self) -> f64 {
+        self.status_updates as f64 / self.elapsed().as_secs_f64()
+    }
+
+    /// Average rate of complete samples received since the link was opened
+    pub fn sample_throughput_hz(&self) -> f64 {
+        self.samples_received as f64 / self.elapsed().as_secs_f64()
+    }
+
+    pub(crate) fn record_packet(&mut self, endpoint: u8, bytes: usize) {
+        let endpoint = self.endpoints.entry(endpoint).or_default();
+        endpoint.packets += 1;
+        endpoint.bytes += bytes as u64;
+    }
+
+    pub(crate) fn record_latency(&mut self, command: &'static str, latency: Duration) {
+        self.latency.entry(command).or_default().record(latency);
+    }
+}
+
+impl fmt::Display for LinkStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:.1} status updates/s, {:.0} samples/s, {} USB errors, {} retries, {} timeouts, {} stale responses",
+               self.status_update_rate_hz(), self.sample_throughput_hz(), self.usb_errors,
+               self.retries, self.timeouts, self.stale_responses)?;
+        for (command, latency) in &self.latency {
+            if let Some(mean) = latency.mean() {
+                write!(f, ", {command} latency {mean:?} mean {:?} max", latency.max)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Logs the link statistics at a fixed interval, reporting rates over the most recent interval
+pub(crate) struct StatsLogger {
+    last_logged: Instant,
+    last_status_updates: u64,
+    last_samples_received: u64,
+}
+
+impl StatsLogger {
+    pub(crate) fn new() -> Self {
+        StatsLogger {
+            last_logged: Instant::now(),
+            last_status_updates: 0,
+            last_samples_received: 0,
+        }
+    }
+
+    pub(crate) fn poll(&mut self, stats: &LinkStats) {
+        let interval = self.last_logged.elapsed();
+        if interval < LOG_INTERVAL {
+            return;
+        }
+
+        let status_rate = (stats.status_updates - self.last_status_updates) as f64 / interval.as_secs_f64();
+        let sample_rate = (stats.samples_received - self.last_samples_received) as f64 / interval.as_secs_f64();
+        info!("nLab link over the last {:.0}s: {status_rate:.1} status updates/s, {sample_rate:.0} samples/s; since connecting: {stats}",
+              interval.as_secs_f64());
+
+        self.last_logged = Instant::now();
+        self.last_status_updates = stats.status_updates;
+        self.last_samples_received = stats.samples_received;
+    }
+}
+
+impl Nlab {
+    /// Returns diagnostics about the USB link to the nLab
+    pub fn link_stats(&self) -> LinkStats {
+        self.link_stats.read().unwrap().clone()
+    }
+}
@@ -0,0 +1,203 @@
+/***************************************************************************************************
+ *
+ *  nLabs, LLC
+ *  https://getnlab.com
+ *  Copyright(c) 2020. All Rights Reserved
+ *
+ *  This file is part of the nLab API
+ *
+ **************************************************************************************************/
+
+use std::fmt;
+use std::sync::Arc;
+
+use super::data_requests::{Sample, SampleBlock};
+use super::Nlab;
+
+/// Function deriving a value from the readings of a sample, or `None` where it has no value
+pub type MathFunction = Arc<dyn Fn(&Sample) -> Option<f64> + Send + Sync>;
+
+/// How a math channel is computed from the scope channels, which are numbered from 0
+#[derive(Clone)]
+pub enum MathOperation {
+    /// The first channel minus the second, for differential measurements
+    Difference(usize, usize),
+    Sum(usize, usize),
+    /// The product of two channels, such as the power from a voltage and a current
+    Product(usize, usize),
+    /// A channel times `gain` plus `offset`, such as a sensor reading in its own units
+    Scale { channel: usize, gain: f64, offset: f64 },
+    /// Running integral of a channel since the start of the sweep, in volt seconds
+    Integral(usize),
+    /// Rate of change of a channel since the previous sample, in volts per second
+    Derivative(usize),
+    /// Any function of the readings of a sample
+    Custom(MathFunction),
+}
+
+impl fmt::Debug for MathOperation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MathOperation::Difference(a, b) => f.debug_tuple("Difference").field(a).field(b).finish(),
+            MathOperation::Sum(a, b) => f.debug_tuple("Sum").field(a).field(b).finish(),
+            MathOperation::Product(a, b) => f.debug_tuple("Product").field(a).field(b).finish(),
+            MathOperation::Scale { channel, gain, offset } => f.debug_struct("Scale")
+                .field("channel", channel)
+                .field("gain", gain)
+                .field("offset", offset)
+                .finish(),
+            MathOperation::Integral(channel) => f.debug_tuple("Integral").field(channel).finish(),
+            MathOperation::Derivative(channel) => f.debug_tuple("Derivative").field(channel).finish(),
+            MathOperation::Custom(_) => f.write_str("Custom"),
+        }
+    }
+}
+
+/// Channel derived from the scope channels as a sweep is collated, see [`Nlab::add_math_channel`]
+#[derive(Debug, Clone)]
+pub struct MathChannel {
+    pub name: String,
+    pub operation: MathOperation,
+}
+
+impl MathChannel {
+    pub fn new(name: &str, operation: MathOperation) -> Self {
+        MathChannel { name: name.to_string(), operation }
+    }
+}
+
+/// What an integral or derivative carries from one sample to the next
+#[derive(Debug, Default, Copy, Clone)]
+struct MathState {
+    integral: f64,
+    /// Last valid reading of the input, and the time it was read
+    previous: Option<(f64, f64)>,
+}
+
+/// Computes the math channels of a sweep, block by block
+#[derive(Debug, Default, Clone)]
+pub(crate) struct MathChannels {
+    channels: Vec<MathChannel>,
+    states: Vec<MathState>,
+}
+
+impl MathChannels {
+    pub(crate) fn new(channels: &[MathChannel]) -> Self {
+        MathChannels {
+            channels: channels.to_vec(),
+            states: vec![MathState::default(); channels.len()],
+        }
+    }
+
+    /// Returns math channels computing the same values, for a new sweep
+    pub(crate) fn restart(&self) -> Self {
+        MathChannels::new(&self.channels)
+    }
+
+    /// Fills in the math channels of `block`, where a value that cannot be computed is NaN
+    pub(crate) fn compute(&mut self, block: &mut SampleBlock) {
+        if self.channels.is_empty() {
+            return;
+        }
+        let mut math = vec![Vec::with_capacity(block.len()); self.channels.len()];
+        for sample in block.samples() {
+            for ((channel, state), values) in self.channels.iter().zip(self.states.iter_mut()).zip(math.iter_mut()) {
+                values.push(Self::value(&channel.operation, state, &sample).map_or(f32::NAN, |value| value as f32));
+            }
+        }
+        block.math = math;
+    }
+
+    fn value(operation: &MathOperation, state: &mut MathState, sample: &Sample) -> Option<f64> {
+        let reading = |channel: usize| *sample.data.get(channel)?;
+        match *operation {
+            MathOperation::Difference(a, b) => Some(reading(a)? - reading(b)?),
+            MathOperation::Sum(a, b) => Some(reading(a)? + reading(b)?),
+            MathOperation::Product(a, b) => Some(reading(a)? * reading(b)?),
+            MathOperation::Scale { channel, gain, offset } => Some(reading(channel)? * gain + offset),
+            MathOperation::Integral(channel) => {
+                let (value, time) = (reading(channel)?, sample.channel_time(channel));
+                // Trapezoids between valid readings, bridging any lost in between
+                if let Some((previous, previous_time)) = state.previous {
+                    state.integral += (previous + value) / 2.0 * (time - previous_time);
+                }
+                state.previous = Some((value, time));
+                Some(state.integral)
+            }
+            MathOperation::Derivative(channel) => {
+                let (value, time) = (reading(channel)?, sample.channel_time(channel));
+                let previous = state.previous.replace((value, time));
+                previous.map(|(previous, previous_time)| (value - previous) / (time - previous_time))
+            }
+            MathOperation::Custom(ref function) => function(sample),
+        }
+    }
+}
+
+impl Nlab {
+    /// Adds a channel computed from the scope channels, returning its position in the `math`
+    /// readings of the samples of each sweep
+    ///
+    /// Math channels are computed on the host for sweeps in volts, after the acquisition mode
+    /// and any deskewing, and can be the source of a software trigger, see
+    /// [`TriggerSource::MathChannel`](crate::TriggerSource::MathChannel). Only applies to sweeps
+    /// requested after it is added.
+    pub fn add_math_channel(&mut self, channel: MathChannel) -> usize {
+        self.math_channels.push(channel);
+        self.math_channels.len() - 1
+    }
+
+    pub fn math_channels(&self) -> &[MathChannel] {
+        &self.math_channels
+    }
+
+    pub fn clear_math_channels(&mut self) {
+        self.math_channels.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn math_channels_are_computed_across_blocks() {
+        let operations = [
+            MathOperation::Difference(0, 1),
+            MathOperation::Product(0, 1),
+            MathOperation::Scale { channel: 1, gain: 2.0, offset: 1.0 },
+            MathOperation::Integral(0),
+            MathOperation::Derivative(0),
+            MathOperation::Custom(Arc::new(|sample: &Sample| Some(sample.data[0]?.max(sample.data[1]?)))),
+        ];
+        let channels: Vec<MathChannel> = operations.iter().map(|operation| MathChannel::new("math", operation.clone())).collect();
+        let mut math = MathChannels::new(&channels);
+
+        let block = |start_time: f64, a: [f32; 2], b: [f32; 2]| SampleBlock {
+            start_time,
+            dt: 0.5,
+            channels: [Some(a.to_vec()), Some(b.to_vec()), None, None],
+            ..Default::default()
+        };
+        let mut first = block(0.0, [1.0, 3.0], [2.0, 2.0]);
+        let mut second = block(1.0, [f32::NAN, 1.0], [2.0, 1.0]);
+        math.compute(&mut first);
+        math.compute(&mut second);
+
+        assert_eq!(first.math[0], vec![-1.0, 1.0]);
+        assert_eq!(first.math[1], vec![2.0, 6.0]);
+        assert_eq!(first.math[2], vec![5.0, 5.0]);
+        assert_eq!(first.math[3], vec![0.0, 1.0]);
+        // The lost reading is bridged by one trapezoid
+        assert_eq!(second.math[3][1], 3.0);
+        assert!(first.math[4][0].is_nan() && second.math[4][0].is_nan());
+        assert_eq!((first.math[4][1], second.math[4][1]), (4.0, -2.0));
+        assert_eq!(first.math[5], vec![2.0, 3.0]);
+        assert!(second.math[5][0].is_nan());
+
+        let mut sample = second.samples().nth(1).unwrap();
+        assert_eq!(sample.math[1], Some(1.0));
+        sample.clear();
+        assert!(sample.math.is_empty());
+    }
+}
@@ -8,10 +8,22 @@
  *
  **************************************************************************************************/
 
-use std::io;
+use std::collections::VecDeque;
+use std::error::Error;
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::time::{Duration, Instant};
+use std::{fmt, io, thread};
+use log::warn;
 #[cfg(feature = "python_support")]
 use pyo3::{pyclass, pymethods};
-use super::Nlab;
+use super::commands::Command;
+use super::{Nlab, RequestError};
+
+/// Number of status updates kept in the power usage history
+const POWER_HISTORY_LEN: usize = 4096;
+
+/// Longest the power supply may take to reach the requested state
+const POWER_SETTLE_TIMEOUT: Duration = Duration::from_secs(2);
 
 /// Information about the power supply status of nLab
 #[derive(Debug, Copy, Clone)]
@@ -62,6 +74,112 @@ impl From<u8> for PowerState {
     }
 }
 
+/// A change in the nLab power supply, sent to power subscribers
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub enum PowerEvent {
+    /// The power supply changed state
+    StateChanged { previous: PowerState, current: PowerState },
+    /// Power usage rose above the threshold of the subscription
+    UsageAboveThreshold { usage: f64, threshold: f64 },
+    /// Power usage fell back to or below the threshold of the subscription
+    UsageBelowThreshold { usage: f64, threshold: f64 },
+}
+
+/// Reasons the nLab power supply did not reach the requested state
+#[derive(Debug, Clone, PartialEq)]
+pub enum PowerError {
+    /// The power request was not carried out
+    Request(RequestError),
+    /// The supply shut down because its output is shorted
+    Shorted,
+    /// The supply shut down because too much current was drawn from it
+    Overcurrent,
+    /// The supply did not settle in the requested state, and was left in the given state
+    Stalled(PowerState),
+}
+
+impl fmt::Display for PowerError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PowerError::Request(error) => write!(f, "{error}"),
+            PowerError::Shorted => write!(f, "nLab power supply output is shorted"),
+            PowerError::Overcurrent => write!(f, "nLab power supply shut down from overcurrent"),
+            PowerError::Stalled(state) => write!(f, "nLab power supply stalled in state {state:?}"),
+        }
+    }
+}
+
+impl Error for PowerError {}
+
+impl From<RequestError> for PowerError {
+    fn from(error: RequestError) -> Self {
+        PowerError::Request(error)
+    }
+}
+
+/// Power supply status reported by the nLab at a point in time
+#[derive(Debug, Copy, Clone)]
+pub struct PowerRecord {
+    pub time: Instant,
+    pub status: PowerStatus,
+}
+
+struct PowerSubscriber {
+    sender: Sender<PowerEvent>,
+    usage_threshold: Option<f64>,
+    above_threshold: bool,
+}
+
+/// Turns the status updates from the nLab into power events and a usage history
+#[derive(Default)]
+pub(crate) struct PowerMonitor {
+    subscribers: Vec<PowerSubscriber>,
+    history: VecDeque<PowerRecord>,
+}
+
+impl PowerMonitor {
+    pub(crate) fn update(&mut self, status: PowerStatus) {
+        let previous = self.history.back().map_or(PowerState::Unknown, |record| record.status.state);
+        if status.state != previous && matches!(status.state, PowerState::Shorted | PowerState::Overcurrent) {
+            warn!("nLab power supply is {:?}, drawing {:.3} W", status.state, status.usage);
+        }
+
+        if self.history.len() == POWER_HISTORY_LEN {
+            self.history.pop_front();
+        }
+        self.history.push_back(PowerRecord { time: Instant::now(), status });
+
+        // Notify every subscriber, forgetting those that have hung up
+        self.subscribers.retain_mut(|subscriber| {
+            let mut events = Vec::new();
+            if status.state != previous {
+                events.push(PowerEvent::StateChanged { previous, current: status.state });
+            }
+            if let Some(threshold) = subscriber.usage_threshold {
+                let above_threshold = status.usage > threshold;
+                if above_threshold != subscriber.above_threshold {
+                    subscriber.above_threshold = above_threshold;
+                    events.push(match above_threshold {
+                        true => PowerEvent::UsageAboveThreshold { usage: status.usage, threshold },
+                        false => PowerEvent::UsageBelowThreshold { usage: status.usage, threshold },
+                    });
+                }
+            }
+            events.into_iter().all(|event| subscriber.sender.send(event).is_ok())
+        });
+    }
+
+    fn subscribe(&mut self, usage_threshold: Option<f64>) -> Receiver<PowerEvent> {
+        let (sender, receiver) = mpsc::channel();
+        self.subscribers.push(PowerSubscriber {
+            sender,
+            usage_threshold,
+            above_threshold: false,
+        });
+        receiver
+    }
+}
+
 impl Nlab {
     pub fn power_status(&self) -> Result<PowerStatus, io::Error> {
         if !self.is_connected() {
@@ -72,4 +190,98 @@ impl Nlab {
         }
         Ok(*self.power_status.read().unwrap())
     }
+
+    /// Returns a receiver of every change in power state from now on
+    ///
+    /// With a `usage_threshold` in watts, the receiver is also told each time the power usage
+    /// rises above or falls back below it. The receiver disconnects when the nLab is closed.
+    pub fn subscribe_power(&self, usage_threshold: Option<f64>) -> Receiver<PowerEvent> {
+        self.power_monitor.write().unwrap().subscribe(usage_threshold)
+    }
+
+    /// Turns the nLab power supply on or off, waiting for it to settle in the requested state
+    ///
+    /// nLab v2 firmware only switches the power supply when it is initialized, so the request is
+    /// sent as an initialization carrying the new power setting. Anything else the firmware
+    /// resets on initialization is reset too: the request is refused with
+    /// [`RequestError::Rejected`] while a sweep is in progress, and the analog and pulse outputs
+    /// should be set again once it succeeds.
+    ///
+    /// [`PowerError::Shorted`] and [`PowerError::Overcurrent`] are only returned when the supply
+    /// trips after the request is answered. A supply that was already tripped and never leaves
+    /// that state ends in [`PowerError::Stalled`].
+    pub fn set_power(&self, power_on: bool) -> Result<(), PowerError> {
+        // Subscribe before sending so that no state change is missed
+        let events = self.subscribe_power(None);
+
+        let (tx, rx) = mpsc::channel::<Result<(), RequestError>>();
+        self.command_tx.send(Command::SetPower(power_on, tx))?;
+        rx.recv().map_err(|_| RequestError::Disconnected)??;
+
+        // Changes from before the answer may predate the request, such as a short it is meant to
+        // recover from, so only the state they left behind is kept
+        events.try_iter().for_each(drop);
+        let target = if power_on { PowerState::PowerOn } else { PowerState::PowerOff };
+        let deadline = Instant::now() + POWER_SETTLE_TIMEOUT;
+        let mut state = self.power_status.read().unwrap().state;
+        while state != target {
+            match events.recv_timeout(deadline.saturating_duration_since(Instant::now())) {
+                Ok(PowerEvent::StateChanged { current: PowerState::Shorted, .. }) if power_on => return Err(PowerError::Shorted),
+                Ok(PowerEvent::StateChanged { current: PowerState::Overcurrent, .. }) if power_on => return Err(PowerError::Overcurrent),
+                Ok(PowerEvent::StateChanged { current, .. }) => state = current,
+                Ok(_) => {}
+                Err(RecvTimeoutError::Timeout) => return Err(PowerError::Stalled(state)),
+                Err(RecvTimeoutError::Disconnected) => return Err(RequestError::Disconnected.into()),
+            }
+        }
+        Ok(())
+    }
+
+    /// Turns the power supply off for `off_time`, then back on
+    ///
+    /// Use this to reset a circuit under test without closing the nLab.
+    pub fn power_cycle(&self, off_time: Duration) -> Result<(), PowerError> {
+        self.set_power(false)?;
+        thread::sleep(off_time);
+        self.set_power(true)
+    }
+
+    /// Returns the most recent power status updates, oldest first
+    pub fn power_history(&self) -> Vec<PowerRecord> {
+        self.power_monitor.read().unwrap().history.iter().copied().collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn status(state: PowerState, usage: f64) -> PowerStatus {
+        PowerStatus { state, usage }
+    }
+
+    #[test]
+    fn subscribers_see_state_changes_and_threshold_crossings() {
+        let mut monitor = PowerMonitor::default();
+        let events = monitor.subscribe(Some(1.0));
+
+        monitor.update(status(PowerState::PowerOn, 0.5));
+        monitor.update(status(PowerState::PowerOn, 0.6));
+        monitor.update(status(PowerState::PowerOn, 1.5));
+        monitor.update(status(PowerState::Shorted, 2.5));
+        monitor.update(status(PowerState::PowerOff, 0.0));
+
+        assert_eq!(events.try_iter().collect::<Vec<_>>(), vec![
+            PowerEvent::StateChanged { previous: PowerState::Unknown, current: PowerState::PowerOn },
+            PowerEvent::UsageAboveThreshold { usage: 1.5, threshold: 1.0 },
+            PowerEvent::StateChanged { previous: PowerState::PowerOn, current: PowerState::Shorted },
+            PowerEvent::StateChanged { previous: PowerState::Shorted, current: PowerState::PowerOff },
+            PowerEvent::UsageBelowThreshold { usage: 0.0, threshold: 1.0 },
+        ]);
+        assert_eq!(monitor.history.len(), 5);
+
+        drop(events);
+        monitor.update(status(PowerState::PowerOn, 0.5));
+        assert!(monitor.subscribers.is_empty());
+    }
 }
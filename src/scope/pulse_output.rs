@@ -13,7 +13,11 @@ use std::sync::{mpsc, RwLock};
 use std::sync::mpsc::Sender;
 use std::time::Duration;
 
-use crate::scope::commands::{Command, ScopeCommand};
+use log::warn;
+
+use crate::scope::capabilities::Capabilities;
+use crate::scope::commands::{Command, CommandSender, ScopeCommand};
+use crate::scope::RequestError;
 
 #[derive(Debug, Copy, Clone)]
 enum PulsePreScale {
@@ -64,18 +68,24 @@ impl PulseOutputState {
 #[derive(Debug)]
 pub struct PulseOutput {
     pub channel: usize,
-    command_tx: Sender<Command>,
+    command_tx: CommandSender,
     state: RwLock<PulseOutputState>,
 }
 
 
-impl PulseOutput {
-    pub(super) fn create(cmd_tx: Sender<Command>, px_channel: usize) -> Self {
-        let default_state = PulseOutputState {
+impl Default for PulseOutputState {
+    fn default() -> Self {
+        PulseOutputState {
             is_on: false,
             frequency: 1.0,
             duty: 0.5,
-        };
+        }
+    }
+}
+
+impl PulseOutput {
+    pub(super) fn create(cmd_tx: CommandSender, px_channel: usize) -> Self {
+        let default_state = PulseOutputState::default();
 
         let px = PulseOutput {
             command_tx: cmd_tx,
@@ -83,13 +93,13 @@ impl PulseOutput {
             state: RwLock::new(default_state),
         };
 
-        px.set(default_state);
+        px.set(default_state).ok();
         px
     }
 
-    fn set(&self, px_state: PulseOutputState) {
+    fn set(&self, px_state: PulseOutputState) -> Result<(), RequestError> {
         // Create a method for the backend to communicate back to us what we want
-        let (tx, rx) = mpsc::channel::<PulseOutputState>();
+        let (tx, rx) = mpsc::channel::<Result<PulseOutputState, RequestError>>();
 
         // Create the command to set an analog output
         let command = Command::SetPulseOutput(PxRequest {
@@ -100,14 +110,14 @@ impl PulseOutput {
         });
 
         // Send the command to the backend
-        if self.command_tx.send(command).is_ok() {
+        self.command_tx.send(command)?;
 
-            // Wait for the response from the backend
-            if let Ok(response_state) = rx.recv() {
-                // Write the response state
-                *self.state.write().unwrap() = response_state;
-            }
-        }
+        // Wait for the response from the backend, which reports requests that go unanswered
+        let response_state = rx.recv().map_err(|_| RequestError::Disconnected)??;
+
+        // Write the response state
+        *self.state.write().unwrap() = response_state;
+        Ok(())
     }
 
     pub fn is_on(&self) -> bool {
@@ -126,28 +136,56 @@ impl PulseOutput {
         self.state.read().unwrap().pulse_width()
     }
 
-    pub fn turn_on(&self) {
+    pub fn try_turn_on(&self) -> Result<(), RequestError> {
         let mut state = *self.state.read().unwrap();
         state.is_on = true;
         self.set(state)
     }
-    pub fn turn_off(&self) {
+    pub fn try_turn_off(&self) -> Result<(), RequestError> {
         let mut state = *self.state.read().unwrap();
         state.is_on = false;
         self.set(state)
     }
 
-    pub fn set_frequency(&self, desired_hz: f64) {
+    pub fn try_set_frequency(&self, desired_hz: f64) -> Result<(), RequestError> {
         let mut state = *self.state.read().unwrap();
         state.frequency = desired_hz;
         self.set(state)
     }
 
-    pub fn set_duty(&self, desired_percentage: f64) {
+    pub fn try_set_duty(&self, desired_percentage: f64) -> Result<(), RequestError> {
         let mut state = *self.state.read().unwrap();
         state.duty = desired_percentage;
         self.set(state)
     }
+
+    /// Same as [`PulseOutput::try_turn_on`], logging any failure instead of returning it
+    pub fn turn_on(&self) {
+        if let Err(error) = self.try_turn_on() {
+            warn!("Cannot set pulse output P{}: {error}", self.channel + 1);
+        }
+    }
+
+    /// Same as [`PulseOutput::try_turn_off`], logging any failure instead of returning it
+    pub fn turn_off(&self) {
+        if let Err(error) = self.try_turn_off() {
+            warn!("Cannot set pulse output P{}: {error}", self.channel + 1);
+        }
+    }
+
+    /// Same as [`PulseOutput::try_set_frequency`], logging any failure instead of returning it
+    pub fn set_frequency(&self, desired_hz: f64) {
+        if let Err(error) = self.try_set_frequency(desired_hz) {
+            warn!("Cannot set pulse output P{}: {error}", self.channel + 1);
+        }
+    }
+
+    /// Same as [`PulseOutput::try_set_duty`], logging any failure instead of returning it
+    pub fn set_duty(&self, desired_percentage: f64) {
+        if let Err(error) = self.try_set_duty(desired_percentage) {
+            warn!("Cannot set pulse output P{}: {error}", self.channel + 1);
+        }
+    }
 }
 
 fn get_registers(pulse_output: &PulseOutputState) -> Result<(u8, u32, u32), Box<dyn Error>> {
@@ -180,10 +218,25 @@ fn get_registers(pulse_output: &PulseOutputState) -> Result<(u8, u32, u32), Box<
 pub(crate) struct PxRequest {
     channel: usize,
     px_state: PulseOutputState,
-    sender: Sender<PulseOutputState>,
+    sender: Sender<Result<PulseOutputState, RequestError>>,
+}
+
+impl PxRequest {
+    /// A request that turns off a pulse output, for which nobody waits on the response
+    pub(crate) fn off(channel: usize) -> Self {
+        PxRequest {
+            channel,
+            px_state: PulseOutputState::default(),
+            sender: mpsc::channel().0,
+        }
+    }
 }
 
 impl ScopeCommand for PxRequest {
+    fn check_capabilities(&self, _capabilities: &Capabilities) -> Result<(), Box<dyn Error>> {
+        Ok(())
+    }
+
     fn fill_tx_buffer_legacy(&self, usb_buf: &mut [u8; 65]) -> Result<(), Box<dyn Error>> {
         usb_buf[1] = 0x01;
 
@@ -217,11 +270,15 @@ impl ScopeCommand for PxRequest {
     }
 
     fn handle_rx_legacy(&self, _usb_buf: &[u8; 64]) {
-        self.sender.send(self.px_state).unwrap();
+        self.sender.send(Ok(self.px_state)).ok();
     }
 
     fn handle_rx(&self, _usb_buf: &[u8; 64]) {
-        self.sender.send(self.px_state).unwrap();
+        self.sender.send(Ok(self.px_state)).ok();
+    }
+
+    fn handle_failure(&self, error: RequestError) {
+        self.sender.send(Err(error)).ok();
     }
 
     fn is_finished(&self) -> bool {
@@ -0,0 +1,184 @@
+/***************************************************************************************************
+ *
+ *  nLabs, LLC
+ *  https://getnlab.com
+ *  Copyright(c) 2020. All Rights Reserved
+ *
+ *  This file is part of the nLab API
+ *
+ **************************************************************************************************/
+
+use std::collections::VecDeque;
+use std::sync::{Arc, mpsc, RwLock};
+
+use super::data_requests::{DataSink, Sample, SampleBlock, StreamIntegrity, SweepHandle};
+use super::Nlab;
+use super::RequestError;
+
+/// Fixed-length record of the latest samples of a roll, oldest first
+#[derive(Debug)]
+pub(crate) struct RollBuffer {
+    capacity: usize,
+    samples_taken: u64,
+    dt: f64,
+    /// Time just after the latest sample, in seconds since the start of the roll
+    end_time: f64,
+    skews: [f64; Sample::num_channels() as usize],
+    channels: [Option<VecDeque<f32>>; Sample::num_channels() as usize],
+    clipped: [Option<VecDeque<bool>>; Sample::num_channels() as usize],
+    math: Vec<VecDeque<f32>>,
+}
+
+impl RollBuffer {
+    fn new(capacity: usize) -> Self {
+        RollBuffer {
+            capacity: capacity.max(1),
+            samples_taken: 0,
+            dt: 0.0,
+            end_time: 0.0,
+            skews: Default::default(),
+            channels: Default::default(),
+            clipped: Default::default(),
+            math: Vec::new(),
+        }
+    }
+
+    /// Adds the samples in `block`, dropping the oldest samples beyond the capacity
+    pub(crate) fn push(&mut self, block: &SampleBlock) {
+        if block.is_empty() {
+            return;
+        }
+        let capacity = self.capacity;
+        for (ch, readings) in block.channels.iter().enumerate() {
+            if let Some(readings) = readings {
+                let buffer = self.channels[ch].get_or_insert_with(|| VecDeque::with_capacity(capacity));
+                buffer.extend(readings);
+                buffer.drain(..buffer.len().saturating_sub(capacity));
+            }
+            if let Some(clipped) = &block.clipped[ch] {
+                let buffer = self.clipped[ch].get_or_insert_with(|| VecDeque::with_capacity(capacity));
+                buffer.extend(clipped);
+                buffer.drain(..buffer.len().saturating_sub(capacity));
+            }
+        }
+        self.math.resize_with(block.math.len(), || VecDeque::with_capacity(capacity));
+        for (buffer, values) in self.math.iter_mut().zip(&block.math) {
+            buffer.extend(values);
+            buffer.drain(..buffer.len().saturating_sub(capacity));
+        }
+        self.samples_taken += block.len() as u64;
+        self.dt = block.dt;
+        self.end_time = block.start_time + block.len() as f64 * block.dt;
+        self.skews = block.skews;
+    }
+
+    fn snapshot(&self) -> SampleBlock {
+        let len = (self.samples_taken as usize).min(self.capacity);
+        SampleBlock {
+            start_time: self.end_time - len as f64 * self.dt,
+            dt: self.dt,
+            channels: self.channels.clone().map(|buffer| buffer.map(Vec::from)),
+            clipped: self.clipped.clone().map(|buffer| buffer.map(Vec::from)),
+            skews: self.skews,
+            math: self.math.iter().map(|buffer| Vec::from(buffer.clone())).collect(),
+        }
+    }
+}
+
+/// Handle to an ongoing roll, which keeps the latest samples of a continuous sweep like a
+/// chart recorder, see [`Nlab::roll`]
+///
+/// Dropping the handle stops the roll.
+#[derive(Debug)]
+pub struct RollHandle {
+    buffer: Arc<RwLock<RollBuffer>>,
+    sweep: SweepHandle<()>,
+}
+
+impl RollHandle {
+    /// Returns the latest samples, oldest first, up to the capacity of the roll
+    pub fn snapshot(&self) -> SampleBlock {
+        self.buffer.read().unwrap().snapshot()
+    }
+
+    /// Number of samples taken since the roll started, including those that have rolled out
+    pub fn samples_taken(&self) -> u64 {
+        self.buffer.read().unwrap().samples_taken
+    }
+
+    /// Returns whether the nLab is still sampling
+    pub fn is_rolling(&self) -> bool {
+        self.sweep.remaining_samples() > 0
+    }
+
+    /// Rate at which samples are taken, see [`SweepHandle::sample_rate_hz`]
+    pub fn sample_rate_hz(&self) -> f64 {
+        self.sweep.sample_rate_hz()
+    }
+
+    /// Stops sampling, keeping the samples taken so far
+    pub fn stop(&self) {
+        self.sweep.stop();
+    }
+
+    /// Returns the reason the roll ended early, if the nLab could not fulfill it
+    pub fn error(&self) -> Option<RequestError> {
+        self.sweep.error()
+    }
+
+    /// Returns the number of packets lost or discarded so far in the roll
+    pub fn integrity(&self) -> StreamIntegrity {
+        self.sweep.integrity()
+    }
+}
+
+impl Drop for RollHandle {
+    fn drop(&mut self) {
+        self.sweep.stop();
+    }
+}
+
+impl Nlab {
+    /// Starts sampling continuously into a buffer of the latest `capacity` samples, which can
+    /// be read at any time with [`RollHandle::snapshot`]
+    ///
+    /// No released firmware implements the roll request, so the roll is a sweep streamed at
+    /// `sample_rate_hz` and kept on the host. It must be slow enough to stream, see
+    /// [`Nlab::max_samples_at`], and runs until stopped or until it has taken 2^23 samples,
+    /// over two hours at 1 khz. Samples are in volts, in the current acquisition mode.
+    pub fn roll(&self, sample_rate_hz: f64, capacity: usize) -> Result<RollHandle, RequestError> {
+        let buffer = Arc::new(RwLock::new(RollBuffer::new(capacity)));
+        let (_, receiver) = mpsc::channel::<()>();
+        let sweep = self.start_continuous_sweep(sample_rate_hz, DataSink::Roll(buffer.clone()), receiver)?;
+        Ok(RollHandle { buffer, sweep })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn the_latest_samples_are_kept() {
+        let block = |start: usize, len: usize| SampleBlock {
+            start_time: start as f64 * 0.5,
+            dt: 0.5,
+            channels: [None, Some((start..start + len).map(|i| i as f32).collect()), None, None],
+            clipped: [None, Some(vec![false; len]), None, None],
+            ..Default::default()
+        };
+
+        let mut buffer = RollBuffer::new(4);
+        buffer.push(&block(0, 3));
+        let snapshot = buffer.snapshot();
+        assert_eq!(snapshot.channels[1], Some(vec![0.0, 1.0, 2.0]));
+        assert_eq!(snapshot.start_time, 0.0);
+
+        buffer.push(&block(3, 3));
+        let snapshot = buffer.snapshot();
+        assert_eq!(snapshot.channels[1], Some(vec![2.0, 3.0, 4.0, 5.0]));
+        assert_eq!(snapshot.clipped[1].as_ref().map(Vec::len), Some(4));
+        assert_eq!(snapshot.start_time, 1.0);
+        assert_eq!(buffer.samples_taken, 6);
+    }
+}
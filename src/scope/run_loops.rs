@@ -1,2 +1,224 @@
+use std::sync::{Arc, RwLock};
+use crate::PowerStatus;
+use crate::scope::power::PowerMonitor;
+use crate::scope::safe_state::SafeState;
+use crate::scope::capabilities::Capabilities;
+use crate::scope::link_stats::LinkStats;
+
+mod engine;
 mod v1;
 mod v2;
+
+/// State the communication thread shares with the `Nlab` front end
+#[derive(Clone)]
+pub(crate) struct SharedState {
+    pub(crate) fw_version: Arc<RwLock<Option<u16>>>,
+    pub(crate) power_status: Arc<RwLock<PowerStatus>>,
+    pub(crate) power_monitor: Arc<RwLock<PowerMonitor>>,
+    pub(crate) capabilities: Arc<RwLock<Capabilities>>,
+    pub(crate) link_stats: Arc<RwLock<LinkStats>>,
+    pub(crate) safe_state: Arc<RwLock<SafeState>>,
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::{HashMap, VecDeque};
+    use std::io;
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+    use std::time::Duration;
+    use crate::{Nlab, PowerState, RequestError, SafeState};
+    use crate::scope::transport::{PACKET_SIZE, Transport};
+
+    /// Answers every request with a status response, except for commands told to stay silent
+    ///
+    /// Speaks the v2 protocol, or the legacy protocol of the nLab v1 when `legacy` is set.
+    #[derive(Default)]
+    struct Simulator {
+        responses: VecDeque<[u8; PACKET_SIZE]>,
+        requests: Vec<[u8; PACKET_SIZE]>,
+        unanswered: HashMap<u8, usize>,
+        power_on: bool,
+        shorted: bool,
+        legacy: bool,
+    }
+
+    #[derive(Clone, Default)]
+    struct SimulatedTransport(Arc<Mutex<Simulator>>);
+
+    impl Transport for SimulatedTransport {
+        fn write(&mut self, _endpoint: u8, packet: &[u8], _timeout: Duration) -> io::Result<()> {
+            let mut request = [0u8; PACKET_SIZE];
+            request[..packet.len()].copy_from_slice(packet);
+
+            let mut sim = self.0.lock().unwrap();
+            sim.requests.push(request);
+            // Legacy packets lead with the command, then the request ID
+            let (command, request_id) = match sim.legacy {
+                true => (request[0], request[1]),
+                false => (request[1], request[0]),
+            };
+            match (sim.legacy, command) {
+                (false, 1) => sim.power_on = request[2] != 0,
+                (true, 0x06) | (true, 0x07) => sim.power_on = command == 0x07,
+                _ => {}
+            }
+            if let Some(remaining) = sim.unanswered.get_mut(&command).filter(|n| **n > 0) {
+                *remaining -= 1;
+                return Ok(());
+            }
+
+            let mut response = [0u8; PACKET_SIZE];
+            if sim.legacy {
+                response[0] = 0x05 | (sim.power_on as u8) << 6;
+                response[2] = request_id;
+            } else {
+                response[0] = request_id;
+                response[1..3].copy_from_slice(&0x0206u16.to_le_bytes());
+                response[3] = if sim.shorted { 2 } else { sim.power_on as u8 };
+            }
+            sim.responses.push_back(response);
+            Ok(())
+        }
+
+        fn read(&mut self, endpoint: u8, _timeout: Duration) -> io::Result<Option<[u8; PACKET_SIZE]>> {
+            match endpoint {
+                0x81 => Ok(self.0.lock().unwrap().responses.pop_front()),
+                _ => Ok(None),
+            }
+        }
+
+        fn wait(&mut self, timeout: Duration) -> io::Result<()> {
+            if self.0.lock().unwrap().responses.is_empty() {
+                thread::sleep(timeout.min(Duration::from_millis(1)));
+            }
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn requests_are_answered_over_a_simulated_transport() {
+        let transport = SimulatedTransport::default();
+        let nlab = Nlab::with_transport(transport.clone(), true).unwrap();
+
+        assert_eq!(nlab.version().unwrap(), 0x0206);
+        assert_eq!(nlab.a1.try_turn_on(), Ok(()));
+
+        let stats = nlab.link_stats();
+        assert_eq!(stats.retries, 0);
+        assert_eq!(stats.stale_responses, 0);
+        assert_eq!(stats.latency["SetAnalogOutput"].count(), 3);
+    }
+
+    #[test]
+    fn lost_responses_are_retried_then_reported() {
+        let transport = SimulatedTransport::default();
+        let nlab = Nlab::with_transport(transport.clone(), true).unwrap();
+
+        // The pulse output request is answered on its second attempt
+        transport.0.lock().unwrap().unanswered.insert(3, 1);
+        assert_eq!(nlab.p1.try_turn_on(), Ok(()));
+        assert_eq!(nlab.link_stats().retries, 1);
+
+        // The analog output request is never answered
+        transport.0.lock().unwrap().unanswered.insert(2, usize::MAX);
+        assert_eq!(nlab.a1.try_turn_on(), Err(RequestError::Timeout));
+        let stats = nlab.link_stats();
+        assert_eq!(stats.retries, 3);
+        assert_eq!(stats.timeouts, 1);
+
+        // Every attempt at a request reuses its request ID
+        let requests = &transport.0.lock().unwrap().requests;
+        let attempts: Vec<_> = requests.iter().filter(|rq| rq[1] == 2).rev().take(3).collect();
+        assert!(attempts.iter().all(|rq| rq[0] == attempts[0][0]));
+    }
+
+    #[test]
+    fn lost_legacy_responses_are_retried_then_reported() {
+        let transport = SimulatedTransport::default();
+        transport.0.lock().unwrap().legacy = true;
+        let nlab = Nlab::start(transport.clone(), true, true, None).unwrap();
+        assert_eq!(nlab.version().unwrap(), 0x05);
+
+        // The analog output request is never answered
+        transport.0.lock().unwrap().unanswered.insert(0x02, usize::MAX);
+        assert_eq!(nlab.a1.try_turn_on(), Err(RequestError::Timeout));
+        let stats = nlab.link_stats();
+        assert_eq!((stats.retries, stats.timeouts), (2, 1));
+
+        // The loop is idle again, and carries on with the next request
+        transport.0.lock().unwrap().unanswered.clear();
+        assert_eq!(nlab.p1.try_turn_on(), Ok(()));
+    }
+
+    #[test]
+    fn unanswered_data_requests_are_not_resent() {
+        let transport = SimulatedTransport::default();
+        let nlab = Nlab::with_transport(transport.clone(), true).unwrap();
+
+        transport.0.lock().unwrap().unanswered.insert(4, usize::MAX);
+        let sweep = nlab.request(1000.0, 10, None);
+        assert_eq!(sweep.receiver.iter().count(), 0);
+        assert_eq!(sweep.error(), Some(RequestError::Timeout));
+        assert_eq!(nlab.link_stats().retries, 0);
+
+        let requests = &transport.0.lock().unwrap().requests;
+        assert_eq!(requests.iter().filter(|rq| rq[1] == 4).count(), 1);
+    }
+
+    #[test]
+    fn power_can_be_switched_after_open() {
+        let transport = SimulatedTransport::default();
+        let nlab = Nlab::with_transport(transport.clone(), true).unwrap();
+        assert_eq!(nlab.power_status().unwrap().state, PowerState::PowerOn);
+
+        assert_eq!(nlab.set_power(false), Ok(()));
+        assert_eq!(nlab.power_status().unwrap().state, PowerState::PowerOff);
+        assert_eq!(nlab.power_cycle(Duration::from_millis(10)), Ok(()));
+        assert_eq!(nlab.power_status().unwrap().state, PowerState::PowerOn);
+
+        // The v2 firmware is switched by initializing it with the new power setting
+        let requests = &transport.0.lock().unwrap().requests;
+        let power_settings: Vec<u8> = requests.iter().filter(|rq| rq[1] == 1).map(|rq| rq[2]).collect();
+        assert_eq!(power_settings, vec![1, 0, 0, 1]);
+    }
+
+    #[test]
+    fn power_can_be_restored_after_a_short() {
+        let transport = SimulatedTransport::default();
+        let nlab = Nlab::with_transport(transport.clone(), true).unwrap();
+        transport.0.lock().unwrap().shorted = true;
+        nlab.a1.try_turn_on().unwrap();
+        assert_eq!(nlab.power_status().unwrap().state, PowerState::Shorted);
+
+        // The supply still reports the short when it answers, and comes back on shortly after
+        let simulator = transport.0.clone();
+        let recovery = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(50));
+            let mut sim = simulator.lock().unwrap();
+            sim.shorted = false;
+            let mut status = [0u8; PACKET_SIZE];
+            status[1..3].copy_from_slice(&0x0206u16.to_le_bytes());
+            status[3] = sim.power_on as u8;
+            sim.responses.push_back(status);
+        });
+        assert_eq!(nlab.set_power(true), Ok(()));
+        recovery.join().unwrap();
+        assert_eq!(nlab.power_status().unwrap().state, PowerState::PowerOn);
+    }
+
+    #[test]
+    fn safe_state_is_applied_when_the_nlab_is_dropped() {
+        let transport = SimulatedTransport::default();
+        let nlab = Nlab::with_transport(transport.clone(), true).unwrap();
+        nlab.a1.try_turn_on().unwrap();
+        nlab.set_safe_state(SafeState::PowerOff);
+        let sent_before_drop = transport.0.lock().unwrap().requests.len();
+        drop(nlab);
+
+        let sim = transport.0.lock().unwrap();
+        let commands: Vec<u8> = sim.requests[sent_before_drop..].iter().map(|rq| rq[1]).collect();
+        assert_eq!(commands, vec![2, 2, 3, 3, 1]);
+        assert!(!sim.power_on);
+    }
+}
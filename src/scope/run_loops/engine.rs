@@ -0,0 +1,321 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::io;
+use std::sync::mpsc;
+use std::time::{Duration, Instant};
+use log::{debug, error, warn};
+use crate::scope::analog_output::AxRequest;
+use crate::scope::commands::{Command, CommandSender};
+use crate::scope::data_requests::DataRequest;
+use crate::scope::link_stats::StatsLogger;
+use crate::scope::power::PowerState;
+use crate::scope::pulse_output::PxRequest;
+use crate::scope::RequestError;
+use crate::scope::safe_state::SafeState;
+use crate::scope::transport::{PACKET_SIZE, Transport};
+use super::SharedState;
+
+/// Longest a single packet write may take before the link is considered broken
+const WRITE_TIMEOUT: Duration = Duration::from_millis(100);
+/// Longest to wait on the answer to each request that puts the nLab in its safe state
+const SAFE_STATE_TIMEOUT: Duration = Duration::from_millis(100);
+
+/// A request that has been written to the nLab and is waiting on its response
+struct PendingRequest {
+    command: Command,
+    packet: Vec<u8>,
+    sent_at: Instant,
+    attempts: u8,
+}
+
+/// Request bookkeeping shared by the communication loops of every protocol version
+///
+/// The engine assigns request IDs, matches responses to the requests waiting on them, tracks
+/// the data sweep in progress, and keeps the link statistics. The loops built on it only deal
+/// with the packet layout and pacing of their protocol. Anything still waiting on the nLab
+/// when the engine is dropped is reported as disconnected.
+pub(super) struct Engine<T: Transport> {
+    transport: T,
+    pub(super) shared: SharedState,
+    pending_requests: HashMap<u8, PendingRequest>,
+    active_data_request: Option<(u8, Command)>,
+    request_id: u8,
+    stats_logger: StatsLogger,
+}
+
+impl<T: Transport> Engine<T> {
+    pub(super) fn new(transport: T, shared: SharedState) -> Self {
+        Engine {
+            transport,
+            shared,
+            pending_requests: HashMap::new(),
+            active_data_request: None,
+            request_id: 0,
+            stats_logger: StatsLogger::new(),
+        }
+    }
+
+    /// Number of requests that have been sent and not yet answered
+    pub(super) fn pending_requests(&self) -> usize {
+        self.pending_requests.len()
+    }
+
+    /// True when nothing is waiting on the nLab and no sweep is in progress
+    pub(super) fn is_idle(&self) -> bool {
+        self.pending_requests.is_empty() && self.active_data_request.is_none()
+    }
+
+    /// Sends a stop command if the front end has asked to end the sweep in progress
+    pub(super) fn stop_if_requested(&self, command_tx: &CommandSender) {
+        if let Some((id, Command::RequestData(rq))) = &self.active_data_request {
+            if let Ok(()) = rq.stop_recv.lock().unwrap().try_recv() {
+                command_tx.send(Command::StopData).ok();
+                debug!("Sent a stop command to request {id}");
+            }
+        }
+    }
+
+    /// Checks a command against the capabilities of the firmware, refusing it if it cannot be carried out
+    pub(super) fn accept(&self, command: &Command) -> bool {
+        match command.check_capabilities(&self.shared.capabilities.read().unwrap()) {
+            Ok(()) => true,
+            Err(error) => {
+                self.reject(command, error);
+                false
+            }
+        }
+    }
+
+    /// Reports to the front end that a command could not be turned into a request
+    pub(super) fn reject(&self, command: &Command, error: Box<dyn Error>) {
+        error!("{error}");
+        command.handle_failure(RequestError::Rejected(error.to_string()));
+    }
+
+    /// Returns a request ID that is not zero and is not already waiting on a response
+    pub(super) fn next_request_id(&mut self) -> u8 {
+        loop {
+            self.request_id = self.request_id.wrapping_add(1);
+            let in_use = self.pending_requests.contains_key(&self.request_id)
+                || matches!(&self.active_data_request, Some((id, _)) if *id == self.request_id);
+            if self.request_id != 0 && !in_use {
+                return self.request_id;
+            }
+        }
+    }
+
+    /// Writes a packet to the nLab, recording it in the link statistics
+    pub(super) fn write(&mut self, endpoint: u8, packet: &[u8]) -> io::Result<()> {
+        if let Err(error) = self.transport.write(endpoint, packet, WRITE_TIMEOUT) {
+            error!("USB write error: {error}");
+            self.shared.link_stats.write().unwrap().usb_errors += 1;
+            return Err(error);
+        }
+        self.shared.link_stats.write().unwrap().record_packet(endpoint, packet.len());
+        Ok(())
+    }
+
+    /// Sends a request and waits on its response, failing the command if it cannot be sent
+    pub(super) fn submit(&mut self, request_id: u8, command: Command, packet: &[u8]) -> io::Result<()> {
+        if let Err(error) = self.write(0x01, packet) {
+            command.handle_failure(RequestError::Disconnected);
+            return Err(error);
+        }
+        debug!("Sent request {}: command: {}", request_id, command.id_byte());
+
+        self.pending_requests.insert(request_id, PendingRequest {
+            command,
+            packet: packet.to_vec(),
+            sent_at: Instant::now(),
+            attempts: 1,
+        });
+        Ok(())
+    }
+
+    /// Resends any request whose response has gone missing, and gives up on those that have
+    /// been sent `max_attempts` times, or that cannot safely be sent again
+    pub(super) fn retry_expired(&mut self, max_attempts: u8) -> io::Result<()> {
+        let now = Instant::now();
+        let expired: Vec<u8> = self.pending_requests.iter()
+            .filter(|(_, rq)| now.duration_since(rq.sent_at) > rq.command.timeout())
+            .map(|(&id, _)| id)
+            .collect();
+
+        for id in expired {
+            let rq = &self.pending_requests[&id];
+            if rq.attempts >= max_attempts || !rq.command.is_idempotent() {
+                warn!("Request {id} was not answered after {} attempts", rq.attempts);
+                self.shared.link_stats.write().unwrap().timeouts += 1;
+                let rq = self.pending_requests.remove(&id).unwrap();
+                rq.command.handle_failure(RequestError::Timeout);
+                continue;
+            }
+
+            debug!("Resending request {id}, attempt {}", rq.attempts + 1);
+            let packet = rq.packet.clone();
+            self.write(0x01, &packet)?;
+            self.shared.link_stats.write().unwrap().retries += 1;
+
+            let rq = self.pending_requests.get_mut(&id).unwrap();
+            rq.sent_at = now;
+            rq.attempts += 1;
+        }
+        Ok(())
+    }
+
+    /// Time until the next pending request times out, at most `limit`
+    pub(super) fn next_timeout(&self, limit: Duration) -> Duration {
+        let now = Instant::now();
+        self.pending_requests.values()
+            .map(|rq| (rq.sent_at + rq.command.timeout()).saturating_duration_since(now))
+            .fold(limit, Duration::min)
+    }
+
+    /// Sleeps until a packet arrives, a command is sent, or `timeout` expires
+    pub(super) fn wait(&mut self, timeout: Duration) -> io::Result<()> {
+        self.transport.wait(timeout).map_err(|error| {
+            error!("USB event error: {error}");
+            self.shared.link_stats.write().unwrap().usb_errors += 1;
+            error
+        })
+    }
+
+    /// Reads the next packet from an IN endpoint, recording it in the link statistics
+    pub(super) fn read(&mut self, endpoint: u8, timeout: Duration) -> io::Result<Option<[u8; PACKET_SIZE]>> {
+        match self.transport.read(endpoint, timeout) {
+            Ok(Some(packet)) => {
+                self.shared.link_stats.write().unwrap().record_packet(endpoint, packet.len());
+                Ok(Some(packet))
+            }
+            Ok(None) => Ok(None),
+            Err(error) => {
+                error!("USB read error: {error}");
+                self.shared.link_stats.write().unwrap().usb_errors += 1;
+                Err(error)
+            }
+        }
+    }
+
+    /// Records the firmware version and power status carried by every status response
+    pub(super) fn update_status(&self, fw_version: u16, power_state: PowerState, power_usage: f64) {
+        *self.shared.fw_version.write().unwrap() = Some(fw_version);
+        let status = {
+            let mut power_status = self.shared.power_status.write().unwrap();
+            power_status.state = power_state;
+            power_status.usage = power_usage;
+            *power_status
+        };
+        self.shared.power_monitor.write().unwrap().update(status);
+        self.shared.link_stats.write().unwrap().status_updates += 1;
+    }
+
+    /// Passes a response to the request it answers
+    ///
+    /// The first response to a data request makes it the active sweep, which receives every
+    /// later response carrying its request ID until it finishes.
+    pub(super) fn dispatch(&mut self, request_id: u8, handle: impl FnOnce(&Command, &SharedState)) {
+        if let Some(PendingRequest { command, sent_at, .. }) = self.pending_requests.remove(&request_id) {
+            self.shared.link_stats.write().unwrap().record_latency(command.name(), sent_at.elapsed());
+
+            // Handle the incoming usb packet
+            handle(&command, &self.shared);
+
+            // If the command has finished its work
+            if command.is_finished() {
+                debug!("Finished request ID: {request_id}");
+                if let Command::StopData = command {
+                    if let Some((_, Command::RequestData(rq))) = self.active_data_request.take() {
+                        rq.end();
+                    }
+                }
+            } else if let Command::RequestData(_) = command {
+                debug!("Setting Active Data Request: {request_id}");
+                self.active_data_request = Some((request_id, command));
+            } else {
+                debug!("Received request ID: {request_id}");
+            }
+        } else if matches!(&self.active_data_request, Some((id, _)) if *id == request_id) {
+            let (_, command) = self.active_data_request.as_ref().unwrap();
+            handle(command, &self.shared);
+            if command.is_finished() {
+                debug!("Finished request ID: {request_id}");
+                self.active_data_request = None;
+            }
+        } else {
+            error!("Received response for request {request_id}, but cannot find a record of that request");
+            self.shared.link_stats.write().unwrap().stale_responses += 1;
+        }
+    }
+
+    /// Returns the ID and request of the sweep in progress
+    pub(super) fn active_data_request(&self) -> Option<(u8, &DataRequest)> {
+        match &self.active_data_request {
+            Some((id, Command::RequestData(rq))) => Some((*id, rq.as_ref())),
+            _ => None,
+        }
+    }
+
+    /// Ends the sweep in progress once it has received all of its samples
+    pub(super) fn finish_data_request(&mut self) {
+        if let Some((id, _)) = self.active_data_request.take() {
+            debug!("Finished request ID: {id}");
+        }
+    }
+
+    /// Puts the nLab in the safe state chosen by the front end, if it can still be reached
+    ///
+    /// `encode` lays out the request packet for a command in the protocol of the loop.
+    pub(super) fn apply_safe_state(&mut self, encode: impl Fn(&mut Command, u8) -> Result<Vec<u8>, Box<dyn Error>>) {
+        let safe_state = *self.shared.safe_state.read().unwrap();
+        let mut commands = match safe_state {
+            SafeState::LeaveAsIs => return,
+            SafeState::OutputsOff | SafeState::PowerOff => vec![
+                Command::SetAnalogOutput(AxRequest::off(0)),
+                Command::SetAnalogOutput(AxRequest::off(1)),
+                Command::SetPulseOutput(PxRequest::off(0)),
+                Command::SetPulseOutput(PxRequest::off(1)),
+            ],
+        };
+        // The connection is ending, so initializing the nLab again with its power supply off
+        // loses nothing, and works on every firmware
+        if safe_state == SafeState::PowerOff {
+            commands.push(Command::Initialize(false, mpsc::channel().0));
+        }
+
+        debug!("Applying safe state: {safe_state:?}");
+        for mut command in commands {
+            let request_id = self.next_request_id();
+            let packet = match encode(&mut command, request_id) {
+                Ok(packet) => packet,
+                Err(error) => {
+                    error!("Cannot apply safe state: {error}");
+                    continue;
+                }
+            };
+            if self.write(0x01, &packet).is_err() {
+                return;
+            }
+            // Collect the answer, so the nLab is not left with responses nobody reads
+            if self.read(0x81, SAFE_STATE_TIMEOUT).is_err() {
+                return;
+            }
+        }
+    }
+
+    /// Logs the link statistics if the logging interval has passed
+    pub(super) fn poll_stats(&mut self) {
+        self.stats_logger.poll(&self.shared.link_stats.read().unwrap());
+    }
+}
+
+impl<T: Transport> Drop for Engine<T> {
+    fn drop(&mut self) {
+        // Anything still waiting on the nLab will never be answered
+        for (_, rq) in self.pending_requests.drain() {
+            rq.command.handle_failure(RequestError::Disconnected);
+        }
+        if let Some((_, command)) = self.active_data_request.take() {
+            command.handle_failure(RequestError::Disconnected);
+        }
+    }
+}
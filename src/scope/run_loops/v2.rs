@@ -1,175 +1,163 @@
-use std::sync::{Arc, RwLock};
-use std::sync::mpsc::{Receiver, Sender};
+use std::error::Error;
+use std::sync::mpsc::Receiver;
 use std::time::Duration;
-use log::{error, trace, debug};
-use rusb::DeviceHandle;
-use crate::PowerStatus;
-use crate::scope::commands::{Command, ScopeCommand};
+use log::{trace, debug};
+use crate::scope::capabilities::Capabilities;
+use crate::scope::commands::{Command, CommandSender, ScopeCommand};
 use crate::scope::StatusResponse;
+use crate::scope::transport::Transport;
+use super::engine::Engine;
+use super::SharedState;
+
+/// Number of requests that may be waiting on a response from the nLab at once
+const MAX_OUTSTANDING_REQUESTS: usize = 8;
+/// Number of times a request is sent before it is reported as unanswered
+const MAX_REQUEST_ATTEMPTS: u8 = 3;
+
+/// Longest the loop sleeps when the nLab has nothing to say, bounding how long a stopped sweep waits
+const IDLE_WAIT: Duration = Duration::from_millis(50);
+
+/// Lays out the request packet for a command
+fn encode(command: &Command, request_id: u8) -> Result<[u8; 64], Box<dyn Error>> {
+    let mut packet = [0u8; 64];
+    packet[0] = request_id;
+    packet[1] = command.id_byte();
+
+    // Fill the outgoing buffer with whatever we need
+    match command {
+        Command::Quit => {}
+        Command::Initialize(power_on, _) | Command::SetPower(power_on, _) => {
+            packet[2] = *power_on as u8;
+        }
+        Command::SetAnalogOutput(cmd) => { cmd.fill_tx_buffer(&mut packet)? }
+        Command::SetPulseOutput(cmd) => { cmd.fill_tx_buffer(&mut packet)? }
+        Command::RequestData(cmd) => { cmd.fill_tx_buffer(&mut packet)? }
+        Command::StopData => {}
+    };
+    Ok(packet)
+}
 
 impl crate::Nlab {
-    pub(crate) fn run_v2(
-        usb_device: DeviceHandle<rusb::GlobalContext>,
-        command_tx: Sender<Command>,
+    pub(crate) fn run_v2<T: Transport>(
+        transport: T,
+        command_tx: CommandSender,
         command_rx: Receiver<Command>,
-        fw_version: Arc<RwLock<Option<u16>>>,
-        power_status: Arc<RwLock<PowerStatus>>,
+        shared: SharedState,
     ) {
-        let mut active_comms_request: Option<(u8, Command)> = None;
-        let mut active_data_request: Option<(u8, Command)> = None;
-        let mut incoming_usb_buffer: [u8; 64] = [0u8; 64];
-        let mut outgoing_usb_buffer: [u8; 64] = [0u8; 64];
-        let mut incoming_channel_buffers: [[u8; 64]; 4] = [[0u8; 64]; 4];
-        let mut request_id: u8 = 0;
+        let mut engine = Engine::new(transport, shared);
 
         'communication: loop {
             // Check first to see if we have a cancelled active request
-            if let Some((id, Command::RequestData(rq))) = &active_data_request {
-                // we get the active request
-                if let Ok(()) = rq.stop_recv.try_recv() {
-                    // We have received a stop signal
-                    command_tx.send(Command::StopData).unwrap();
-                    debug!("Sent a stop command to request {id}");
-                }
-            }
+            engine.stop_if_requested(&command_tx);
 
-            if active_comms_request.is_none() {
-                if let Ok(command) = command_rx.try_recv() {
-                    // If we have a command from the front-end, assign a new requestID
-                    // and send the request out to the usb control line
+            // Send as many commands from the front-end as we have room for, each with its own
+            // requestID so that responses can arrive in any order
+            while engine.pending_requests() < MAX_OUTSTANDING_REQUESTS {
+                let command = match command_rx.try_recv() {
+                    Ok(command) => command,
+                    Err(_) => break,
+                };
 
-                    outgoing_usb_buffer.fill(0);
-                    request_id = request_id.wrapping_add(1);
-                    if request_id == 0 {
-                        request_id += 1
-                    }
+                if let Command::Quit = command {
+                    break 'communication;
+                }
 
-                    outgoing_usb_buffer[0] = request_id;
-                    outgoing_usb_buffer[1] = command.id_byte();
-                    debug!("Sent request {}: command: {}", request_id, command.id_byte());
+                // Refuse any command the firmware cannot carry out
+                if !engine.accept(&command) {
+                    continue;
+                }
 
-                    // Fill the outgoing buffer with whatever we need
-                    match &command {
-                        Command::Quit => { break 'communication; }
-                        Command::Initialize(power_on, _) => {
-                            outgoing_usb_buffer[2] = *power_on as u8;
-                            active_comms_request = Some((request_id, command));
-                        }
-                        Command::SetAnalogOutput(cmd) => {
-                            cmd.fill_tx_buffer(&mut outgoing_usb_buffer)
-                                .expect("Invalid parameters given to AxRequest");
-                            active_comms_request = Some((request_id, command));
-                        }
-                        Command::SetPulseOutput(cmd) => {
-                            cmd.fill_tx_buffer(&mut outgoing_usb_buffer)
-                                .expect("Invalid parameters given to PxRequest");
-                            active_comms_request = Some((request_id, command));
-                        }
-                        Command::RequestData(cmd) => {
-                            cmd.fill_tx_buffer(&mut outgoing_usb_buffer)
-                                .expect("Invalid parameters given to DataRequest");
-                            active_comms_request = Some((request_id, command));
-                        }
-                        Command::StopData => {
-                            active_comms_request = Some((request_id, command));
-                        }
-                    };
+                // Switching the power supply initializes the firmware again, which would cut a
+                // sweep short
+                if let (Command::SetPower(..), Some(_)) = (&command, engine.active_data_request()) {
+                    engine.reject(&command, "Cannot switch the nLab power supply during a sweep".into());
+                    continue;
+                }
 
-                    if let Err(error) = usb_device.write_bulk(0x01,
-                                                              &outgoing_usb_buffer,
-                                                              Duration::from_millis(100))
-                    {
-                        error!("USB write error: {error:?}");
-                        break 'communication;
+                let request_id = engine.next_request_id();
+                let packet = match encode(&command, request_id) {
+                    Ok(packet) => packet,
+                    Err(error) => {
+                        engine.reject(&command, error);
+                        continue;
                     }
+                };
+
+                if engine.submit(request_id, command, &packet).is_err() {
+                    break 'communication;
                 }
             }
 
-            match usb_device.read_bulk(0x81,
-                                       &mut incoming_usb_buffer,
-                                       Duration::from_millis(1))
-            {
-                Err(rusb::Error::Timeout) => {}
-                Ok(_) => {
-                    let response = StatusResponse::new(&incoming_usb_buffer);
-
-                    *fw_version.write().unwrap() = Some(response.fw_version);
-                    power_status.write().unwrap().state = response.power_state;
-                    power_status.write().unwrap().usage = response.power_usage as f64 / 1000.0 * 5.0;
-
-                    if response.request_id == 0 {
-                        trace!("Received a status update from nLab");
-                    } else if let Some((id, command)) = &active_comms_request {
-                        // If we have an active request with this ID
-                        if *id == response.request_id {
-
-                            // Handle the incoming usb packet
-                            command.handle_rx(&incoming_usb_buffer);
-
-                            // If the command has finished it's work
-                            if command.is_finished() {
-                                debug!("Finished request ID: {request_id}");
-                                if let Command::StopData = command {
-                                    active_data_request = None;
-                                }
-                            } else {
-                                debug!("Received request ID: {request_id}");
-                            }
-
-                            if let Command::RequestData(_) = command {
-                                debug!("Setting Active Data Request: {request_id}");
-                                active_data_request = active_comms_request.take();
-                            } else {
-                                active_comms_request = None;
-                            }
-                        }
-                    } else {
-                        error!("Received response for request {}, but cannot find a record of that request", response.request_id);
-                    }
-                }
-                Err(error) => {
-                    error!("USB read error: {error:?}");
-                    break 'communication;
+            // Resend any request whose response has gone missing
+            if engine.retry_expired(MAX_REQUEST_ATTEMPTS).is_err() {
+                break 'communication;
+            }
+
+            // Sleep until a packet arrives, a command is sent, or the next request times out
+            let wait = engine.next_timeout(IDLE_WAIT);
+            if engine.wait(wait).is_err() {
+                break 'communication;
+            }
+
+            loop {
+                let incoming_usb_buffer = match engine.read(0x81, Duration::ZERO) {
+                    Ok(Some(packet)) => packet,
+                    Ok(None) => break,
+                    Err(_) => break 'communication,
+                };
+                let response = StatusResponse::new(&incoming_usb_buffer);
+                engine.update_status(response.fw_version, response.power_state,
+                                     response.power_usage as f64 / 1000.0 * 5.0);
+
+                if response.request_id == 0 {
+                    trace!("Received a status update from nLab");
+                    continue;
                 }
+
+                engine.dispatch(response.request_id, |command, shared| {
+                    // Record the capabilities reported in response to initialization
+                    if let Command::Initialize(..) = command {
+                        *shared.capabilities.write().unwrap() = Capabilities::from_init_response(&incoming_usb_buffer);
+                    }
+                    command.handle_rx(&incoming_usb_buffer);
+                });
             }
 
             let mut received_ch_data = false;
 
-            if let Some((request_id, Command::RequestData(data_request))) = &active_data_request {
-                for (ch, &ep) in [0x82u8, 0x83u8, 0x84u8, 0x85u8].iter().enumerate() {
-                    let buf = &mut incoming_channel_buffers[ch];
-                    if data_request.channels[ch].is_on {
-                        match usb_device.read_bulk(ep, buf, Duration::from_millis(1))
-                        {
-                            Err(rusb::Error::Timeout) => {}
-                            Ok(_) => {
-                                let received_request_id = buf[0];
-                                debug!("Received data for request {received_request_id}, active request {request_id}");
-                                if received_request_id == *request_id {
-                                    data_request.handle_incoming_data(buf, ch);
-                                    received_ch_data = true;
-                                }
-                            }
-                            Err(error) => {
-                                error!("USB read error: {error:?}");
-                                break 'communication;
-                            }
+            for (ch, &ep) in [0x82u8, 0x83u8, 0x84u8, 0x85u8].iter().enumerate() {
+                loop {
+                    let buf = match engine.read(ep, Duration::ZERO) {
+                        Ok(Some(packet)) => packet,
+                        Ok(None) => break,
+                        Err(_) => break 'communication,
+                    };
+                    if let Some((request_id, data_request)) = engine.active_data_request() {
+                        let received_request_id = buf[0];
+                        debug!("Received data for request {received_request_id}, active request {request_id}");
+                        if received_request_id == request_id && data_request.channels[ch].is_on {
+                            data_request.handle_incoming_data(&buf, ch);
+                            received_ch_data = true;
                         }
                     }
                 }
             }
 
-
             // If we received data on any incoming channel, collate any results
             if received_ch_data {
-                if let Some((request_id, Command::RequestData(data_request))) = &active_data_request {
-                    data_request.collate_results();
-                    if data_request.is_finished() {
-                        debug!("Finished request ID: {request_id}");
-                        active_data_request = None;
+                if let Some((_, data_request)) = engine.active_data_request() {
+                    let collated = data_request.collate_results();
+                    let finished = data_request.is_finished();
+                    engine.shared.link_stats.write().unwrap().samples_received += collated as u64;
+                    if finished {
+                        engine.finish_data_request();
                     }
                 }
             }
+
+            engine.poll_stats();
         }
+
+        engine.apply_safe_state(|command, request_id| Ok(encode(command, request_id)?.to_vec()));
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,37 @@
+/***************************************************************************************************
+ *
+ *  nLabs, LLC
+ *  https://getnlab.com
+ *  Copyright(c) 2020. All Rights Reserved
+ *
+ *  This file is part of the nLab API
+ *
+ **************************************************************************************************/
+
+use super::Nlab;
+
+/// What the nLab is left doing when the connection to it ends
+///
+/// The safe state is applied when the nLab is closed or dropped, and when the communication
+/// thread exits for any other reason while the nLab can still be reached. The firmware has no
+/// watchdog, so an nLab that loses its connection to the host keeps doing what it was doing.
+#[derive(Debug, Default, PartialEq, Eq, Copy, Clone)]
+pub enum SafeState {
+    /// Leave the outputs and power supply as they are
+    LeaveAsIs,
+    /// Turn off the analog and pulse outputs
+    #[default]
+    OutputsOff,
+    /// Turn off the analog and pulse outputs, and the power supply
+    PowerOff,
+}
+
+impl Nlab {
+    pub fn safe_state(&self) -> SafeState {
+        *self.safe_state.read().unwrap()
+    }
+
+    pub fn set_safe_state(&self, safe_state: SafeState) {
+        *self.safe_state.write().unwrap() = safe_state;
+    }
+}
@@ -0,0 +1,89 @@
+/***************************************************************************************************
+ *
+ *  nLabs, LLC
+ *  https://getnlab.com
+ *  Copyright(c) 2020. All Rights Reserved
+ *
+ *  This file is part of the nLab API
+ *
+ **************************************************************************************************/
+
+use super::data_requests::{Sample, SampleBlock};
+use super::Nlab;
+
+/// Resamples the readings of channels that are read after the sample time onto the sample time,
+/// by interpolating between each reading and the one before it
+#[derive(Debug, Default, Clone)]
+pub(crate) struct Deskewer {
+    /// Last reading of each channel, carried over from the previous block
+    previous: [Option<f32>; Sample::num_channels() as usize],
+}
+
+impl Deskewer {
+    /// Returns `block` with every channel resampled onto the sample times, and no skew
+    ///
+    /// The first reading of a sweep has nothing before it, and is left as read.
+    pub(crate) fn deskew(&mut self, mut block: SampleBlock) -> SampleBlock {
+        for ch in 0..Sample::num_channels() as usize {
+            let readings = match &mut block.channels[ch] {
+                Some(readings) => readings,
+                None => continue,
+            };
+            let fraction = (block.skews[ch] / block.dt).min(1.0) as f32;
+            let mut previous = self.previous[ch];
+            for reading in readings.iter_mut() {
+                let current = *reading;
+                if let Some(previous) = previous.filter(|previous| !previous.is_nan()) {
+                    *reading = current - (current - previous) * fraction;
+                }
+                previous = Some(current);
+            }
+            self.previous[ch] = previous;
+            block.skews[ch] = 0.0;
+        }
+        block
+    }
+}
+
+impl Nlab {
+    /// Returns whether sweeps in volts are resampled so every channel is read at the sample time
+    pub fn deskew(&self) -> bool {
+        self.deskew
+    }
+
+    /// Sets whether sweeps in volts are resampled so every channel is read at the sample time
+    ///
+    /// Channels that share a converter are read one after another, see
+    /// [`Capabilities::channel_skews`](crate::Capabilities::channel_skews). Resampling lines them
+    /// up for phase measurements between channels, at the cost of smoothing each channel slightly.
+    /// Only applies to sweeps requested after it is set.
+    pub fn set_deskew(&mut self, deskew: bool) {
+        self.deskew = deskew;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn skewed_readings_are_interpolated_onto_the_sample_times() {
+        // A ramp of 1 V per sample, with the second channel read a quarter sample late
+        let block = |start: f32| SampleBlock {
+            start_time: 0.0,
+            dt: 1e-6,
+            channels: [Some(vec![start, start + 1.0]), Some(vec![start + 0.25, start + 1.25]), None, None],
+            skews: [0.0, 0.25e-6, 0.0, 0.0],
+            ..Default::default()
+        };
+
+        let mut deskewer = Deskewer::default();
+        let first = deskewer.deskew(block(0.0));
+        assert_eq!(first.channels[1], Some(vec![0.25, 1.0]));
+        assert_eq!(first.skews, [0.0; 4]);
+
+        let second = deskewer.deskew(block(2.0));
+        assert_eq!(second.channels[0], Some(vec![2.0, 3.0]));
+        assert_eq!(second.channels[1], Some(vec![2.0, 3.0]));
+    }
+}
@@ -0,0 +1,519 @@
+/***************************************************************************************************
+ *
+ *  nLabs, LLC
+ *  https://getnlab.com
+ *  Copyright(c) 2020. All Rights Reserved
+ *
+ *  This file is part of the nLab API
+ *
+ **************************************************************************************************/
+
+use std::collections::VecDeque;
+use std::fmt;
+use std::sync::{Arc, mpsc, RwLock};
+use std::time::Duration;
+
+use super::data_requests::{DataSink, Sample, SampleBlock, StreamIntegrity, SweepHandle};
+use super::math_channels::MathFunction;
+use super::Nlab;
+use super::RequestError;
+
+/// Signal a software trigger watches
+#[derive(Clone)]
+pub enum TriggerSource {
+    /// A scope channel, numbered from 0
+    Channel(usize),
+    /// A math channel, by its position in the order they were added, see
+    /// [`Nlab::add_math_channel`]
+    MathChannel(usize),
+    /// A value computed from each sample, such as the difference between two channels
+    Math(MathFunction),
+}
+
+impl fmt::Debug for TriggerSource {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TriggerSource::Channel(channel) => f.debug_tuple("Channel").field(channel).finish(),
+            TriggerSource::MathChannel(channel) => f.debug_tuple("MathChannel").field(channel).finish(),
+            TriggerSource::Math(_) => f.write_str("Math"),
+        }
+    }
+}
+
+impl TriggerSource {
+    /// Value and time of the source in `sample`
+    fn read(&self, sample: &Sample) -> Option<(f64, f64)> {
+        match self {
+            TriggerSource::Channel(channel) => {
+                let value = (*sample.data.get(*channel)?)?;
+                Some((value, sample.channel_time(*channel)))
+            }
+            TriggerSource::MathChannel(channel) => Some(((*sample.math.get(*channel)?)?, sample.time_since_start)),
+            TriggerSource::Math(function) => Some((function(sample)?, sample.time_since_start)),
+        }
+    }
+}
+
+/// Direction of a signal crossing a level
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Slope {
+    Rising,
+    Falling,
+    Either,
+}
+
+/// Whether a pulse goes above or below the level it starts from
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Polarity {
+    Positive,
+    Negative,
+}
+
+/// Condition on a single source that fires a software trigger
+///
+/// Durations are in seconds, and `f64::INFINITY` leaves a duration unbounded.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum TriggerCondition {
+    /// The signal crosses `level`, after first moving `hysteresis` away from it on the other side
+    Edge { slope: Slope, level: f64, hysteresis: f64 },
+    /// The signal enters the window between `low` and `high`, or leaves it if `on_entry` is false
+    Window { low: f64, high: f64, on_entry: bool },
+    /// A pulse across `level` ends having lasted between `min_width` and `max_width`
+    PulseWidth { level: f64, polarity: Polarity, min_width: f64, max_width: f64 },
+    /// A pulse crosses `low` and returns without reaching `high`, or for a negative pulse
+    /// crosses `high` and returns without reaching `low`
+    Runt { low: f64, high: f64, polarity: Polarity },
+    /// The signal moves from `low` to `high`, or back for a falling slope, in between `min_time`
+    /// and `max_time`
+    Slope { low: f64, high: f64, slope: Slope, min_time: f64, max_time: f64 },
+}
+
+/// Trigger evaluated on the host over the samples of a continuous sweep, see
+/// [`Nlab::request_software_triggered`]
+#[derive(Debug, Clone)]
+pub enum SoftwareTrigger {
+    /// A condition on one source
+    Condition(TriggerSource, TriggerCondition),
+    /// Fires when every trigger has fired within `within` seconds of each other
+    And(Vec<SoftwareTrigger>, f64),
+    /// Fires when any trigger fires
+    Or(Vec<SoftwareTrigger>),
+}
+
+impl SoftwareTrigger {
+    /// Edge trigger on a scope channel, numbered from 0
+    pub fn edge(channel: usize, slope: Slope, level: f64) -> Self {
+        SoftwareTrigger::Condition(TriggerSource::Channel(channel), TriggerCondition::Edge { slope, level, hysteresis: 0.0 })
+    }
+}
+
+/// Progress of a condition through the signal
+#[derive(Debug, Default, Copy, Clone)]
+struct ConditionState {
+    previous: Option<f64>,
+    /// Set once the signal is on the far side of the level, ready for a rising or falling edge
+    armed: (bool, bool),
+    /// Time a pulse, runt or slope being measured started
+    started_at: Option<f64>,
+    /// The pulse being measured reached the far threshold, so it is not a runt
+    reached_far: bool,
+}
+
+impl ConditionState {
+    /// Returns whether `condition` fires on a reading of `value` at `time`
+    fn update(&mut self, condition: &TriggerCondition, value: f64, time: f64) -> bool {
+        let previous = self.previous.replace(value);
+        match *condition {
+            TriggerCondition::Edge { slope, level, hysteresis } => {
+                self.armed.0 |= value < level - hysteresis;
+                self.armed.1 |= value > level + hysteresis;
+                let rising = self.armed.0 && value >= level && previous.is_some_and(|previous| previous < level);
+                let falling = self.armed.1 && value <= level && previous.is_some_and(|previous| previous > level);
+                if rising {
+                    self.armed.0 = false;
+                }
+                if falling {
+                    self.armed.1 = false;
+                }
+                match slope {
+                    Slope::Rising => rising,
+                    Slope::Falling => falling,
+                    Slope::Either => rising || falling,
+                }
+            }
+            TriggerCondition::Window { low, high, on_entry } => {
+                let inside = |value: f64| (low..=high).contains(&value);
+                match previous {
+                    Some(previous) => inside(previous) != inside(value) && inside(value) == on_entry,
+                    None => false,
+                }
+            }
+            TriggerCondition::PulseWidth { level, polarity, min_width, max_width } => {
+                let active = match polarity {
+                    Polarity::Positive => value > level,
+                    Polarity::Negative => value < level,
+                };
+                match (self.started_at, active) {
+                    (None, true) if previous.is_some() && self.armed.0 => {
+                        self.started_at = Some(time);
+                        false
+                    }
+                    (Some(started_at), false) => {
+                        self.started_at = None;
+                        (min_width..=max_width).contains(&(time - started_at))
+                    }
+                    (None, false) => {
+                        // Pulses are only measured from their start, not from the start of the sweep
+                        self.armed.0 = true;
+                        false
+                    }
+                    _ => false,
+                }
+            }
+            TriggerCondition::Runt { low, high, polarity } => {
+                let (near, far) = match polarity {
+                    Polarity::Positive => (value > low, value >= high),
+                    Polarity::Negative => (value < high, value <= low),
+                };
+                match (self.started_at.is_some(), near) {
+                    (false, true) => {
+                        if self.armed.0 {
+                            self.started_at = Some(time);
+                            self.reached_far = far;
+                        }
+                        false
+                    }
+                    (true, true) => {
+                        self.reached_far |= far;
+                        false
+                    }
+                    (true, false) => {
+                        self.started_at = None;
+                        !self.reached_far
+                    }
+                    (false, false) => {
+                        self.armed.0 = true;
+                        false
+                    }
+                }
+            }
+            TriggerCondition::Slope { low, high, slope, min_time, max_time } => {
+                // Rising slopes start when the signal leaves the low threshold and end when it
+                // reaches the high one, falling slopes the other way around
+                let rising_start = slope != Slope::Falling && value > low && previous.is_some_and(|previous| previous <= low);
+                let falling_start = slope != Slope::Rising && value < high && previous.is_some_and(|previous| previous >= high);
+                if rising_start || falling_start {
+                    self.started_at = Some(time);
+                    self.armed = (rising_start, falling_start);
+                }
+
+                let (ended, aborted) = match self.armed {
+                    (true, _) => (value >= high, value <= low),
+                    (_, true) => (value <= low, value >= high),
+                    _ => (false, false),
+                };
+                match self.started_at.filter(|_| ended || aborted) {
+                    Some(started_at) => {
+                        self.started_at = None;
+                        self.armed = (false, false);
+                        ended && (min_time..=max_time).contains(&(time - started_at))
+                    }
+                    None => false,
+                }
+            }
+        }
+    }
+}
+
+/// State of a software trigger, mirroring its structure
+#[derive(Debug, Clone)]
+enum TriggerState {
+    Condition(TriggerSource, TriggerCondition, ConditionState),
+    And(Vec<(TriggerState, Option<f64>)>, f64),
+    Or(Vec<TriggerState>),
+}
+
+impl TriggerState {
+    fn new(trigger: &SoftwareTrigger) -> Self {
+        match trigger {
+            SoftwareTrigger::Condition(source, condition) => TriggerState::Condition(source.clone(), *condition, ConditionState::default()),
+            SoftwareTrigger::And(triggers, within) => TriggerState::And(triggers.iter().map(|trigger| (TriggerState::new(trigger), None)).collect(), *within),
+            SoftwareTrigger::Or(triggers) => TriggerState::Or(triggers.iter().map(TriggerState::new).collect()),
+        }
+    }
+
+    /// Returns whether the trigger fires on `sample`
+    ///
+    /// Every part of the trigger sees every sample, so none of them miss part of the signal.
+    fn update(&mut self, sample: &Sample) -> bool {
+        match self {
+            TriggerState::Condition(source, condition, state) => match source.read(sample) {
+                Some((value, time)) => state.update(condition, value, time),
+                None => false,
+            },
+            TriggerState::And(triggers, within) => {
+                let now = sample.time_since_start;
+                let mut any_fired = false;
+                for (trigger, fired_at) in triggers.iter_mut() {
+                    if trigger.update(sample) {
+                        *fired_at = Some(now);
+                        any_fired = true;
+                    }
+                }
+                let all_recent = triggers.iter().all(|(_, fired_at)| fired_at.is_some_and(|fired_at| now - fired_at <= *within));
+                if any_fired && all_recent {
+                    triggers.iter_mut().for_each(|(_, fired_at)| *fired_at = None);
+                    return true;
+                }
+                false
+            }
+            TriggerState::Or(triggers) => {
+                let mut fired = false;
+                for trigger in triggers.iter_mut() {
+                    fired |= trigger.update(sample);
+                }
+                fired
+            }
+        }
+    }
+}
+
+/// Samples around one firing of a software trigger
+#[derive(Debug, Clone)]
+pub struct TriggeredCapture {
+    /// Position of the capture among those of the sweep, from 0
+    pub index: usize,
+    /// Time of the sample the trigger fired on, in seconds since the start of the sweep
+    pub trigger_time: f64,
+    /// Position of the sample the trigger fired on in `samples`
+    ///
+    /// Less than the requested pre-trigger length if the trigger fired soon after the sweep started.
+    pub trigger_index: usize,
+    pub samples: SampleBlock,
+}
+
+/// Watches a stream of samples for a software trigger, capturing the samples around each firing
+///
+/// Triggers that fire while a capture is still being filled are ignored. The samples before a
+/// trigger are kept all the while, so a capture that starts soon after another has its full
+/// pre-trigger length, overlapping the samples of the one before.
+#[derive(Debug)]
+pub struct SoftwareTriggerEngine {
+    state: TriggerState,
+    pre_trigger: usize,
+    post_trigger: usize,
+    dt: f64,
+    open: [bool; Sample::num_channels() as usize],
+    math_channels: usize,
+    history: VecDeque<Sample>,
+    capture: Option<(f64, usize, Vec<Sample>)>,
+    captures: usize,
+}
+
+impl SoftwareTriggerEngine {
+    /// Creates an engine keeping `pre_trigger` samples before the sample the trigger fires on,
+    /// and `post_trigger` samples from it on
+    pub fn new(trigger: &SoftwareTrigger, pre_trigger: usize, post_trigger: usize) -> Self {
+        SoftwareTriggerEngine {
+            state: TriggerState::new(trigger),
+            pre_trigger,
+            post_trigger: post_trigger.max(1),
+            dt: 0.0,
+            open: [false; Sample::num_channels() as usize],
+            math_channels: 0,
+            history: VecDeque::with_capacity(pre_trigger + 1),
+            capture: None,
+            captures: 0,
+        }
+    }
+
+    /// Feeds the samples of `block` through the trigger, returning the captures they complete
+    pub fn process(&mut self, block: &SampleBlock) -> Vec<TriggeredCapture> {
+        self.dt = block.dt;
+        self.open = block.channels.each_ref().map(Option::is_some);
+        self.math_channels = block.math.len();
+        let mut finished = Vec::new();
+        for sample in block.samples() {
+            let fired = self.state.update(&sample);
+            if fired && self.capture.is_none() {
+                let pre_trigger: Vec<Sample> = self.history.iter().cloned().collect();
+                self.capture = Some((sample.time_since_start, pre_trigger.len(), pre_trigger));
+            }
+
+            if let Some((_, trigger_index, samples)) = &mut self.capture {
+                samples.push(sample.clone());
+                if samples.len() - *trigger_index == self.post_trigger {
+                    finished.push(self.finish_capture());
+                }
+            }
+
+            if self.history.len() == self.pre_trigger {
+                self.history.pop_front();
+            }
+            if self.pre_trigger > 0 {
+                self.history.push_back(sample);
+            }
+        }
+        finished
+    }
+
+    fn finish_capture(&mut self) -> TriggeredCapture {
+        let (trigger_time, trigger_index, samples) = self.capture.take().unwrap();
+        let mut block = SampleBlock {
+            start_time: samples.first().map_or(trigger_time, |sample| sample.time_since_start),
+            dt: self.dt,
+            skews: samples.first().map_or([0.0; 4], |sample| sample.skews),
+            ..Default::default()
+        };
+        for ch in (0..Sample::num_channels() as usize).filter(|&ch| self.open[ch]) {
+            // Lost readings are NaN, as in other blocks
+            block.channels[ch] = Some(samples.iter().map(|sample| sample.data[ch].map_or(f32::NAN, |reading| reading as f32)).collect());
+            block.clipped[ch] = Some(samples.iter().map(|sample| sample.clipped[ch]).collect());
+        }
+        block.math = (0..self.math_channels).map(|m| {
+            samples.iter().map(|sample| sample.math.get(m).copied().flatten().map_or(f32::NAN, |value| value as f32)).collect()
+        }).collect();
+        let capture = TriggeredCapture { index: self.captures, trigger_time, trigger_index, samples: block };
+        self.captures += 1;
+        capture
+    }
+}
+
+/// Handle to an ongoing continuous sweep watched by a software trigger, see
+/// [`Nlab::request_software_triggered`]
+///
+/// Dropping the handle stops the sweep.
+#[derive(Debug)]
+pub struct SoftwareTriggerHandle {
+    sweep: SweepHandle<TriggeredCapture>,
+}
+
+impl SoftwareTriggerHandle {
+    /// Waits for the next capture, returning `None` once the sweep has ended
+    pub fn next_capture(&self) -> Option<TriggeredCapture> {
+        self.sweep.receiver.recv().ok()
+    }
+
+    /// Waits up to `timeout` for the next capture
+    pub fn next_capture_timeout(&self, timeout: Duration) -> Option<TriggeredCapture> {
+        self.sweep.receiver.recv_timeout(timeout).ok()
+    }
+
+    /// Rate at which samples are taken, see [`SweepHandle::sample_rate_hz`]
+    pub fn sample_rate_hz(&self) -> f64 {
+        self.sweep.sample_rate_hz()
+    }
+
+    pub fn stop(&self) {
+        self.sweep.stop();
+    }
+
+    /// Returns the reason the sweep ended early, if the nLab could not fulfill it
+    pub fn error(&self) -> Option<RequestError> {
+        self.sweep.error()
+    }
+
+    /// Returns the number of packets lost or discarded so far in the sweep
+    pub fn integrity(&self) -> StreamIntegrity {
+        self.sweep.integrity()
+    }
+}
+
+impl Drop for SoftwareTriggerHandle {
+    fn drop(&mut self) {
+        self.sweep.stop();
+    }
+}
+
+impl Nlab {
+    /// Streams a continuous sweep at `sample_rate_hz`, capturing `pre_trigger` samples before
+    /// and `post_trigger` samples from each firing of a trigger evaluated on the host
+    ///
+    /// The firmware trigger is not used, so any channel and any combination of conditions can
+    /// trigger, but the sweep must be slow enough to stream, see [`Nlab::max_samples_at`].
+    pub fn request_software_triggered(&self, sample_rate_hz: f64, trigger: &SoftwareTrigger, pre_trigger: usize, post_trigger: usize) -> Result<SoftwareTriggerHandle, RequestError> {
+        let (tx, rx) = mpsc::channel::<TriggeredCapture>();
+        let engine = SoftwareTriggerEngine::new(trigger, pre_trigger, post_trigger);
+        let sweep = self.start_continuous_sweep(sample_rate_hz, DataSink::Triggered(tx, Arc::new(RwLock::new(engine))), rx)?;
+        Ok(SoftwareTriggerHandle { sweep })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn block(readings: &[f32]) -> SampleBlock {
+        SampleBlock {
+            dt: 1.0,
+            channels: [Some(readings.to_vec()), Some(readings.iter().map(|r| -r).collect()), None, None],
+            ..Default::default()
+        }
+    }
+
+    fn fires(trigger: SoftwareTrigger, readings: &[f32]) -> Vec<f64> {
+        let mut engine = SoftwareTriggerEngine::new(&trigger, 0, 1);
+        engine.process(&block(readings)).iter().map(|capture| capture.trigger_time).collect()
+    }
+
+    #[test]
+    fn conditions_fire_on_their_samples() {
+        let ch0 = TriggerSource::Channel(0);
+        let pulses = [0.0, 2.0, 2.0, 0.0, 1.0, 0.0, 2.0, 2.0, 2.0, 2.0, 0.0];
+
+        assert_eq!(fires(SoftwareTrigger::edge(0, Slope::Rising, 1.0), &pulses), vec![1.0, 4.0, 6.0]);
+        assert_eq!(fires(SoftwareTrigger::edge(1, Slope::Falling, -1.0), &pulses), vec![1.0, 4.0, 6.0]);
+        let hysteresis = TriggerCondition::Edge { slope: Slope::Rising, level: 1.0, hysteresis: 0.5 };
+        assert_eq!(fires(SoftwareTrigger::Condition(ch0.clone(), hysteresis), &[0.0, 2.0, 0.9, 2.0]), vec![1.0]);
+
+        let window = TriggerCondition::Window { low: 0.5, high: 1.5, on_entry: true };
+        assert_eq!(fires(SoftwareTrigger::Condition(ch0.clone(), window), &pulses), vec![4.0]);
+
+        let width = TriggerCondition::PulseWidth { level: 1.5, polarity: Polarity::Positive, min_width: 3.0, max_width: f64::INFINITY };
+        assert_eq!(fires(SoftwareTrigger::Condition(ch0.clone(), width), &pulses), vec![10.0]);
+
+        let runt = TriggerCondition::Runt { low: 0.5, high: 1.5, polarity: Polarity::Positive };
+        assert_eq!(fires(SoftwareTrigger::Condition(ch0.clone(), runt), &pulses), vec![5.0]);
+
+        let slope = TriggerCondition::Slope { low: 0.5, high: 1.5, slope: Slope::Rising, min_time: 2.0, max_time: 3.0 };
+        assert_eq!(fires(SoftwareTrigger::Condition(ch0.clone(), slope), &[0.0, 1.0, 1.0, 2.0, 0.0, 2.0]), vec![3.0]);
+
+        let difference = TriggerSource::Math(Arc::new(|sample: &Sample| Some(sample.data[0]? - sample.data[1]?)));
+        let doubled = SoftwareTrigger::Condition(difference, TriggerCondition::Edge { slope: Slope::Rising, level: 3.0, hysteresis: 0.0 });
+        assert_eq!(fires(doubled.clone(), &pulses), vec![1.0, 6.0]);
+
+        let both = SoftwareTrigger::And(vec![doubled.clone(), SoftwareTrigger::edge(0, Slope::Rising, 1.0)], 0.0);
+        assert_eq!(fires(both, &pulses), vec![1.0, 6.0]);
+        let either = SoftwareTrigger::Or(vec![doubled, SoftwareTrigger::Condition(ch0, runt)]);
+        assert_eq!(fires(either, &pulses), vec![1.0, 5.0, 6.0]);
+    }
+
+    #[test]
+    fn captures_keep_the_samples_around_the_trigger() {
+        let mut engine = SoftwareTriggerEngine::new(&SoftwareTrigger::edge(0, Slope::Rising, 1.0), 2, 3);
+        let mut captures = engine.process(&block(&[0.0, 0.1, 0.2, 2.0, 2.1]));
+        assert!(captures.is_empty());
+        captures.extend(engine.process(&SampleBlock { start_time: 5.0, ..block(&[2.2, 0.0, 2.0, f32::NAN]) }));
+
+        let capture = &captures[0];
+        assert_eq!((capture.index, capture.trigger_index, capture.trigger_time), (0, 2, 3.0));
+        assert_eq!(capture.samples.start_time, 1.0);
+        assert_eq!(capture.samples.channels[0], Some(vec![0.1, 0.2, 2.0, 2.1, 2.2]));
+        assert_eq!(captures.len(), 1);
+    }
+
+    #[test]
+    fn captures_close_together_keep_their_pre_trigger_samples() {
+        let mut engine = SoftwareTriggerEngine::new(&SoftwareTrigger::edge(0, Slope::Rising, 1.0), 3, 2);
+        let captures = engine.process(&block(&[0.0, 0.0, 0.0, 2.0, 2.0, 0.0, 2.0, 2.0]));
+        assert_eq!(captures.len(), 2);
+
+        assert_eq!((captures[0].trigger_index, captures[0].trigger_time), (3, 3.0));
+        assert_eq!(captures[0].samples.channels[0], Some(vec![0.0, 0.0, 0.0, 2.0, 2.0]));
+
+        // The second capture fires a sample after the first finishes, and overlaps it
+        assert_eq!((captures[1].trigger_index, captures[1].trigger_time), (3, 6.0));
+        assert_eq!(captures[1].samples.start_time, 3.0);
+        assert_eq!(captures[1].samples.channels[0], Some(vec![2.0, 2.0, 0.0, 2.0, 2.0]));
+    }
+}
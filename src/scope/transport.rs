@@ -0,0 +1,48 @@
+/***************************************************************************************************
+ *
+ *  nLabs, LLC
+ *  https://getnlab.com
+ *  Copyright(c) 2020. All Rights Reserved
+ *
+ *  This file is part of the nLab API
+ *
+ **************************************************************************************************/
+
+use std::io;
+use std::sync::Arc;
+use std::time::Duration;
+
+mod hid;
+mod usb;
+
+pub(crate) use hid::HidTransport;
+pub(crate) use usb::UsbTransport;
+
+/// Size of every packet exchanged with an nLab
+pub const PACKET_SIZE: usize = 64;
+
+/// Interrupts a [`Transport::wait`] in progress on another thread
+pub type Waker = Arc<dyn Fn() + Send + Sync>;
+
+/// A link that carries packets to and from the endpoints of an nLab
+///
+/// Endpoint addresses follow the USB convention of the nLab v2: requests are written to 0x01,
+/// status responses are read from 0x81, and scope channel data is read from 0x82 to 0x85.
+/// The communication thread owns the transport, and only calls it from that thread.
+pub trait Transport: Send {
+    /// Writes one packet to an OUT endpoint
+    fn write(&mut self, endpoint: u8, packet: &[u8], timeout: Duration) -> io::Result<()>;
+
+    /// Reads the next packet received on an IN endpoint, waiting at most `timeout` for one
+    ///
+    /// Returns `None` if no packet arrived in time.
+    fn read(&mut self, endpoint: u8, timeout: Duration) -> io::Result<Option<[u8; PACKET_SIZE]>>;
+
+    /// Sleeps until a packet arrives on any endpoint, `timeout` expires, or the waker is called
+    fn wait(&mut self, timeout: Duration) -> io::Result<()>;
+
+    /// Returns a function that interrupts `wait`, called whenever a command is queued for the nLab
+    fn waker(&self) -> Option<Waker> {
+        None
+    }
+}
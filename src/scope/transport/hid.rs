@@ -0,0 +1,44 @@
+use std::io;
+use std::thread;
+use std::time::Duration;
+use hidapi::HidDevice;
+use super::{PACKET_SIZE, Transport};
+
+/// HID link to an nLab v1
+///
+/// The legacy firmware has a single report in each direction, which answers every report
+/// written to it, so the endpoint addresses are ignored.
+pub(crate) struct HidTransport {
+    device: HidDevice,
+}
+
+impl HidTransport {
+    pub(crate) fn new(device: HidDevice) -> Self {
+        HidTransport { device }
+    }
+}
+
+impl Transport for HidTransport {
+    fn write(&mut self, _endpoint: u8, packet: &[u8], _timeout: Duration) -> io::Result<()> {
+        // The first byte of the report is the report ID, which the nLab does not use
+        let mut report = Vec::with_capacity(packet.len() + 1);
+        report.push(0);
+        report.extend_from_slice(packet);
+        self.device.write(&report).map(|_| ()).map_err(io::Error::other)
+    }
+
+    fn read(&mut self, _endpoint: u8, timeout: Duration) -> io::Result<Option<[u8; PACKET_SIZE]>> {
+        let mut packet = [0u8; PACKET_SIZE];
+        let timeout_ms = timeout.as_millis().min(i32::MAX as u128) as i32;
+        match self.device.read_timeout(&mut packet, timeout_ms).map_err(io::Error::other)? {
+            0 => Ok(None),
+            _ => Ok(Some(packet)),
+        }
+    }
+
+    fn wait(&mut self, timeout: Duration) -> io::Result<()> {
+        // Reports are only sent in answer to a write, so there is nothing to wait on
+        thread::sleep(timeout);
+        Ok(())
+    }
+}
@@ -0,0 +1,210 @@
+use std::collections::VecDeque;
+use std::io;
+use std::os::raw::{c_int, c_void};
+use std::ptr::{self, NonNull};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::{Duration, Instant};
+use log::error;
+use rusb::constants::*;
+use rusb::{ffi, DeviceHandle, GlobalContext, UsbContext};
+use super::{PACKET_SIZE, Transport, Waker};
+
+/// Number of reads kept queued on each endpoint so no packet waits on the host
+const TRANSFERS_PER_ENDPOINT: usize = 8;
+
+/// Packets received on one endpoint, filled in by libusb transfer callbacks
+struct EndpointQueue {
+    endpoint: u8,
+    received: VecDeque<rusb::Result<[u8; PACKET_SIZE]>>,
+    in_flight: usize,
+    resubmit: bool,
+}
+
+struct Transfer {
+    raw: NonNull<ffi::libusb_transfer>,
+    _buffer: Box<[u8; PACKET_SIZE]>,
+}
+
+/// Bulk endpoints of an nLab v2, with a queue of asynchronous reads on each IN endpoint
+///
+/// Completed reads are collected during `wait`, which sleeps until a packet arrives, the
+/// timeout expires, or the waker is called. Transfers are resubmitted from their
+/// completion callback, so an endpoint always has reads pending while the link is open.
+pub(crate) struct UsbTransport {
+    handle: DeviceHandle<GlobalContext>,
+    queues: Vec<*const Mutex<EndpointQueue>>,
+    transfers: Vec<Transfer>,
+}
+
+// Every nLab shares the global libusb context, so callbacks for this transport's transfers may
+// run on the communication thread of another nLab. The queues they fill are behind a mutex,
+// and the transfers themselves are only touched through libusb.
+unsafe impl Send for UsbTransport {}
+
+impl UsbTransport {
+    pub(crate) fn new(handle: DeviceHandle<GlobalContext>, endpoints: &[u8]) -> rusb::Result<Self> {
+        let mut usb = UsbTransport {
+            handle,
+            queues: Vec::new(),
+            transfers: Vec::new(),
+        };
+
+        for &endpoint in endpoints {
+            let queue = Box::into_raw(Box::new(Mutex::new(EndpointQueue {
+                endpoint,
+                received: VecDeque::new(),
+                in_flight: 0,
+                resubmit: true,
+            }))) as *const Mutex<EndpointQueue>;
+            usb.queues.push(queue);
+
+            for _ in 0..TRANSFERS_PER_ENDPOINT {
+                let raw = NonNull::new(unsafe { ffi::libusb_alloc_transfer(0) })
+                    .ok_or(rusb::Error::NoMem)?;
+                let mut buffer = Box::new([0u8; PACKET_SIZE]);
+                unsafe {
+                    ffi::libusb_fill_bulk_transfer(
+                        raw.as_ptr(),
+                        usb.handle.as_raw(),
+                        endpoint,
+                        buffer.as_mut_ptr(),
+                        PACKET_SIZE as c_int,
+                        transfer_complete,
+                        queue as *mut c_void,
+                        0,
+                    );
+                }
+                usb.transfers.push(Transfer { raw, _buffer: buffer });
+
+                // Hold the lock so the callback cannot run before the transfer is counted
+                let mut locked = unsafe { &*queue }.lock().unwrap();
+                match unsafe { ffi::libusb_submit_transfer(raw.as_ptr()) } {
+                    0 => locked.in_flight += 1,
+                    _ => return Err(rusb::Error::Io),
+                }
+            }
+        }
+        Ok(usb)
+    }
+
+    fn queue(&self, endpoint: u8) -> Option<&Mutex<EndpointQueue>> {
+        self.queues.iter()
+            .map(|&q| unsafe { &*q })
+            .find(|q| q.lock().unwrap().endpoint == endpoint)
+    }
+
+    fn handle_events(&self, timeout: Duration) -> rusb::Result<()> {
+        match self.handle.context().handle_events(Some(timeout)) {
+            Err(rusb::Error::Interrupted) => Ok(()),
+            result => result,
+        }
+    }
+}
+
+impl Transport for UsbTransport {
+    fn write(&mut self, endpoint: u8, packet: &[u8], timeout: Duration) -> io::Result<()> {
+        self.handle.write_bulk(endpoint, packet, timeout).map(|_| ()).map_err(io_error)
+    }
+
+    fn read(&mut self, endpoint: u8, timeout: Duration) -> io::Result<Option<[u8; PACKET_SIZE]>> {
+        let queue = self.queue(endpoint)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, format!("No reads queued on endpoint 0x{endpoint:02X}")))?;
+        if queue.lock().unwrap().received.is_empty() && !timeout.is_zero() {
+            self.handle_events(timeout).map_err(io_error)?;
+        }
+        queue.lock().unwrap().received.pop_front().transpose().map_err(io_error)
+    }
+
+    fn wait(&mut self, timeout: Duration) -> io::Result<()> {
+        self.handle_events(timeout).map_err(io_error)
+    }
+
+    /// Interrupts libusb event handling, which is shared by every device on the global
+    /// context, so waking one nLab also wakes the event loop of every other open nLab
+    fn waker(&self) -> Option<Waker> {
+        Some(Arc::new(|| GlobalContext::default().interrupt_handle_events()))
+    }
+}
+
+impl Drop for UsbTransport {
+    fn drop(&mut self) {
+        for &queue in &self.queues {
+            unsafe { &*queue }.lock().unwrap().resubmit = false;
+        }
+        for transfer in &self.transfers {
+            unsafe { ffi::libusb_cancel_transfer(transfer.raw.as_ptr()) };
+        }
+
+        // Transfers cannot be freed until libusb has called back for each of them
+        let deadline = Instant::now() + Duration::from_secs(1);
+        while self.queues.iter().any(|&q| unsafe { &*q }.lock().unwrap().in_flight > 0) {
+            if Instant::now() > deadline {
+                error!("USB transfers did not cancel, leaking their buffers");
+                self.transfers.drain(..).for_each(std::mem::forget);
+                self.queues.clear();
+                return;
+            }
+            let _ = self.handle_events(Duration::from_millis(10));
+        }
+
+        for transfer in self.transfers.drain(..) {
+            unsafe { ffi::libusb_free_transfer(transfer.raw.as_ptr()) };
+        }
+        for queue in self.queues.drain(..) {
+            drop(unsafe { Box::from_raw(queue as *mut Mutex<EndpointQueue>) });
+        }
+    }
+}
+
+fn io_error(error: rusb::Error) -> io::Error {
+    let kind = match error {
+        rusb::Error::NoDevice => io::ErrorKind::NotConnected,
+        rusb::Error::Timeout => io::ErrorKind::TimedOut,
+        rusb::Error::Interrupted => io::ErrorKind::Interrupted,
+        _ => io::ErrorKind::Other,
+    };
+    io::Error::new(kind, error)
+}
+
+extern "system" fn transfer_complete(transfer: *mut ffi::libusb_transfer) {
+    unsafe {
+        // Panicking here would unwind into libusb, so a poisoned queue is used as it is
+        let mut queue = (*((*transfer).user_data as *const Mutex<EndpointQueue>))
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        match (*transfer).status {
+            LIBUSB_TRANSFER_COMPLETED => {
+                let length = ((*transfer).actual_length as usize).min(PACKET_SIZE);
+                if length > 0 {
+                    let mut packet = [0u8; PACKET_SIZE];
+                    ptr::copy_nonoverlapping((*transfer).buffer, packet.as_mut_ptr(), length);
+                    queue.received.push_back(Ok(packet));
+                }
+            }
+            LIBUSB_TRANSFER_TIMED_OUT => {}
+            LIBUSB_TRANSFER_CANCELLED => {
+                queue.in_flight -= 1;
+                return;
+            }
+            LIBUSB_TRANSFER_NO_DEVICE => {
+                queue.received.push_back(Err(rusb::Error::NoDevice));
+                queue.in_flight -= 1;
+                return;
+            }
+            LIBUSB_TRANSFER_STALL => {
+                queue.received.push_back(Err(rusb::Error::Pipe));
+                queue.in_flight -= 1;
+                return;
+            }
+            _ => {
+                queue.received.push_back(Err(rusb::Error::Io));
+                queue.in_flight -= 1;
+                return;
+            }
+        }
+
+        if !queue.resubmit || ffi::libusb_submit_transfer(transfer) != 0 {
+            queue.in_flight -= 1;
+        }
+    }
+}